@@ -0,0 +1,252 @@
+use std::collections::{HashMap};
+use std::env;
+use std::fmt::{Display};
+use std::fs;
+use std::path::{PathBuf};
+use std::str::{FromStr};
+
+/// Settings that can be set in a config file, each named after the command line option it
+/// stands in for. There are no audio settings since sound isn't emulated yet, and movies to play
+/// back and one-off screenshots only make sense on the command line.
+const KEYS: [&str; 20] = [
+    "scale", "palette", "debug_speed", "breakpoints", "vram_viewer", "boot_rom", "model",
+    "color_correction", "bindings", "speed", "screenshot_scale", "save_dir", "screenshot_dir",
+    "filter", "ghosting", "lcd_grid", "dmg_tint", "record", "dump_frames", "record_av",
+];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Section {
+    /// Settings before the first section header apply to every game.
+    Global,
+    /// Settings for games with the given title in the cartridge header.
+    Title(String),
+    /// Settings for games with the given global checksum in the cartridge header.
+    Checksum(u16),
+}
+
+/// Settings read from an INI file, with `key = value` on each line and `#` or `;` starting a
+/// comment at the start of a line or after whitespace. Settings under a `[title:<title>]` or `[checksum:<hex>]` header only apply to games
+/// with that title or global checksum in their cartridge header, and override the settings that
+/// apply to every game. Checksum sections override title sections.
+#[derive(Default)]
+pub struct ConfigFile {
+    sections: Vec<(Section, HashMap<String, String>)>,
+}
+
+/// Where the config file lives if one isn't given on the command line: `gbemu/config.ini` in
+/// `$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`, whichever is set first.
+pub fn default_config_filepath() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(config_dir.join("gbemu").join("config.ini"))
+}
+
+/// The title and global checksum from a cartridge header, which are what per-game sections are
+/// matched against.
+pub fn rom_header_keys(rom: &[u8]) -> Option<(String, u16)> {
+    if rom.len() < 0x150 {
+        return None;
+    }
+    // CGB games use the last 5 bytes of the title area for a manufacturer code and the CGB flag.
+    // The manufacturer code is printable and can follow an 11 character title straight away (e.g.
+    // POKEMON_SLVAAXJ), so the title is cut off before it.
+    let title_end = if rom[0x143] & 0x80 != 0 { 0x13f } else { 0x144 };
+    let title = rom[0x134..title_end].iter()
+        .take_while(|&&c| (0x20..0x7f).contains(&c))
+        .map(|&c| c as char)
+        .collect::<String>();
+    let checksum = u16::from_be_bytes([rom[0x14e], rom[0x14f]]);
+    Some((title.trim_end().to_string(), checksum))
+}
+
+impl ConfigFile {
+    pub fn load(filepath: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(filepath)
+            .map_err(|e| format!("Failed to open config file '{filepath}': {e}"))?;
+        Self::parse(&text).map_err(|e| format!("{}:{}", filepath, e))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut sections = vec!((Section::Global, HashMap::new()));
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header.strip_suffix(']')
+                    .ok_or(format!("{}: Expected ']' at the end of '{}'", i + 1, line))?;
+                let section = parse_section(header).map_err(|e| format!("{}: {}", i + 1, e))?;
+                sections.push((section, HashMap::new()));
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or(format!("{}: Expected '<key> = <value>', got '{}'", i + 1, line))?;
+            let key = key.trim();
+            if key == "audio" || key.starts_with("audio_") || key == "volume" {
+                return Err(format!("{}: Sound isn't emulated yet, so there's no '{}' setting", i + 1, key));
+            }
+            if !KEYS.contains(&key) {
+                return Err(format!("{}: Unknown setting '{}'", i + 1, key));
+            }
+            sections.last_mut().unwrap().1.insert(key.to_string(), value.trim().to_string());
+        }
+        Ok(Self { sections })
+    }
+
+    /// The settings that apply to the game with the given title and checksum, or just the ones
+    /// that apply to every game if the header couldn't be read.
+    pub fn settings(&self, header_keys: Option<&(String, u16)>) -> HashMap<String, String> {
+        let mut settings = HashMap::new();
+        let matching = |section: &Section| match (section, header_keys) {
+            (Section::Title(title), Some((rom_title, _))) => title == rom_title,
+            (Section::Checksum(checksum), Some((_, rom_checksum))) => checksum == rom_checksum,
+            _ => false,
+        };
+        let global = self.sections.iter().filter(|(section, _)| *section == Section::Global);
+        let titles = self.sections.iter().filter(|(section, _)| matches!(section, Section::Title(_)) && matching(section));
+        let checksums = self.sections.iter().filter(|(section, _)| matches!(section, Section::Checksum(_)) && matching(section));
+        for (_, section_settings) in global.chain(titles).chain(checksums) {
+            settings.extend(section_settings.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        settings
+    }
+}
+
+/// The line up to the first comment. Comment characters in the middle of a word, like a path,
+/// don't start a comment.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        if (c == '#' || c == ';') && previous.is_whitespace() {
+            return &line[..i];
+        }
+        previous = c;
+    }
+    line
+}
+
+fn parse_section(header: &str) -> Result<Section, String> {
+    match header.split_once(':') {
+        Some(("title", title)) => Ok(Section::Title(title.trim().to_string())),
+        Some(("checksum", checksum)) => {
+            let checksum = checksum.trim();
+            u16::from_str_radix(checksum, 16)
+                .map(Section::Checksum)
+                .map_err(|e| format!("Failed to parse checksum {checksum}: {e}"))
+        },
+        _ => Err(format!("Expected '[title:<title>]' or '[checksum:<hex>]', got '[{header}]'")),
+    }
+}
+
+/// The value given on the command line if there is one, otherwise the one from the config file,
+/// otherwise the default.
+pub fn setting<T>(cli: Option<T>, settings: &HashMap<String, String>, key: &str, default: T) -> Result<T, String>
+    where T: FromStr, T::Err: Display {
+    if let Some(value) = cli {
+        return Ok(value);
+    }
+    match settings.get(key) {
+        Some(value) => value.parse::<T>()
+            .map_err(|e| format!("Failed to parse config setting '{key}' ({value}): {e}")),
+        None => Ok(default),
+    }
+}
+
+/// A setting that's turned on or off by a pair of command line flags (e.g. `--vram` and
+/// `--no-vram`), either of which wins over the config file.
+pub fn flag_setting(cli: Option<bool>, settings: &HashMap<String, String>, key: &str) -> Result<bool, String> {
+    if let Some(value) = cli {
+        return Ok(value);
+    }
+    match settings.get(key).map(String::as_str) {
+        Some("true") | Some("yes") | Some("on") | Some("1") => Ok(true),
+        Some("false") | Some("no") | Some("off") | Some("0") | None => Ok(false),
+        Some(value) => Err(format!("Expected true or false for config setting '{key}', got '{value}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    const TEXT: &str = "
+        # Applies to every game.
+        scale = 3
+        palette = green ; the classic look
+
+        [title:TETRIS]
+        palette = grey
+        model = dmg
+
+        [checksum:3f2a]
+        model = sgb
+    ";
+
+    #[test]
+    fn overrides() {
+        let file = ConfigFile::parse(TEXT).unwrap();
+
+        let other = file.settings(Some(&("ZELDA".to_string(), 0x1234)));
+        assert_eq!(other.get("scale").map(String::as_str), Some("3"));
+        assert_eq!(other.get("palette").map(String::as_str), Some("green"));
+        assert_eq!(other.get("model"), None);
+
+        let tetris = file.settings(Some(&("TETRIS".to_string(), 0x1234)));
+        assert_eq!(tetris.get("scale").map(String::as_str), Some("3"));
+        assert_eq!(tetris.get("palette").map(String::as_str), Some("grey"));
+        assert_eq!(tetris.get("model").map(String::as_str), Some("dmg"));
+
+        // The checksum section wins over the title section.
+        let tetris = file.settings(Some(&("TETRIS".to_string(), 0x3f2a)));
+        assert_eq!(tetris.get("model").map(String::as_str), Some("sgb"));
+    }
+
+    #[test]
+    fn comments() {
+        let file = ConfigFile::parse("# comment\nsave_dir = /gb/saves#1;2 ; comment\nboot_rom = a;b.bin#").unwrap();
+        let settings = file.settings(None);
+        assert_eq!(settings.get("save_dir").map(String::as_str), Some("/gb/saves#1;2"));
+        assert_eq!(settings.get("boot_rom").map(String::as_str), Some("a;b.bin#"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(ConfigFile::parse("scael = 3").is_err());
+        assert!(ConfigFile::parse("scale 3").is_err());
+        assert!(matches!(ConfigFile::parse("audio = off"), Err(e) if e.contains("Sound isn't emulated")));
+        assert!(ConfigFile::parse("[title:TETRIS").is_err());
+        assert!(ConfigFile::parse("[rom:TETRIS]").is_err());
+        assert!(ConfigFile::parse("[checksum:xyz]").is_err());
+    }
+
+    #[test]
+    fn layering() {
+        let file = ConfigFile::parse("scale = 3\nvram_viewer = yes\ndebug_speed = maybe").unwrap();
+        let settings = file.settings(None);
+        assert_eq!(setting(Some(2), &settings, "scale", 4), Ok(2));
+        assert_eq!(setting(None, &settings, "scale", 4), Ok(3));
+        assert_eq!(setting(None, &settings, "palette", "grey".to_string()), Ok("grey".to_string()));
+        assert_eq!(flag_setting(None, &settings, "vram_viewer"), Ok(true));
+        assert_eq!(flag_setting(Some(false), &settings, "vram_viewer"), Ok(false));
+        assert_eq!(flag_setting(None, &settings, "color_correction"), Ok(false));
+        assert_eq!(flag_setting(Some(true), &settings, "color_correction"), Ok(true));
+        assert!(flag_setting(None, &settings, "debug_speed").is_err());
+    }
+
+    #[test]
+    fn header_keys() {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x13a].copy_from_slice(b"TETRIS");
+        rom[0x14e] = 0x3f;
+        rom[0x14f] = 0x2a;
+        assert_eq!(rom_header_keys(&rom), Some(("TETRIS".to_string(), 0x3f2a)));
+        assert_eq!(rom_header_keys(&rom[..0x100]), None);
+
+        rom[0x134..0x144].copy_from_slice(b"POKEMON_SLVAAXJ\x80");
+        assert_eq!(rom_header_keys(&rom), Some(("POKEMON_SLV".to_string(), 0x3f2a)));
+    }
+}
@@ -94,6 +94,10 @@ impl InfoBlock {
     }
 }
 
+pub const EXECUTION_STATE_RUNNING: u8 = 0;
+pub const EXECUTION_STATE_HALTED: u8  = 1;
+pub const EXECUTION_STATE_STOPPED: u8 = 2;
+
 #[derive(Debug)]
 pub struct CoreBlock<'a> {
     pub major: u16,
@@ -107,7 +111,7 @@ pub struct CoreBlock<'a> {
     pub sp: u16,
     pub ime: u8, // 0 or 1
     pub ie: u8,
-    pub execution_state: u8, // 0 = running, 1 = halted, 2 = stopped
+    pub execution_state: u8, // One of EXECUTION_STATE_*
     pub reserved: u8, // Must be 0
     pub memory_mapped_registers: &'a [u8; 128],
     pub ram: &'a [u8],
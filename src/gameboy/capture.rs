@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::{PathBuf};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::png::{encode_png, scale_pixels};
+use crate::gameboy::video::{VideoRecorder};
+use crate::gameboy::filter::{Filter};
+
+/// Saves frames to PNG files, either when asked to or on every frame, and records them to a video
+/// file. Frames are captured at the start of VBlank, when the whole screen has just been drawn.
+/// Screenshots and videos go through the same filter as the screen.
+pub struct FrameCapture {
+    /// Set by the UI to save a screenshot of the next frame.
+    pub screenshot_requested: Arc<AtomicBool>,
+    /// Where hotkey screenshots are saved, with the frame number and .png added to the end.
+    pub screenshot_prefix: String,
+    /// Frame to take a screenshot of without being asked, and the file to save it to.
+    pub screenshot_at_frame: Option<(u64, String)>,
+    /// Screenshots are scaled up by this factor after filtering. Dumped frames are always saved
+    /// at 160x144, without filtering.
+    pub screenshot_scale: u32,
+    pub filter: Filter,
+    /// Directory to save every frame to.
+    pub dump_dir: Option<PathBuf>,
+    pub video: Option<VideoRecorder>,
+    /// Frames since power on.
+    frame: u64,
+}
+
+impl FrameCapture {
+    pub fn new() -> Self {
+        Self {
+            screenshot_requested: Arc::new(AtomicBool::new(false)),
+            screenshot_prefix: String::from("screenshot"),
+            screenshot_at_frame: None,
+            screenshot_scale: 1,
+            filter: Filter::None,
+            dump_dir: None,
+            video: None,
+            frame: 0,
+        }
+    }
+}
+
+/// Called at the start of every frame.
+pub fn run_capture_frame(gb: &mut Gameboy) {
+    let capture = &mut gb.capture;
+    let frame = capture.frame;
+    capture.frame += 1;
+
+    let mut screenshot_filepaths = vec!();
+    if capture.screenshot_requested.swap(false, Ordering::Relaxed) {
+        screenshot_filepaths.push(format!("{}-{:0>6}.png", capture.screenshot_prefix, frame));
+    }
+    if let Some((at_frame, filepath)) = &capture.screenshot_at_frame {
+        if *at_frame == frame {
+            screenshot_filepaths.push(filepath.clone());
+        }
+    }
+    if screenshot_filepaths.is_empty() && capture.dump_dir.is_none() && capture.video.is_none() {
+        return;
+    }
+
+    let pixels: Vec<(u8, u8, u8)> = gb.screen.lock().unwrap().iter().flatten().copied().collect();
+    let capture = &mut gb.capture;
+    let filtered = if !screenshot_filepaths.is_empty() || capture.video.is_some() {
+        capture.filter.apply(160, &pixels)
+    } else {
+        vec!()
+    };
+    let factor = capture.filter.factor();
+
+    if !screenshot_filepaths.is_empty() {
+        let scale = capture.screenshot_scale;
+        let png = encode_png(160 * factor * scale, 144 * factor * scale, &scale_pixels(160 * factor, &filtered, scale));
+        for filepath in screenshot_filepaths {
+            match fs::write(&filepath, &png) {
+                Ok(()) => println!("Saved screenshot '{}'", filepath),
+                Err(err) => eprintln!("Failed to save screenshot '{}': {}", filepath, err),
+            }
+        }
+    }
+
+    if let Some(dir) = &capture.dump_dir {
+        let filepath = dir.join(format!("{:0>6}.png", frame));
+        if let Err(err) = fs::write(&filepath, encode_png(160, 144, &pixels)) {
+            eprintln!("Failed to save frame '{}': {}", filepath.display(), err);
+        }
+    }
+
+    if let Some(video) = &mut capture.video {
+        if let Err(err) = video.write_frame(&filtered) {
+            eprintln!("{}, stopping video recording", err);
+            capture.video = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use std::env;
+    use crate::gameboy::utils::{test_dmg};
+
+    #[test]
+    fn screenshot_at_frame() {
+        let mut gb = test_dmg(&[]);
+        let filepath = env::temp_dir().join(format!("gbemu-capture-{}.png", std::process::id()));
+        let _ = fs::remove_file(&filepath);
+        gb.capture.screenshot_at_frame = Some((1, filepath.to_str().unwrap().to_string()));
+        gb.capture.screenshot_scale = 2;
+
+        run_capture_frame(&mut gb);
+        assert!(!filepath.exists());
+        run_capture_frame(&mut gb);
+        let png = fs::read(&filepath).unwrap();
+        fs::remove_file(&filepath).unwrap();
+        // Width and height in IHDR.
+        assert_eq!(&png[16..24], &[0, 0, 1, 64, 0, 0, 1, 32]);
+    }
+}
@@ -0,0 +1,97 @@
+/// Auto-increment flag for BCPS/OCPS.
+pub const CPS_INCREMENT: u8 = 0b1000_0000;
+/// Byte index into palette RAM for BCPS/OCPS.
+pub const CPS_INDEX: u8     = 0b0011_1111;
+
+/// CGB palette RAM, written through BCPS/BCPD and OCPS/OCPD.
+/// Each memory holds 8 palettes of 4 colors, with each color stored as a little-endian 15-bit
+/// RGB value (5 bits per channel, red in the lowest bits).
+pub struct CgbPalettes {
+    pub bg: [u8; 0x40],
+    pub obj: [u8; 0x40],
+}
+
+impl CgbPalettes {
+    pub fn new() -> Self {
+        // The boot ROM initializes all BG colors to white. OBJ palette RAM is left uninitialized.
+        Self {
+            bg: [0xff; 0x40],
+            obj: [0x00; 0x40],
+        }
+    }
+
+    pub fn bg_color(&self, palette: u8, color_ix: u8, color_correction: bool) -> (u8, u8, u8) {
+        cgb_color(&self.bg, palette, color_ix, color_correction)
+    }
+
+    pub fn obj_color(&self, palette: u8, color_ix: u8, color_correction: bool) -> (u8, u8, u8) {
+        cgb_color(&self.obj, palette, color_ix, color_correction)
+    }
+}
+
+fn cgb_color(palette_ram: &[u8; 0x40], palette: u8, color_ix: u8, color_correction: bool) -> (u8, u8, u8) {
+    let offset = (palette as usize) * 8 + (color_ix as usize) * 2;
+    let color = u16::from_le_bytes([palette_ram[offset], palette_ram[offset + 1]]);
+    rgb555_to_rgb888(color, color_correction)
+}
+
+/// Converts a 15-bit CGB color to 24-bit RGB.
+/// The CGB LCD doesn't display colors the way a modern monitor does: colors bleed into each other
+/// and the brightest values are washed out. With color correction enabled, this is approximated
+/// so that games look the way their artists intended.
+pub fn rgb555_to_rgb888(color: u16, color_correction: bool) -> (u8, u8, u8) {
+    let r = (color & 0x1f) as u32;
+    let g = ((color >> 5) & 0x1f) as u32;
+    let b = ((color >> 10) & 0x1f) as u32;
+
+    if color_correction {
+        let r_out = r * 26 + g * 4 + b * 2;
+        let g_out = g * 24 + b * 8;
+        let b_out = r * 6 + g * 4 + b * 22;
+        (
+            (u32::min(960, r_out) >> 2) as u8,
+            (u32::min(960, g_out) >> 2) as u8,
+            (u32::min(960, b_out) >> 2) as u8,
+        )
+    } else {
+        // Repeat the top bits in the bottom bits so that 0x1f maps to 0xff.
+        (
+            ((r << 3) | (r >> 2)) as u8,
+            ((g << 3) | (g >> 2)) as u8,
+            ((b << 3) | (b >> 2)) as u8,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    #[test]
+    fn rgb555_to_rgb888_uncorrected() {
+        assert_eq!(rgb555_to_rgb888(0x0000, false), (0x00, 0x00, 0x00));
+        assert_eq!(rgb555_to_rgb888(0x7fff, false), (0xff, 0xff, 0xff));
+        assert_eq!(rgb555_to_rgb888(0x001f, false), (0xff, 0x00, 0x00));
+        assert_eq!(rgb555_to_rgb888(0x03e0, false), (0x00, 0xff, 0x00));
+        assert_eq!(rgb555_to_rgb888(0x7c00, false), (0x00, 0x00, 0xff));
+    }
+
+    #[test]
+    fn rgb555_to_rgb888_corrected() {
+        assert_eq!(rgb555_to_rgb888(0x0000, true), (0x00, 0x00, 0x00));
+        assert_eq!(rgb555_to_rgb888(0x7fff, true), (0xf0, 0xf0, 0xf0));
+        // Pure red bleeds into blue on the CGB LCD.
+        let (r, g, b) = rgb555_to_rgb888(0x001f, true);
+        assert!(r > b && b > g);
+    }
+
+    #[test]
+    fn palette_lookup() {
+        let mut palettes = CgbPalettes::new();
+        // Palette 2, color 3
+        palettes.obj[2*8 + 3*2] = 0x1f;
+        palettes.obj[2*8 + 3*2 + 1] = 0x00;
+        assert_eq!(palettes.obj_color(2, 3, false), (0xff, 0x00, 0x00));
+        assert_eq!(palettes.bg_color(0, 0, false), (0xff, 0xff, 0xff));
+    }
+}
@@ -0,0 +1,131 @@
+use std::ops::{DerefMut};
+#[cfg(test)]
+use std::ops::{Deref};
+use crate::gameboy::gameboy::{INT_ALL};
+use crate::gameboy::cpu::registers::{Cpu};
+
+const ADDR_IF: u16 = 0xff0f;
+const ADDR_IE: u16 = 0xffff;
+
+/// Everything the CPU is connected to. The CPU only ever sees the system through this, so the same
+/// core can run against the Gameboy memory map or against plain RAM.
+pub trait Bus: DerefMut<Target = Cpu> {
+    /// Reads from memory without taking any time, e.g. for decoding or the debugger.
+    fn read(&self, addr: u16) -> u8;
+
+    fn write(&mut self, addr: u16, value: u8);
+
+    /// Runs the rest of the system for the given number of machine cycles.
+    fn tick(&mut self, m_cycles: u64);
+
+    /// Machine cycles run since power on.
+    fn cycles(&self) -> u64;
+
+    /// Reads from memory as part of an instruction. The rest of the system runs for the machine
+    /// cycle the access takes first, so the read sees the state at the end of that cycle.
+    fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.tick(1);
+        self.read(addr)
+    }
+
+    /// Writes to memory as part of an instruction, after running the rest of the system for the
+    /// machine cycle the access takes.
+    fn write_cycle(&mut self, addr: u16, value: u8) {
+        self.tick(1);
+        self.write(addr, value);
+    }
+
+    /// Interrupts that are both enabled in IE and requested in IF.
+    fn pending_interrupts(&self) -> u8 {
+        self.read(ADDR_IE) & self.read(ADDR_IF) & INT_ALL
+    }
+
+    /// Clears an interrupt's IF bit once it's been dispatched.
+    fn acknowledge_interrupt(&mut self, flag: u8) {
+        let io_if = self.read(ADDR_IF);
+        self.write(ADDR_IF, io_if & !flag);
+    }
+
+    /// The P1 input lines for the selected button groups. A line is low while a button connected
+    /// to it is pressed.
+    fn joypad_lines(&self) -> u8 {
+        0b0000_1111
+    }
+
+    /// Called when STOP is executed with no buttons held. Returns whether the system actually
+    /// stopped, rather than doing something else with it, like a CGB speed switch.
+    fn stop(&mut self) -> bool {
+        true
+    }
+}
+
+/// 64 KB of plain RAM with nothing else attached, so that CPU tests can put code and data
+/// anywhere.
+#[cfg(test)]
+pub struct FlatRam {
+    pub cpu: Cpu,
+    pub ram: Box<[u8; 0x10000]>,
+    pub cycles: u64,
+    /// Memory accesses made by the CPU, as (cycle, address, value, is_write).
+    pub log: Vec<(u64, u16, u8, bool)>,
+}
+
+#[cfg(test)]
+impl FlatRam {
+    pub fn new(cpu: Cpu) -> Self {
+        Self {
+            cpu,
+            ram: Box::new([0; 0x10000]),
+            cycles: 0,
+            log: vec!(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Deref for FlatRam {
+    type Target = Cpu;
+
+    fn deref(&self) -> &Cpu {
+        &self.cpu
+    }
+}
+
+#[cfg(test)]
+impl DerefMut for FlatRam {
+    fn deref_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+impl Bus for FlatRam {
+    fn read(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.ram[addr as usize] = value;
+    }
+
+    fn tick(&mut self, m_cycles: u64) {
+        self.cycles += m_cycles;
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.tick(1);
+        let value = self.read(addr);
+        self.log.push((self.cycles, addr, value, false));
+        value
+    }
+
+    fn write_cycle(&mut self, addr: u16, value: u8) {
+        self.tick(1);
+        self.log.push((self.cycles, addr, value, true));
+        self.write(addr, value);
+    }
+}
@@ -1,6 +1,7 @@
 use std::num::Wrapping;
 use std::sync::atomic::{Ordering};
-use super::super::gameboy::{*};
+use super::registers::{*};
+use super::bus::{Bus};
 use super::instruction::{
     CarryMode, Src8, Dst8, Src16, Dst16, BitwiseOp, IncDec, AddSub, Cond
 };
@@ -8,31 +9,46 @@ use super::instruction::{
 const BIT_0: u8 = 0b0000_0001;
 const BIT_7: u8 = 0b1000_0000;
 
-pub fn stop(gb: &mut Gameboy) {
-    gb.stopped.store(true, Ordering::Relaxed);
+pub fn stop<B: Bus>(gb: &mut B) {
+    if gb.joypad_lines() != 0b0000_1111 {
+        // A button is already being held, so the system can't be stopped.
+        if gb.pending_interrupts() > 0 {
+            // STOP acts as a 1-byte NOP, so the byte after it is executed after all.
+            gb.pc = gb.pc.wrapping_sub(1);
+        } else {
+            gb.halted.store(true, Ordering::Relaxed);
+        }
+        return;
+    }
+
+    if gb.stop() {
+        gb.stopped.store(true, Ordering::Relaxed);
+    }
 }
 
-pub fn halt(gb: &mut Gameboy)  {
-    if gb.ime.load(Ordering::Relaxed) {
+pub fn halt<B: Bus>(gb: &mut B)  {
+    if gb.pending_interrupts() > 0 && !gb.ime.load(Ordering::Relaxed) {
+        // HALT exits right away without dispatching the interrupt, but runs into the HALT bug.
+        gb.halt_bug = true;
+    } else {
         gb.halted.store(true, Ordering::Relaxed);
     }
-    // TODO handle instruction-skipping behavior
 }
 
-pub fn di(gb: &mut Gameboy) {
+pub fn lock_up<B: Bus>(gb: &mut B) {
+    gb.locked_up = true;
+}
+
+pub fn di<B: Bus>(gb: &mut B) {
     gb.ime.store(false, Ordering::Relaxed);
+    gb.ime_scheduled = false;
 }
 
-pub fn ei(gb: &mut Gameboy) {
-    // TODO this should take effect after next machine cycle, apparently
-    // Should probably schedule this by specifying by what cycle IME should
-    // get enabled. For >2 cycle instructions, IME will be set by the time the
-    // next start of the emulation loop. For 1 cycle instructions, IME will end up
-    // being enabled a cycle too early (could matter).
-    gb.ime.store(true, Ordering::Relaxed);
+pub fn ei<B: Bus>(gb: &mut B) {
+    gb.ime_scheduled = true;
 }
 
-pub fn ccf(gb: &mut Gameboy) {
+pub fn ccf<B: Bus>(gb: &mut B) {
     gb.regs[RF] &= !FLAG_N;
     gb.regs[RF] &= !FLAG_H;
     let c = (gb.regs[RF] & FLAG_C) >> 4;
@@ -43,140 +59,66 @@ pub fn ccf(gb: &mut Gameboy) {
     }
 }
 
-pub fn scf(gb: &mut Gameboy) {
+pub fn scf<B: Bus>(gb: &mut B) {
     gb.regs[RF] &= !FLAG_N;
     gb.regs[RF] &= !FLAG_H;
     gb.regs[RF] |= FLAG_C;
 }
 
-pub fn daa(gb: &mut Gameboy) {
+pub fn daa<B: Bus>(gb: &mut B) {
     let n = gb.regs[RF] & FLAG_N > 0;
     let c = gb.regs[RF] & FLAG_C > 0;
     let h = gb.regs[RF] & FLAG_H > 0;
-    let a_low = gb.regs[RA] & 0b0000_1111;
-    let a_high = (gb.regs[RA] & 0b1111_0000) >> 4;
-
-    // TODO a real GB probably wouldn't lock up here, but not sure right now what it would actually
-    // do
-    let (add_to_a, new_c) = match (n, c, a_high, h, a_low) {
-        (false, false, high, false, low) => {
-            if (0x00..=0x09).contains(&high) && (0x00..=0x09).contains(&low) {
-                (0x00, 0)
-            } else if (0x00..=0x08).contains(&high) && (0x0a..=0x0f).contains(&low) {
-                (0x06, 0)
-            } else if (0x0a..=0x0f).contains(&high) && (0x00..=0x09).contains(&low) {
-                (0x60, 1)
-            } else if (0x09..=0x0f).contains(&high) && (0x0a..=0x0f).contains(&low) {
-                (0x66, 1)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (false, false, high, true, low) => {
-            if (0x00..=0x09).contains(&high) && (0x00..=0x03).contains(&low) {
-                (0x06, 0)
-            } else if (0x0a..=0x0f).contains(&high) && (0x00..=0x03).contains(&low) {
-                (0x66, 1)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (false, true, high, false, low) => {
-            if (0x00..=0x02).contains(&high) && (0x00..=0x09).contains(&low) {
-                (0x60, 1)
-            } else if (0x00..=0x02).contains(&high) && (0x0a..=0x0f).contains(&low) {
-                (0x66, 1)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (false, true, high, true, low) => {
-            if (0x00..=0x03).contains(&high) && (0x00..=0x03).contains(&low) {
-                (0x66, 1)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (true, false, high, false, low) => {
-            if (0x00..=0x09).contains(&high) && (0x00..=0x09).contains(&low) {
-                (0x00, 0)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (true, false, high, true, low) => {
-            if (0x00..=0x08).contains(&high) && (0x06..=0x0f).contains(&low) {
-                (0xfa, 0)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (true, true, high, false, low) => {
-            if (0x07..=0x0f).contains(&high) && (0x00..=0x09).contains(&low) {
-                (0xa0, 1)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-        (true, true, high, true, low) => {
-            if (0x06..=0x0f).contains(&high) && (0x06..=0x0f).contains(&low) {
-                (0x9a, 0)
-            } else {
-                panic!("Invalid register A value 0x{:0>2X} for N={},C={},H={}",
-                       gb.regs[RA], n, c, h)
-            }
-        },
-    };
-
-    gb.regs[RA] = (Wrapping(gb.regs[RA]) + Wrapping(add_to_a)).0;
 
-    if gb.regs[RA] == 0 {
-        gb.regs[RF] |= FLAG_Z;
+    // Adjust A back into BCD after an addition or subtraction of two BCD values, based on which
+    // digits carried or borrowed.
+    let mut adjust = 0;
+    let mut new_c = c;
+    if n {
+        if h { adjust |= 0x06; }
+        if c { adjust |= 0x60; }
+        gb.regs[RA] = gb.regs[RA].wrapping_sub(adjust);
     } else {
-        gb.regs[RF] &= !FLAG_Z;
+        if h || gb.regs[RA] & 0x0f > 0x09 { adjust |= 0x06; }
+        if c || gb.regs[RA] > 0x99 {
+            adjust |= 0x60;
+            new_c = true;
+        }
+        gb.regs[RA] = gb.regs[RA].wrapping_add(adjust);
     }
 
-    gb.regs[RF] &= !FLAG_H;
-
-    if new_c == 1 {
+    gb.regs[RF] &= FLAG_N;
+    gb.regs[RF] |= compute_zero_flag(gb.regs[RA]);
+    if new_c {
         gb.regs[RF] |= FLAG_C;
-    } else {
-        gb.regs[RF] &= !FLAG_C;
     }
 }
 
-pub fn cpl(gb: &mut Gameboy) {
+pub fn cpl<B: Bus>(gb: &mut B) {
     gb.regs[RA] = !gb.regs[RA];
     gb.regs[RF] |= FLAG_N;
     gb.regs[RF] |= FLAG_H;
 }
 
-pub fn ld(gb: &mut Gameboy, dst: Dst8, src: Src8) {
+pub fn ld<B: Bus>(gb: &mut B, dst: Dst8, src: Src8) {
     let value = src.read(gb);
     dst.write(gb, value);
 }
 
-pub fn ld_inc_dec(gb: &mut Gameboy, dst: Dst8, src: Src8, mode: IncDec) {
+pub fn ld_inc_dec<B: Bus>(gb: &mut B, dst: Dst8, src: Src8, mode: IncDec) {
     let value = src.read(gb);
     dst.write(gb, value);
 
-    let offset = match mode {
-        IncDec::Inc => 1,
-        IncDec::Dec => -1,
+    let hl = rr_to_u16(gb, RHL);
+    let new = match mode {
+        IncDec::Inc => hl.wrapping_add(1),
+        IncDec::Dec => hl.wrapping_sub(1),
     };
-    let new = (rr_to_u16(gb, RHL) as i16) + offset;
     gb.regs[RH] = (new >> 8) as u8;
     gb.regs[RL] = new as u8;
 }
 
-pub fn ld_16(gb: &mut Gameboy, dst: Dst16, src: Src16) {
+pub fn ld_16<B: Bus>(gb: &mut B, dst: Dst16, src: Src16) {
     if let Src16::SPD8(n) = src {
         gb.regs[RF] &= 0;
         gb.regs[RF] |= compute_half_carry_flag(gb.sp as u8, n as u8);
@@ -187,63 +129,53 @@ pub fn ld_16(gb: &mut Gameboy, dst: Dst16, src: Src16) {
     dst.write(gb, value);
 }
 
-pub fn push(gb: &mut Gameboy, r_pair: RR) {
-    gb.sp -= 1;
-    gb.write(gb.sp, gb.regs[r_pair.0]);
-    gb.sp -= 1;
-    gb.write(gb.sp, gb.regs[r_pair.1]);
+pub fn push<B: Bus>(gb: &mut B, r_pair: RR) {
+    // SP is decremented in an internal cycle before anything is written.
+    gb.tick(1);
+    gb.sp = gb.sp.wrapping_sub(1);
+    gb.write_cycle(gb.sp, gb.regs[r_pair.0]);
+    gb.sp = gb.sp.wrapping_sub(1);
+    gb.write_cycle(gb.sp, gb.regs[r_pair.1]);
 }
 
-pub fn pop(gb: &mut Gameboy, r_pair: RR) {
-    gb.regs[r_pair.1] = gb.read(gb.sp);
-    gb.sp += 1;
-    gb.regs[r_pair.0] = gb.read(gb.sp);
-    gb.sp += 1;
+pub fn pop<B: Bus>(gb: &mut B, r_pair: RR) {
+    gb.regs[r_pair.1] = gb.read_cycle(gb.sp);
+    gb.sp = gb.sp.wrapping_add(1);
+    gb.regs[r_pair.0] = gb.read_cycle(gb.sp);
+    gb.sp = gb.sp.wrapping_add(1);
+    // The bottom 4 bits of F don't exist, and always read 0.
+    gb.regs[RF] &= 0xf0;
 }
 
-pub fn inc_dec(gb: &mut Gameboy, dst: Dst8, mode: IncDec) {
+pub fn inc_dec<B: Bus>(gb: &mut B, dst: Dst8, mode: IncDec) {
     let value = dst.read(gb);
-    let computed_value = match mode {
-        IncDec::Inc => (Wrapping(value) + Wrapping(1)).0,
-        IncDec::Dec => (Wrapping(value) + Wrapping((-1 as i8) as u8)).0,
+    let (computed_value, half_carry) = match mode {
+        IncDec::Inc => (value.wrapping_add(1), value & 0x0f == 0x0f),
+        IncDec::Dec => (value.wrapping_sub(1), value & 0x0f == 0x00),
     };
 
-    gb.regs[RF] &= !(FLAG_Z ^ FLAG_N ^ FLAG_H);
+    gb.regs[RF] &= FLAG_C;
     if let IncDec::Dec = mode { gb.regs[RF] |= FLAG_N; }
     gb.regs[RF] |= compute_zero_flag(computed_value);
-    gb.regs[RF] |= compute_half_carry_flag(value, match mode {
-        IncDec::Inc => 1,
-        IncDec::Dec => (-1 as i8) as u8,
-    });
+    if half_carry { gb.regs[RF] |= FLAG_H; }
 
     dst.write(gb, computed_value);
 }
 
-pub fn add_sub(gb: &mut Gameboy, src: Src8, mode: AddSub, carry_mode: CarryMode) {
-    let mut value = src.read(gb);
-    if let CarryMode::WithCarry = carry_mode {
-        value += (gb.regs[RF] & FLAG_C) >> 4
-    }
-    let sum = match mode {
-        AddSub::Add => (Wrapping(gb.regs[RA]) + Wrapping(value)).0,
-        AddSub::Sub => (Wrapping(gb.regs[RA]) - Wrapping(value)).0,
+pub fn add_sub<B: Bus>(gb: &mut B, src: Src8, mode: AddSub, carry_mode: CarryMode) {
+    let value = src.read(gb);
+    let carry = match carry_mode {
+        CarryMode::WithCarry => (gb.regs[RF] & FLAG_C) >> 4,
+        CarryMode::NoCarry => 0,
+    };
+    gb.regs[RF] = 0;
+    gb.regs[RA] = match mode {
+        AddSub::Add => add_8(gb, gb.regs[RA], value, carry),
+        AddSub::Sub => sub_8(gb, gb.regs[RA], value, carry),
     };
-
-    gb.regs[RF] &= 0;
-    gb.regs[RF] |= compute_zero_flag(sum);
-    if let AddSub::Sub = mode { 
-        gb.regs[RF] |= FLAG_N;
-        gb.regs[RF] |= FLAG_H & !compute_half_carry_flag(gb.regs[RA], -(value as i8) as u8);
-        gb.regs[RF] |= FLAG_C & !compute_carry_flag(gb.regs[RA], -(value as i8) as u8);
-    } else {
-        gb.regs[RF] |= compute_half_carry_flag(gb.regs[RA], value);
-        gb.regs[RF] |= compute_carry_flag(gb.regs[RA], value);
-    }
-    
-    gb.regs[RA] = sum;
 }
 
-pub fn bitwise(gb: &mut Gameboy, src: Src8, operation: BitwiseOp) {
+pub fn bitwise<B: Bus>(gb: &mut B, src: Src8, operation: BitwiseOp) {
     let value = src.read(gb);
     let computed_value = match operation {
         BitwiseOp::And => gb.regs[RA] & value,
@@ -251,42 +183,45 @@ pub fn bitwise(gb: &mut Gameboy, src: Src8, operation: BitwiseOp) {
         BitwiseOp::Or  => gb.regs[RA] | value,
     };
 
-    gb.regs[RF] &= FLAG_H;
-    gb.regs[RF] |= compute_zero_flag(computed_value);
+    gb.regs[RF] = compute_zero_flag(computed_value);
+    if let BitwiseOp::And = operation { gb.regs[RF] |= FLAG_H; }
 
     gb.regs[RA] = computed_value;
 }
 
-pub fn cp(gb: &mut Gameboy, src: Src8) {
+pub fn cp<B: Bus>(gb: &mut B, src: Src8) {
     let value = src.read(gb);
-    let sum = (Wrapping(gb.regs[RA]) - Wrapping(value)).0;
-
-    gb.regs[RF] &= 0;
-    gb.regs[RF] |= compute_zero_flag(sum);
-    gb.regs[RF] |= FLAG_N;
-    gb.regs[RF] |= FLAG_H & !compute_half_carry_flag(gb.regs[RA], -(value as i8) as u8);
-    gb.regs[RF] |= FLAG_C & !compute_carry_flag(gb.regs[RA], -(value as i8) as u8);
+    gb.regs[RF] = 0;
+    sub_8(gb, gb.regs[RA], value, 0);
 }
 
-pub fn add_16_hl(gb: &mut Gameboy, src: Src16) {
+pub fn add_16_hl<B: Bus>(gb: &mut B, src: Src16) {
+    let hl = rr_to_u16(gb, RHL);
     let value = src.read(gb);
-    let sum = (Wrapping(rr_to_u16(gb, RHL)) + Wrapping(value)).0;
+    let sum = hl.wrapping_add(value);
 
-    gb.regs[RF] &= !(FLAG_N ^ FLAG_H ^ FLAG_C);
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] |= compute_half_carry_flag(gb.regs[RH], (value >> 8) as u8);
-    gb.regs[RF] |= compute_carry_flag(gb.regs[RH], (value >> 8) as u8);
+    // Z is left alone. H and C are carries out of bits 11 and 15.
+    gb.regs[RF] &= FLAG_Z;
+    if (hl & 0x0fff) + (value & 0x0fff) > 0x0fff {
+        gb.regs[RF] |= FLAG_H;
+    }
+    if (hl as u32) + (value as u32) > 0xffff {
+        gb.regs[RF] |= FLAG_C;
+    }
 
     gb.regs[RH] = (sum >> 8) as u8;
     gb.regs[RL] = sum as u8;
 }
 
-pub fn add_16_sp(gb: &mut Gameboy, n: i8) {
+pub fn add_16_sp<B: Bus>(gb: &mut B, n: i8) {
+    // Flags are set the same way as for LD HL,SP+n.
+    gb.regs[RF] = compute_half_carry_flag(gb.sp as u8, n as u8);
+    gb.regs[RF] |= compute_carry_flag(gb.sp as u8, n as u8);
     let value = Src16::SPD8(n).read(gb);
     Dst16::RSP.write(gb, value);
 }
 
-pub fn inc_dec_16(gb: &mut Gameboy, dst: Dst16, mode: IncDec) {
+pub fn inc_dec_16<B: Bus>(gb: &mut B, dst: Dst16, mode: IncDec) {
     let value = dst.read(gb);
     let new_value = match mode {
         IncDec::Inc => (Wrapping(value) + Wrapping(1)).0,
@@ -296,219 +231,158 @@ pub fn inc_dec_16(gb: &mut Gameboy, dst: Dst16, mode: IncDec) {
     dst.write(gb, new_value);
 }
 
-pub fn jp(gb: &mut Gameboy, src: Src16) {
+pub fn jp<B: Bus>(gb: &mut B, src: Src16) {
     gb.pc = src.read(gb);
 }
 
-pub fn jp_cond(gb: &mut Gameboy, cond: Cond, addr: u16) {
+pub fn jp_cond<B: Bus>(gb: &mut B, cond: Cond, addr: u16) {
     if cond.check(gb) {
         gb.pc = addr;
     }
 }
 
-pub fn jr(gb: &mut Gameboy, offset: i8) {
+pub fn jr<B: Bus>(gb: &mut B, offset: i8) {
     gb.pc = (Wrapping(gb.pc as i16) + Wrapping(2 as i16) + Wrapping(offset as i16)).0 as u16;
 }
 
-pub fn jr_cond(gb: &mut Gameboy, cond: Cond, offset: i8) {
+pub fn jr_cond<B: Bus>(gb: &mut B, cond: Cond, offset: i8) {
     if cond.check(gb) {
         gb.pc = (Wrapping(gb.pc as i16) + Wrapping(2 as i16) + Wrapping(offset as i16)).0 as u16;
     }
 }
 
-pub fn call(gb: &mut Gameboy, addr: u16) {
-    gb.pc += 3;
+pub fn call<B: Bus>(gb: &mut B, addr: u16) {
+    gb.pc = gb.pc.wrapping_add(3);
+    gb.tick(1);
     push_pc(gb);
     gb.pc = addr;
 }
 
-pub fn call_cond(gb: &mut Gameboy, cond: Cond, addr: u16) {
+pub fn call_cond<B: Bus>(gb: &mut B, cond: Cond, addr: u16) {
     if cond.check(gb) {
-        gb.pc += 3;
+        gb.pc = gb.pc.wrapping_add(3);
+        gb.tick(1);
         push_pc(gb);
         gb.pc = addr;
     }
 }
 
-pub fn ret(gb: &mut Gameboy) {
+pub fn ret<B: Bus>(gb: &mut B) {
     pop_pc(gb);
 }
 
-pub fn ret_cond(gb: &mut Gameboy, cond: Cond) {
+pub fn ret_cond<B: Bus>(gb: &mut B, cond: Cond) {
+    // The condition is checked in an internal cycle, whether or not it holds.
+    gb.tick(1);
     if cond.check(gb) {
         pop_pc(gb);
     }
 }
 
-pub fn reti(gb: &mut Gameboy) {
+pub fn reti<B: Bus>(gb: &mut B) {
     pop_pc(gb);
     gb.ime.store(true, Ordering::Relaxed);
 }
 
-pub fn rst(gb: &mut Gameboy, addr: u8) {
+pub fn rst<B: Bus>(gb: &mut B, addr: u8) {
+    gb.pc = gb.pc.wrapping_add(1);
+    gb.tick(1);
     push_pc(gb);
     gb.pc = addr as u16;
 }
 
-pub fn rlca(gb: &mut Gameboy) {
+pub fn rlca<B: Bus>(gb: &mut B) {
     rlc(gb, Dst8::R8(RA));
     gb.regs[RF] &= !FLAG_Z;
 }
 
-pub fn rla(gb: &mut Gameboy) {
+pub fn rla<B: Bus>(gb: &mut B) {
     rl(gb, Dst8::R8(RA));
     gb.regs[RF] &= !FLAG_Z;
 }
 
-pub fn rrca(gb: &mut Gameboy) {
+pub fn rrca<B: Bus>(gb: &mut B) {
     rrc(gb, Dst8::R8(RA));
     gb.regs[RF] &= !FLAG_Z;
 }
 
-pub fn rra(gb: &mut Gameboy) {
+pub fn rra<B: Bus>(gb: &mut B) {
     rr(gb, Dst8::R8(RA));
     gb.regs[RF] &= !FLAG_Z;
 }
 
-pub fn rlc(gb: &mut Gameboy, dst: Dst8) {
+pub fn rlc<B: Bus>(gb: &mut B, dst: Dst8) {
     // Copy bit 7 to both carry and bit 0
     let value = dst.read(gb);
     let bit7 = value >> 7;
-    dst.write(gb, value << 1);
-    let value = dst.read(gb);
-    if bit7 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-        dst.write(gb, value & !BIT_0);
-    } else {
-        gb.regs[RF] |= FLAG_C;
-        dst.write(gb, value | BIT_0);
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = (value << 1) | bit7;
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit7 << 4);
 }
 
-pub fn rrc(gb: &mut Gameboy, dst: Dst8) {
+pub fn rrc<B: Bus>(gb: &mut B, dst: Dst8) {
     // Copy bit 0 to both carry and bit 7
     let value = dst.read(gb);
     let bit0 = value & BIT_0;
-    dst.write(gb, value >> 1);
-    let value = dst.read(gb);
-    if bit0 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-        dst.write(gb, value & !BIT_7);
-    } else {
-        gb.regs[RF] |= FLAG_C;
-        gb.regs[RA] |= BIT_7;
-        dst.write(gb, value | BIT_7);
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = (value >> 1) | (bit0 << 7);
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit0 << 4);
 }
 
-pub fn rl(gb: &mut Gameboy, dst: Dst8) {
+pub fn rl<B: Bus>(gb: &mut B, dst: Dst8) {
     // Copy carry to bit 0, bit 7 to carry
     let value = dst.read(gb);
     let c = (gb.regs[RF] & FLAG_C) >> 4;
     let bit7 = value >> 7;
-    dst.write(gb, value << 1);
-    let value = dst.read(gb);
-    if c == 0 {
-        dst.write(gb, value & !BIT_0);
-    } else {
-        dst.write(gb, value | BIT_0);
-    }
-    if bit7 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-    } else {
-        gb.regs[RF] |= FLAG_C;
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = (value << 1) | c;
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit7 << 4);
 }
 
-pub fn rr(gb: &mut Gameboy, dst: Dst8) {
+pub fn rr<B: Bus>(gb: &mut B, dst: Dst8) {
     // Copy carry to bit 7, bit 0 to carry
     let value = dst.read(gb);
     let c = (gb.regs[RF] & FLAG_C) >> 4;
     let bit0 = value & BIT_0;
-    dst.write(gb, value >> 1);
-    let value = dst.read(gb);
-    if c == 0 {
-        dst.write(gb, value & !BIT_7);
-    } else {
-        dst.write(gb, value | BIT_7);
-    }
-    if bit0 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-    } else {
-        gb.regs[RF] |= FLAG_C;
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = (value >> 1) | (c << 7);
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit0 << 4);
 }
 
-pub fn sla(gb: &mut Gameboy, dst: Dst8) {
+pub fn sla<B: Bus>(gb: &mut B, dst: Dst8) {
     let value = dst.read(gb);
     let bit7 = value >> 7;
-    dst.write(gb, value << 1);
-    if bit7 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-    } else {
-        gb.regs[RF] |= FLAG_C;
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = value << 1;
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit7 << 4);
 }
 
-pub fn sra(gb: &mut Gameboy, dst: Dst8) {
+pub fn sra<B: Bus>(gb: &mut B, dst: Dst8) {
     // Like a normal right shift, but bit 7 is repeated
     let value = dst.read(gb);
-    let bit7 = value >> 7;
     let bit0 = value & BIT_0;
-    dst.write(gb, value >> 1);
-    let value = dst.read(gb);
-    if bit7 == 0 {
-        dst.write(gb, value & !BIT_7);
-    } else {
-        dst.write(gb, value | BIT_7);
-    }
-    if bit0 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-    } else {
-        gb.regs[RF] |= FLAG_C;
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = (value >> 1) | (value & BIT_7);
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit0 << 4);
 }
 
-pub fn srl(gb: &mut Gameboy, dst: Dst8) {
+pub fn srl<B: Bus>(gb: &mut B, dst: Dst8) {
     let value = dst.read(gb);
     let bit0 = value & BIT_0;
-    dst.write(gb, value >> 1);
-    if bit0 == 0 {
-        gb.regs[RF] &= !FLAG_C;
-    } else {
-        gb.regs[RF] |= FLAG_C;
-    }
-    gb.regs[RF] |= compute_zero_flag(dst.read(gb));
-    gb.regs[RF] &= !FLAG_N;
-    gb.regs[RF] &= !FLAG_H;
+    let value = value >> 1;
+    dst.write(gb, value);
+    gb.regs[RF] = compute_zero_flag(value) | (bit0 << 4);
 }
 
-pub fn swap(gb: &mut Gameboy, dst: Dst8) {
+pub fn swap<B: Bus>(gb: &mut B, dst: Dst8) {
     let value = dst.read(gb);
     let top = value >> 4;
     let bottom = value << 4;
     dst.write(gb, bottom | top);
+    gb.regs[RF] = compute_zero_flag(bottom | top);
 }
 
-pub fn bit(gb: &mut Gameboy, bt: u8, dst: Dst8) {
+pub fn bit<B: Bus>(gb: &mut B, bt: u8, dst: Dst8) {
     let value = dst.read(gb);
     if (value & (1 << bt)) == 0 {
         gb.regs[RF] |= FLAG_Z;
@@ -519,12 +393,12 @@ pub fn bit(gb: &mut Gameboy, bt: u8, dst: Dst8) {
     gb.regs[RF] |= FLAG_H;
 }
 
-pub fn res(gb: &mut Gameboy, bt: u8, dst: Dst8) {
+pub fn res<B: Bus>(gb: &mut B, bt: u8, dst: Dst8) {
     let value = dst.read(gb);
     dst.write(gb, value & !(BIT_0 << bt));
 }
 
-pub fn set(gb: &mut Gameboy, bt: u8, dst: Dst8) {
+pub fn set<B: Bus>(gb: &mut B, bt: u8, dst: Dst8) {
     let value = dst.read(gb);
     dst.write(gb, value | (BIT_0 << bt));
 }
@@ -542,6 +416,33 @@ fn compute_half_carry_flag(x: u8, y: u8) -> u8 {
     (sum_4_bit & 0b0001_0000) << 1
 }
 
+/// Adds y and a carry bit to x, setting Z, H and C accordingly. N is left as-is.
+fn add_8<B: Bus>(gb: &mut B, x: u8, y: u8, carry: u8) -> u8 {
+    let sum = x.wrapping_add(y).wrapping_add(carry);
+    gb.regs[RF] |= compute_zero_flag(sum);
+    if (x & 0x0f) + (y & 0x0f) + carry > 0x0f {
+        gb.regs[RF] |= FLAG_H;
+    }
+    if (x as u16) + (y as u16) + (carry as u16) > 0xff {
+        gb.regs[RF] |= FLAG_C;
+    }
+    sum
+}
+
+/// Subtracts y and a borrow bit from x, setting Z, N, H and C accordingly.
+fn sub_8<B: Bus>(gb: &mut B, x: u8, y: u8, borrow: u8) -> u8 {
+    let difference = x.wrapping_sub(y).wrapping_sub(borrow);
+    gb.regs[RF] |= FLAG_N;
+    gb.regs[RF] |= compute_zero_flag(difference);
+    if (x & 0x0f) < (y & 0x0f) + borrow {
+        gb.regs[RF] |= FLAG_H;
+    }
+    if (x as u16) < (y as u16) + (borrow as u16) {
+        gb.regs[RF] |= FLAG_C;
+    }
+    difference
+}
+
 fn compute_carry_flag(x: u8, y: u8) -> u8 {
     // Compute an 8-bit sum, check if the 9th bit is 1. 
     // If so, then a carry occurred.
@@ -549,17 +450,17 @@ fn compute_carry_flag(x: u8, y: u8) -> u8 {
     ((sum_8_bit & 0x100) >> 4) as u8
 }
 
-pub fn push_pc(gb: &mut Gameboy) {
-    gb.sp -= 1;
-    gb.write(gb.sp, (gb.pc >> 8) as u8);
-    gb.sp -= 1;
-    gb.write(gb.sp, gb.pc as u8);
+pub fn push_pc<B: Bus>(gb: &mut B) {
+    gb.sp = gb.sp.wrapping_sub(1);
+    gb.write_cycle(gb.sp, (gb.pc >> 8) as u8);
+    gb.sp = gb.sp.wrapping_sub(1);
+    gb.write_cycle(gb.sp, gb.pc as u8);
 }
 
-fn pop_pc(gb: &mut Gameboy) {
-    let lsb = gb.read(gb.sp);
-    gb.sp += 1;
-    let msb = gb.read(gb.sp);
-    gb.sp += 1;
+fn pop_pc<B: Bus>(gb: &mut B) {
+    let lsb = gb.read_cycle(gb.sp);
+    gb.sp = gb.sp.wrapping_add(1);
+    let msb = gb.read_cycle(gb.sp);
+    gb.sp = gb.sp.wrapping_add(1);
     gb.pc = ((msb as u16) << 8) | (lsb as u16);
 }
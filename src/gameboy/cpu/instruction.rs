@@ -1,6 +1,7 @@
 use std::fmt;
 use std::num::Wrapping;
-use crate::gameboy::gameboy::{*};
+use crate::gameboy::cpu::registers::{*};
+use crate::gameboy::cpu::bus::{Bus};
 
 #[derive(Debug, Copy, Clone)]
 pub enum CarryMode {
@@ -25,15 +26,17 @@ pub enum Src8 {
 }
 
 impl Src8 {
-    pub fn read(&self, gb: &Gameboy) -> u8 {
-        match *self {
-            Src8::R8(r) => gb.regs[r],
-            Src8::Id(rr) => gb.read(rr_to_u16(gb, rr)),
-            Src8::IdFFRC => gb.read(0xff00 | (gb.regs[RC] as u16)),
-            Src8::IdFF(n) => gb.read(0xff00 | (n as u16)),
-            Src8::IdNN(nn) => gb.read(nn),
-            Src8::D8(n) => n,
-        }
+    /// Reads the value, taking a machine cycle if it's in memory.
+    pub fn read<B: Bus>(&self, gb: &mut B) -> u8 {
+        let addr = match *self {
+            Src8::R8(r) => return gb.regs[r],
+            Src8::D8(n) => return n,
+            Src8::Id(rr) => rr_to_u16(gb, rr),
+            Src8::IdFFRC => 0xff00 | (gb.regs[RC] as u16),
+            Src8::IdFF(n) => 0xff00 | (n as u16),
+            Src8::IdNN(nn) => nn,
+        };
+        gb.read_cycle(addr)
     }
 }
 
@@ -52,23 +55,35 @@ pub enum Dst8 {
 }
 
 impl Dst8 {
-    pub fn read(&self, gb: &Gameboy) -> u8 {
+    /// Reads the value, taking a machine cycle if it's in memory.
+    pub fn read<B: Bus>(&self, gb: &mut B) -> u8 {
         match *self {
             Dst8::R8(r) => gb.regs[r],
-            Dst8::Id(rr) => gb.read(rr_to_u16(gb, rr)),
-            Dst8::IdFFRC => gb.read(0xff00 | (gb.regs[RC] as u16)),
-            Dst8::IdFF(n) => gb.read(0xff00 | (n as u16)),
-            Dst8::IdNN(nn) => gb.read(nn),
+            _ => {
+                let addr = self.addr(gb);
+                gb.read_cycle(addr)
+            },
         }
     }
 
-    pub fn write(&self, gb: &mut Gameboy, value: u8) {
+    /// Writes the value, taking a machine cycle if it's in memory.
+    pub fn write<B: Bus>(&self, gb: &mut B, value: u8) {
         match *self {
             Dst8::R8(r) => gb.regs[r] = value,
-            Dst8::Id(rr) => gb.write(rr_to_u16(gb, rr), value),
-            Dst8::IdFFRC => gb.write(0xff00 | (gb.regs[RC] as u16), value),
-            Dst8::IdFF(n) => gb.write(0xff00 | (n as u16), value),
-            Dst8::IdNN(nn) => gb.write(nn, value),
+            _ => {
+                let addr = self.addr(gb);
+                gb.write_cycle(addr, value)
+            },
+        }
+    }
+
+    fn addr(&self, gb: &Cpu) -> u16 {
+        match *self {
+            Dst8::R8(_) => unreachable!("Registers don't have an address"),
+            Dst8::Id(rr) => rr_to_u16(gb, rr),
+            Dst8::IdFFRC => 0xff00 | (gb.regs[RC] as u16),
+            Dst8::IdFF(n) => 0xff00 | (n as u16),
+            Dst8::IdNN(nn) => nn,
         }
     }
 }
@@ -86,7 +101,7 @@ pub enum Src16 {
 }
 
 impl Src16 {
-    pub fn read(&self, gb: &Gameboy) -> u16 {
+    pub fn read(&self, gb: &Cpu) -> u16 {
         match *self {
             Src16::R16(rr) => rr_to_u16(gb, rr),
             Src16::RSP => gb.sp,
@@ -107,19 +122,19 @@ pub enum Dst16 {
 }
 
 impl Dst16 {
-    pub fn read(&self, gb: &Gameboy) -> u16 {
+    pub fn read<B: Bus>(&self, gb: &mut B) -> u16 {
         match *self {
             Dst16::R16(rr) => rr_to_u16(gb, rr),
             Dst16::RSP => gb.sp,
             Dst16::IdNN(nn) => {
-                let high = (gb.read(nn) as u16) << 8;
-                let low = gb.read(nn + 1) as u16;
+                let low = gb.read_cycle(nn) as u16;
+                let high = (gb.read_cycle(nn.wrapping_add(1)) as u16) << 8;
                 high | low
             },
         }
     }
 
-    pub fn write(&self, gb: &mut Gameboy, value: u16) {
+    pub fn write<B: Bus>(&self, gb: &mut B, value: u16) {
         match *self {
             Dst16::R16(rr) => {
                 gb.regs[rr.0] = (value >> 8) as u8;
@@ -127,8 +142,8 @@ impl Dst16 {
             },
             Dst16::RSP => gb.sp = value,
             Dst16::IdNN(nn) => {
-                gb.write(nn, (gb.sp >> 8) as u8);
-                gb.write(nn + 1, gb.sp as u8);
+                gb.write_cycle(nn, gb.sp as u8);
+                gb.write_cycle(nn.wrapping_add(1), (gb.sp >> 8) as u8);
             },
         }
     }
@@ -158,7 +173,7 @@ pub enum Cond {
 }
 
 impl Cond {
-    pub fn check(&self, gb: &Gameboy) -> bool {
+    pub fn check(&self, gb: &Cpu) -> bool {
         match *self {
             Cond::Z => ((gb.regs[RF] & FLAG_Z) >> 7) == 1,
             Cond::Nz => ((gb.regs[RF] & FLAG_Z) >> 7) == 0,
@@ -180,6 +195,8 @@ pub enum Instr {
     Scf,
     Daa,
     Cpl,
+    /// One of the unused opcodes, which lock up the CPU.
+    Illegal(u8),
     // 8-bit load
     Ld(Dst8, Src8),
     LdInc(Dst8, Src8),
@@ -235,7 +252,7 @@ pub enum Instr {
 
 impl Instr {
     /// The number of machine cycles an instruction takes to execute.
-    pub fn num_cycles(&self, gb: &Gameboy) -> u64 {
+    pub fn num_cycles(&self, gb: &Cpu) -> u64 {
         match self {
             Instr::Nop => 1,
             Instr::Stop => 1,
@@ -246,6 +263,7 @@ impl Instr {
             Instr::Scf => 1,
             Instr::Daa => 1,
             Instr::Cpl => 1,
+            Instr::Illegal(_) => 1,
 
             Instr::Ld(dst, src) => match (dst, src) {
                 (Dst8::R8(_), Src8::R8(_)) => 1,
@@ -331,7 +349,7 @@ impl Instr {
     /// execute.
     /// The second value is the actual size, i.e. the number of bytes the instruction actually
     /// takes up.
-    pub fn size(&self, gb: &Gameboy) -> (u16, u16) {
+    pub fn size(&self, gb: &Cpu) -> (u16, u16) {
         match self {
             Instr::Nop => (1, 1),
             Instr::Stop => (2, 2),
@@ -342,6 +360,8 @@ impl Instr {
             Instr::Scf => (1, 1),
             Instr::Daa => (1, 1),
             Instr::Cpl => (1, 1),
+            // PC is left pointing at the opcode, since the CPU never gets past it.
+            Instr::Illegal(_) => (0, 1),
 
             Instr::Ld(dst, src) => match (dst, src) {
                 (Dst8::R8(_), Src8::R8(_)) => (1, 1),
@@ -490,6 +510,7 @@ impl Instr {
             Instr::Scf => String::from("scf"),
             Instr::Daa => String::from("daa"),
             Instr::Cpl => String::from("cpl"),
+            Instr::Illegal(opcode) => format!("illegal ${:0>2x}", opcode),
             Instr::Ld(dst, src) => {
                 let mut s = String::from("ld");
                 match dst {
@@ -0,0 +1,43 @@
+use std::sync::atomic::{Ordering};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::cpu::bus::{Bus};
+
+/// Interrupt vectors, from highest to lowest priority.
+const VECTORS: [(u8, u16); 5] = [
+    (INT_VBLANK, 0x0040),
+    (INT_LCDC, 0x0048),
+    (INT_TIMER, 0x0050),
+    (INT_SERIAL, 0x0058),
+    (INT_HILO, 0x0060),
+];
+
+/// Dispatches the highest-priority interrupt that is both enabled in IE and requested in IF, as
+/// long as IME is set. Returns whether an interrupt was dispatched.
+pub fn handle_interrupts<B: Bus>(gb: &mut B) -> bool {
+    let pending = gb.pending_interrupts();
+    if !gb.ime.load(Ordering::Relaxed) || pending == 0 {
+        return false;
+    }
+
+    gb.ime.store(false, Ordering::Relaxed);
+    // Dispatch takes 5 machine cycles: 2 internal ones, one for each byte of PC pushed, and one
+    // to jump to the vector.
+    gb.tick(2);
+
+    gb.sp = gb.sp.wrapping_sub(1);
+    gb.write_cycle(gb.sp, (gb.pc >> 8) as u8);
+    // The interrupt to service is only picked after the high byte of PC has been pushed. If that
+    // push overwrote IE, a different interrupt may get serviced, or none at all, in which case the
+    // CPU ends up at 0x0000.
+    let pending = gb.pending_interrupts();
+    gb.sp = gb.sp.wrapping_sub(1);
+    gb.write_cycle(gb.sp, gb.pc as u8);
+    gb.tick(1);
+
+    gb.pc = 0x0000;
+    if let Some(&(flag, vector)) = VECTORS.iter().find(|(flag, _)| pending & flag > 0) {
+        gb.acknowledge_interrupt(flag);
+        gb.pc = vector;
+    }
+    true
+}
@@ -1,9 +1,14 @@
+mod registers;
+mod bus;
 mod run;
 mod step;
 mod exec;
 mod instruction;
+mod interrupt;
 mod tests;
 
+pub use registers::{*};
+pub use bus::{*};
 pub use run::{*};
 pub use step::{*};
 pub use exec::{*};
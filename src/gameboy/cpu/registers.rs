@@ -0,0 +1,68 @@
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool};
+
+/// 8-bit register.
+pub type R = usize;
+/// 16-bit register, i.e. a pair of 8-bit registers used together.
+pub type RR = (usize, usize);
+
+pub const RB: R = 0;
+pub const RC: R = 1;
+pub const RD: R = 2;
+pub const RE: R = 3;
+pub const RH: R = 4;
+pub const RL: R = 5;
+pub const RF: R = 6;
+pub const RA: R = 7;
+
+pub const RAF: RR = (RA, RF);
+pub const RBC: RR = (RB, RC);
+pub const RDE: RR = (RD, RE);
+pub const RHL: RR = (RH, RL);
+
+pub const FLAG_Z: u8 = 0b10000000;
+pub const FLAG_N: u8 = 0b01000000;
+pub const FLAG_H: u8 = 0b00100000;
+pub const FLAG_C: u8 = 0b00010000;
+
+/// SM83 registers and execution state. Every bus owns one of these and exposes it through
+/// DerefMut, so that instructions can get at the registers the same way whatever they run on.
+pub struct Cpu {
+    pub pc: u16,
+    pub sp: u16,
+    pub regs: [u8; 8],
+    pub ime: Arc<AtomicBool>,
+    /// Set by EI. IME only gets enabled once the instruction after EI has executed.
+    pub ime_scheduled: bool,
+    pub halted: Arc<AtomicBool>,
+    pub stopped: Arc<AtomicBool>,
+    /// Set when HALT is executed with IME=0 while an interrupt is already pending. The CPU then
+    /// fails to increment PC after fetching the next opcode, so that byte gets read twice.
+    pub halt_bug: bool,
+    /// Set when the CPU executes an illegal opcode. It then stops fetching instructions and
+    /// servicing interrupts for good, while the rest of the system keeps running.
+    pub locked_up: bool,
+}
+
+impl Cpu {
+    pub fn new(pc: u16, regs: [u8; 8]) -> Self {
+        Self {
+            pc,
+            sp: 0xfffe,
+            regs,
+            ime: Arc::new(AtomicBool::new(false)),
+            ime_scheduled: false,
+            halted: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            halt_bug: false,
+            locked_up: false,
+        }
+    }
+}
+
+/// Converts a register pair alias into a u16.
+pub fn rr_to_u16(cpu: &Cpu, reg_pair: RR) -> u16 {
+    let upper = (cpu.regs[reg_pair.0] as u16) << 8;
+    let lower = cpu.regs[reg_pair.1] as u16;
+    upper ^ lower
+}
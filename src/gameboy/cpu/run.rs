@@ -1,102 +1,145 @@
 use std::time::{Duration, Instant};
 use std::sync::atomic::{Ordering};
-use std::thread::{JoinHandle};
 use std::io::{self, Write};
+use std::thread;
 use crate::gameboy::gameboy::{*};
 use crate::gameboy::debug::{*};
 use crate::gameboy::debug_info::{DebugInfoCpu};
 use crate::gameboy::cpu::step::{step, decode};
-use crate::gameboy::cpu::exec::{push_pc};
+use crate::gameboy::cpu::interrupt::{handle_interrupts};
+use crate::gameboy::hdma::{run_hdma_hblank};
+use crate::gameboy::joypad::{joypad_lines};
+use crate::gameboy::movie::{run_movie_frame};
+use crate::gameboy::ppu::{FRAME_TIME};
+use crate::gameboy::scheduler::{tick};
 use crate::gameboy::utils::{sleep_precise};
 
-pub fn run_cpu<T>(gb: &mut Gameboy, debug_info: DebugInfoCpu, components: &[JoinHandle<T>]) {
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
+/// How often to check the joypad while the system is stopped.
+const STOPPED_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// How often to check whether emulation has been unpaused.
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(1);
 
+pub fn run_cpu(gb: &mut Gameboy, debug_info: DebugInfoCpu) {
     loop {
-        if gb.halted.load(Ordering::Relaxed) {
-            let (mutex, cvar) = &*gb.interrupt_received;
-            let mut interrupted = mutex.lock().unwrap();
-            while !*interrupted {
-                interrupted = cvar.wait(interrupted).unwrap();
-            }
-            *interrupted = false;
-            gb.halted.store(false, Ordering::Relaxed);
-        } 
+        if gb.speed.is_paused() {
+            thread::sleep(PAUSED_POLL_INTERVAL);
+            continue;
+        }
 
         let cpu_start = Instant::now();
+        let cycles_start = gb.cycles;
 
-        let io_if = gb.io_ports.read(IO_IF);
-        if gb.ime.load(Ordering::Relaxed) && io_if > 0 {
-            push_pc(gb);
+        if gb.locked_up {
+            // Only a reset gets the CPU going again, but the debugger can still be used to
+            // inspect it.
+            if gb.debug.step_mode.load(Ordering::Acquire) {
+                debug_prompt(gb);
+            }
+            tick(gb, 1);
+            pace(gb, &debug_info, cpu_start, cycles_start);
+            continue;
+        }
 
-            if io_if & INT_VBLANK > 0 {
-                gb.pc = 0x0040;
-                gb.io_ports.and(IO_IF, !INT_VBLANK);
-            } else if io_if & INT_LCDC > 0 {
-                gb.pc = 0x0048;
-                gb.io_ports.and(IO_IF, !INT_LCDC);
-            } else if io_if & INT_TIMER > 0 {
-                gb.pc = 0x0050;
-                gb.io_ports.and(IO_IF, !INT_TIMER);
-            } else if io_if & INT_SERIAL > 0 {
-                gb.pc = 0x0058;
-                gb.io_ports.and(IO_IF, !INT_SERIAL);
-            } else if io_if & INT_HILO > 0 {
-                gb.pc = 0x0060;
-                gb.io_ports.and(IO_IF, !INT_HILO);
+        if gb.stopped.load(Ordering::Relaxed) {
+            // Every clock is stopped, so there's nothing to run until a joypad input line goes
+            // low, which wakes the system back up whether or not the joypad interrupt is enabled.
+            // No frames go by while stopped, so a movie moves on in real time instead, one frame
+            // per check, which lets playback wake up on the same frame as the recording did. Each
+            // check also counts as a frame for pausing.
+            let movie_active = gb.movie.is_some();
+            if movie_active {
+                run_movie_frame(gb);
             }
+            gb.speed.start_frame();
+            if joypad_lines(gb) == 0b0000_1111 {
+                if movie_active {
+                    if let Some(frame_time) = gb.speed.speed().scale(FRAME_TIME) {
+                        thread::sleep(frame_time);
+                    }
+                } else {
+                    thread::sleep(STOPPED_POLL_INTERVAL);
+                }
+                continue;
+            }
+            gb.stopped.store(false, Ordering::Relaxed);
+        }
 
-            gb.ime.store(false, Ordering::Relaxed);
-            let (mutex, _) = &*gb.interrupt_received;
-            let mut interrupted = mutex.lock().unwrap();
-            *interrupted = false;
+        if gb.halted.load(Ordering::Relaxed) {
+            // The rest of the system keeps running while the CPU is halted, so it has to be
+            // ticked one cycle at a time until an interrupt is pending. HALT exits once that
+            // happens even if IME=0, in which case the interrupt just isn't dispatched.
+            if gb.io_ports.read(IO_IE) & gb.io_ports.read(IO_IF) & INT_ALL > 0 {
+                gb.halted.store(false, Ordering::Relaxed);
+            } else {
+                tick(gb, 1);
+                pace(gb, &debug_info, cpu_start, cycles_start);
+                continue;
+            }
         }
 
+        handle_interrupts(gb);
+
         if gb.debug.breakpoints.contains(&gb.pc) || gb.pc == gb.debug.over_ret_addr {
             gb.debug.step_mode.store(true, Ordering::Release);
         }
 
         if gb.debug.step_mode.load(Ordering::Acquire) {
-            loop {
-                println!("==> ${:0>4X}: {}",
-                         gb.pc, 
-                         decode(gb, gb.pc).map(|i| i.to_string()).unwrap_or("".to_string()));
-                print!("> ");
-                stdout.flush().unwrap();
-                let mut line = String::new();
-                stdin.read_line(&mut line).unwrap();
-                match DebugCmd::new(&line) {
-                    Ok(cmd) => {
-                        match cmd.run(gb) {
-                            Ok(exit_prompt_loop) => {
-                                if exit_prompt_loop { break; }
-                            },
-                            Err(err) => eprintln!("{}", err),
-                        }
-                    },
-                    Err(err) => eprintln!("{}", err),
-                }
-            }
-
-            unpark_components(components);
+            debug_prompt(gb);
         }
 
-        let cycles_start = gb.cycles;
         step(gb).unwrap();
+        if gb.locked_up {
+            eprintln!("CPU locked up by illegal opcode ${:0>2X} at ${:0>4X}", gb.read(gb.pc), gb.pc);
+        }
+        run_hdma_hblank(gb);
+
+        pace(gb, &debug_info, cpu_start, cycles_start);
+    }
+}
 
-        let elapsed = cpu_start.elapsed();
-        let expected = Duration::from_micros(gb.cycles - cycles_start); 
-        if expected > elapsed {
-            sleep_precise(expected - elapsed);
+/// Reads and runs debugger commands until one of them resumes execution.
+fn debug_prompt(gb: &mut Gameboy) {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    loop {
+        println!("==> ${:0>4X}: {}{}",
+                 gb.pc,
+                 decode(gb, gb.pc).map(|i| i.to_string()).unwrap_or("".to_string()),
+                 if gb.locked_up { " (locked up)" } else { "" });
+        print!("> ");
+        stdout.flush().unwrap();
+        let mut line = String::new();
+        stdin.read_line(&mut line).unwrap();
+        match DebugCmd::new(&line) {
+            Ok(cmd) => {
+                match cmd.run(gb) {
+                    Ok(exit_prompt_loop) => {
+                        if exit_prompt_loop { break; }
+                    },
+                    Err(err) => eprintln!("{}", err),
+                }
+            },
+            Err(err) => eprintln!("{}", err),
         }
-        debug_info.actual_time_nanos.store(cpu_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
-        debug_info.expected_time_nanos.store(expected.as_nanos() as u64, Ordering::Relaxed);
     }
 }
 
-fn unpark_components<T>(components: &[JoinHandle<T>]) {
-    for component in components.iter() {
-        component.thread().unpark();
+/// Sleeps until the cycles run since cycles_start have taken as long as they would on hardware,
+/// adjusted for the emulation speed.
+fn pace(gb: &Gameboy, debug_info: &DebugInfoCpu, cpu_start: Instant, cycles_start: u64) {
+    let elapsed = cpu_start.elapsed();
+    // In double speed mode, each machine cycle takes half as long.
+    let expected = if gb.double_speed.load(Ordering::Relaxed) {
+        Duration::from_nanos((gb.cycles - cycles_start) * 500)
+    } else {
+        Duration::from_micros(gb.cycles - cycles_start)
+    };
+    if let Some(target) = gb.speed.speed().scale(expected) {
+        if target > elapsed {
+            sleep_precise(target - elapsed);
+        }
     }
+    debug_info.actual_time_nanos.store(cpu_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    debug_info.expected_time_nanos.store(expected.as_nanos() as u64, Ordering::Relaxed);
 }
@@ -1,10 +1,35 @@
-use crate::gameboy::gameboy::{*};
+use std::sync::atomic::{Ordering};
+use crate::gameboy::cpu::registers::{*};
+use crate::gameboy::cpu::bus::{Bus};
 use crate::gameboy::cpu::exec::{*};
 use crate::gameboy::cpu::instruction::{*};
 
-/// Decode the instruction at PC, then execute it and update PC and cycle count accordingly.
-pub fn step(gb: &mut Gameboy) -> Result<(), String> {
-    let instr = decode(gb, gb.pc)?;
+/// Decode the instruction at PC, then execute it and update PC accordingly.
+/// The rest of the system is ticked along with every memory access the instruction makes, and for
+/// any internal cycles left over at the end.
+pub fn step<B: Bus>(gb: &mut B) -> Result<(), String> {
+    let enable_ime = gb.ime_scheduled;
+    let halt_bug = gb.halt_bug;
+    gb.halt_bug = false;
+
+    let instr = if halt_bug {
+        // PC didn't get incremented after the opcode was fetched, so the opcode gets read again as
+        // the first byte of the instruction's argument.
+        decode_bytes(gb.read(gb.pc), gb.read(gb.pc), gb.read(gb.pc.wrapping_add(1)))?
+    } else {
+        decode(gb, gb.pc)?
+    };
+    // These depend on flags that the instruction might change, so get them up front.
+    let num_cycles = instr.num_cycles(gb);
+    let (size, actual_size) = instr.size(gb);
+
+    let cycles_start = gb.cycles();
+    // Fetching the opcode and each argument byte takes a cycle.
+    for i in 0..u64::min(actual_size as u64, num_cycles) as u16 {
+        let offset = if halt_bug && i > 0 { i - 1 } else { i };
+        gb.read_cycle(gb.pc.wrapping_add(offset));
+    }
+
     match instr {
         // Control/misc
         Instr::Nop             => (),
@@ -16,6 +41,7 @@ pub fn step(gb: &mut Gameboy) -> Result<(), String> {
         Instr::Scf             => scf(gb),
         Instr::Daa             => daa(gb),
         Instr::Cpl             => cpl(gb),
+        Instr::Illegal(_)      => lock_up(gb),
         // 8-bit load
         Instr::Ld(dst, src)    => ld(gb, dst, src),
         Instr::LdInc(dst, src) => ld_inc_dec(gb, dst, src, IncDec::Inc),
@@ -68,19 +94,27 @@ pub fn step(gb: &mut Gameboy) -> Result<(), String> {
         Instr::Set(bt, dst)    => set(gb, bt, dst),
         Instr::Swap(dst)       => swap(gb, dst),
     }
-    gb.pc += instr.size(gb).0;
-    gb.cycles += instr.num_cycles(gb);
+    gb.pc = gb.pc.wrapping_add(if halt_bug && size > 0 { size - 1 } else { size });
+    gb.tick(num_cycles.saturating_sub(gb.cycles() - cycles_start));
+
+    // EI only takes effect after the instruction following it, unless that instruction was DI.
+    if enable_ime && gb.ime_scheduled {
+        gb.ime.store(true, Ordering::Relaxed);
+        gb.ime_scheduled = false;
+    }
     Ok(())
 }
 
-pub fn decode(gb: &Gameboy, addr: u16) -> Result<Instr, String> {
-    let opcode = gb.read(addr);
-    // The bottom three bits of the opcode are used to indicate src reg for certain loads
-    let src_reg = reg_encoding_to_src(opcode & 0b0000_0111)?;
+pub fn decode<B: Bus>(gb: &B, addr: u16) -> Result<Instr, String> {
     // Grab data from addr+1 and addr+2 in case we need them as arguments
     // This shouldn't go out of bounds since instructions aren't executed in top of mem
-    let n = gb.read(addr + 1);
-    let n2 = gb.read(addr + 2);
+    decode_bytes(gb.read(addr), gb.read(addr.wrapping_add(1)), gb.read(addr.wrapping_add(2)))
+}
+
+/// Decodes an instruction from its opcode and the two bytes following it.
+pub fn decode_bytes(opcode: u8, n: u8, n2: u8) -> Result<Instr, String> {
+    // The bottom three bits of the opcode are used to indicate src reg for certain loads
+    let src_reg = reg_encoding_to_src(opcode & 0b0000_0111)?;
     // n and n2 intepreted as LSB 16-bit value
     let nn = ((n2 as u16) << 8) | (n as u16);
     // For 0xCB instructions, n encodes a register in the bottom three bits
@@ -230,7 +264,7 @@ pub fn decode(gb: &Gameboy, addr: u16) -> Result<Instr, String> {
         0xd0        => Ok(Instr::RetCC(Cond::Nc)),
         0xd1        => Ok(Instr::Pop(RDE)),
         0xd2        => Ok(Instr::JpCC(Cond::Nc, nn)),
-        0xd3        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xd3        => Ok(Instr::Illegal(opcode)),
         0xd4        => Ok(Instr::CallCC(Cond::Nc, nn)),
         0xd5        => Ok(Instr::Push(RDE)),
         0xd6        => Ok(Instr::Sub(Src8::D8(n))),
@@ -238,26 +272,26 @@ pub fn decode(gb: &Gameboy, addr: u16) -> Result<Instr, String> {
         0xd8        => Ok(Instr::RetCC(Cond::C)),
         0xd9        => Ok(Instr::Reti),
         0xda        => Ok(Instr::JpCC(Cond::C, nn)),
-        0xdb        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xdb        => Ok(Instr::Illegal(opcode)),
         0xdc        => Ok(Instr::CallCC(Cond::C, nn)),
-        0xdd        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xdd        => Ok(Instr::Illegal(opcode)),
         0xde        => Ok(Instr::Sbc(Src8::D8(n))),
         0xdf        => Ok(Instr::Rst(0x18)),
 
         0xe0        => Ok(Instr::Ld(Dst8::IdFF(n), Src8::R8(RA))),
         0xe1        => Ok(Instr::Pop(RHL)),
         0xe2        => Ok(Instr::Ld(Dst8::IdFFRC, Src8::R8(RA))),
-        0xe3        => Err(format!("Invalid opcode {:#2x}", opcode)),
-        0xe4        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xe3        => Ok(Instr::Illegal(opcode)),
+        0xe4        => Ok(Instr::Illegal(opcode)),
         0xe5        => Ok(Instr::Push(RHL)),
         0xe6        => Ok(Instr::And(Src8::D8(n))),
         0xe7        => Ok(Instr::Rst(0x20)),
         0xe8        => Ok(Instr::Add16SP(n as i8)),
         0xe9        => Ok(Instr::Jp(Src16::R16(RHL))),
         0xea        => Ok(Instr::Ld(Dst8::IdNN(nn), Src8::R8(RA))),
-        0xeb        => Err(format!("Invalid opcode {:#2x}", opcode)),
-        0xec        => Err(format!("Invalid opcode {:#2x}", opcode)),
-        0xed        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xeb        => Ok(Instr::Illegal(opcode)),
+        0xec        => Ok(Instr::Illegal(opcode)),
+        0xed        => Ok(Instr::Illegal(opcode)),
         0xee        => Ok(Instr::Xor(Src8::D8(n))),
         0xef        => Ok(Instr::Rst(0x28)),
 
@@ -265,7 +299,7 @@ pub fn decode(gb: &Gameboy, addr: u16) -> Result<Instr, String> {
         0xf1        => Ok(Instr::Pop(RAF)),
         0xf2        => Ok(Instr::Ld(Dst8::R8(RA), Src8::IdFFRC)),
         0xf3        => Ok(Instr::Di),
-        0xf4        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xf4        => Ok(Instr::Illegal(opcode)),
         0xf5        => Ok(Instr::Push(RAF)),
         0xf6        => Ok(Instr::Or(Src8::D8(n))),
         0xf7        => Ok(Instr::Rst(0x30)),
@@ -273,8 +307,8 @@ pub fn decode(gb: &Gameboy, addr: u16) -> Result<Instr, String> {
         0xf9        => Ok(Instr::Ld16(Dst16::RSP, Src16::R16(RHL))),
         0xfa        => Ok(Instr::Ld(Dst8::R8(RA), Src8::IdNN(nn))),
         0xfb        => Ok(Instr::Ei),
-        0xfc        => Err(format!("Invalid opcode {:#2x}", opcode)),
-        0xfd        => Err(format!("Invalid opcode {:#2x}", opcode)),
+        0xfc        => Ok(Instr::Illegal(opcode)),
+        0xfd        => Ok(Instr::Illegal(opcode)),
         0xfe        => Ok(Instr::Cp(Src8::D8(n))),
         0xff        => Ok(Instr::Rst(0x38)),
     }
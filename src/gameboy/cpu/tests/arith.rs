@@ -1,11 +1,10 @@
-use super::utils::test_cartridge;
-use crate::gameboy::gameboy::{*};
+use super::utils::{test_bus};
+use crate::gameboy::cpu::{*};
 use crate::gameboy::cpu::step::{step};
 
 #[test]
 fn inc_r8() {
-    let cartridge = test_cartridge(vec!(0x0c));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x0c));
     gb.regs[RC] = 0x05;
 
     step(&mut gb).unwrap();
@@ -18,8 +17,7 @@ fn inc_r8() {
 
 #[test]
 fn inc_r8_overflow() {
-    let cartridge = test_cartridge(vec!(0x0c));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x0c));
     gb.regs[RC] = 0xff;
 
     step(&mut gb).unwrap();
@@ -32,50 +30,46 @@ fn inc_r8_overflow() {
 
 #[test]
 fn dec_r8() {
-    let cartridge = test_cartridge(vec!(0x0d));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x0d));
     gb.regs[RC] = 0x05;
 
     step(&mut gb).unwrap();
 
     assert_eq!(gb.regs[RC], 0x04);
-    assert_eq!(gb.regs[RF], FLAG_N | FLAG_H);
+    assert_eq!(gb.regs[RF], FLAG_N);
     assert_eq!(gb.cycles, 1);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn dec_r8_z() {
-    let cartridge = test_cartridge(vec!(0x0d));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x0d));
     gb.regs[RC] = 0x01;
 
     step(&mut gb).unwrap();
 
     assert_eq!(gb.regs[RC], 0x00);
-    assert_eq!(gb.regs[RF], FLAG_Z | FLAG_N | FLAG_H);
+    assert_eq!(gb.regs[RF], FLAG_Z | FLAG_N);
     assert_eq!(gb.cycles, 1);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn dec_r8_underflow() {
-    let cartridge = test_cartridge(vec!(0x0d));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x0d));
     gb.regs[RC] = 0x00;
 
     step(&mut gb).unwrap();
 
     assert_eq!(gb.regs[RC], 0xff);
-    assert_eq!(gb.regs[RF], FLAG_N);
+    assert_eq!(gb.regs[RF], FLAG_N | FLAG_H);
     assert_eq!(gb.cycles, 1);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn inc_id_hl() {
-    let cartridge = test_cartridge(vec!(0x34));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x34));
     gb.write(0xc234, 0x05);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -90,8 +84,7 @@ fn inc_id_hl() {
 
 #[test]
 fn inc_id_hl_overflow() {
-    let cartridge = test_cartridge(vec!(0x34));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x34));
     gb.write(0xc234, 0xff);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -106,8 +99,7 @@ fn inc_id_hl_overflow() {
 
 #[test]
 fn dec_id_hl() {
-    let cartridge = test_cartridge(vec!(0x35));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x35));
     gb.write(0xc234, 0x05);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -115,15 +107,14 @@ fn dec_id_hl() {
     step(&mut gb).unwrap();
 
     assert_eq!(gb.read(0xc234), 0x04);
-    assert_eq!(gb.regs[RF], FLAG_N | FLAG_H);
+    assert_eq!(gb.regs[RF], FLAG_N);
     assert_eq!(gb.cycles, 3);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn dec_id_hl_z() {
-    let cartridge = test_cartridge(vec!(0x35));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x35));
     gb.write(0xc234, 0x01);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -131,15 +122,14 @@ fn dec_id_hl_z() {
     step(&mut gb).unwrap();
 
     assert_eq!(gb.read(0xc234), 0x00);
-    assert_eq!(gb.regs[RF], FLAG_Z | FLAG_N | FLAG_H);
+    assert_eq!(gb.regs[RF], FLAG_Z | FLAG_N);
     assert_eq!(gb.cycles, 3);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn dec_id_hl_underflow() {
-    let cartridge = test_cartridge(vec!(0x35));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x35));
     gb.write(0xc234, 0x00);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -147,15 +137,14 @@ fn dec_id_hl_underflow() {
     step(&mut gb).unwrap();
 
     assert_eq!(gb.read(0xc234), 0xFF);
-    assert_eq!(gb.regs[RF], FLAG_N);
+    assert_eq!(gb.regs[RF], FLAG_N | FLAG_H);
     assert_eq!(gb.cycles, 3);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn add_r8() {
-    let cartridge = test_cartridge(vec!(0x83));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x83));
     gb.regs[RF] = FLAG_C;
     gb.regs[RA] = 0x05;
     gb.regs[RE] = 0x94;
@@ -170,8 +159,7 @@ fn add_r8() {
 
 #[test]
 fn adc_r8() {
-    let cartridge = test_cartridge(vec!(0x8b));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x8b));
     gb.regs[RF] = FLAG_C;
     gb.regs[RA] = 0x05;
     gb.regs[RE] = 0x94;
@@ -186,8 +174,7 @@ fn adc_r8() {
 
 #[test]
 fn sub_r8() {
-    let cartridge = test_cartridge(vec!(0x93));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x93));
     gb.regs[RF] = FLAG_C;
     gb.regs[RA] = 0x99;
     gb.regs[RE] = 0x05;
@@ -202,8 +189,7 @@ fn sub_r8() {
 
 #[test]
 fn sbc_r8() {
-    let cartridge = test_cartridge(vec!(0x9b));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x9b));
     gb.regs[RF] = FLAG_C;
     gb.regs[RA] = 0x99;
     gb.regs[RE] = 0x05;
@@ -218,8 +204,7 @@ fn sbc_r8() {
 
 #[test]
 fn cp_eq() {
-    let cartridge = test_cartridge(vec!(0xbb));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xbb));
     gb.regs[RA] = 0x99;
     gb.regs[RE] = 0x99;
 
@@ -232,8 +217,7 @@ fn cp_eq() {
 
 #[test]
 fn cp_lt() {
-    let cartridge = test_cartridge(vec!(0xbb));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xbb));
     gb.regs[RA] = 0x99;
     gb.regs[RE] = 0x9a;
 
@@ -246,8 +230,7 @@ fn cp_lt() {
 
 #[test]
 fn cp_gt() {
-    let cartridge = test_cartridge(vec!(0xbb));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xbb));
     gb.regs[RA] = 0x9a;
     gb.regs[RE] = 0x99;
 
@@ -260,8 +243,7 @@ fn cp_gt() {
 
 #[test]
 fn add_16_hl() {
-    let cartridge = test_cartridge(vec!(0x19));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x19));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.regs[RD] = 0x00;
@@ -280,8 +262,7 @@ fn add_16_hl() {
 #[test]
 fn add_16_hl_h() {
     // TODO finish
-    let cartridge = test_cartridge(vec!(0x19));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x19));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.regs[RD] = 0x00;
@@ -299,11 +280,10 @@ fn add_16_hl_h() {
 
 #[test]
 fn daa_add() {
-    let cartridge = test_cartridge(vec!(
+    let mut gb = test_bus(vec!(
         0x80, // add A, B
         0x27, // daa
     ));
-    let mut gb = Gameboy::new(cartridge);
     gb.regs[RA] = 0x45;
     gb.regs[RB] = 0x38;
     gb.regs[RF] = FLAG_H;
@@ -319,11 +299,10 @@ fn daa_add() {
 
 #[test]
 fn daa_sub() {
-    let cartridge = test_cartridge(vec!(
+    let mut gb = test_bus(vec!(
         0x90, // sub B
         0x27, // daa
     ));
-    let mut gb = Gameboy::new(cartridge);
     gb.regs[RA] = 0x83;
     gb.regs[RB] = 0x38;
     gb.regs[RF] = FLAG_H;
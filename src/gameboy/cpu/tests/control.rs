@@ -0,0 +1,184 @@
+use std::sync::atomic::{Ordering};
+use super::utils::{test_bus, test_gameboy};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::cpu::{*};
+use crate::gameboy::bess::{*};
+use crate::gameboy::cpu::step::{step};
+
+#[test]
+fn ei_delayed_by_one_instruction() {
+    let mut gb = test_bus(vec!(0xfb, 0x00));
+
+    step(&mut gb).unwrap();
+
+    assert!(!gb.ime.load(Ordering::Relaxed));
+
+    step(&mut gb).unwrap();
+
+    assert!(gb.ime.load(Ordering::Relaxed));
+    assert_eq!(gb.cycles, 2);
+    assert_eq!(gb.pc, 0x0102);
+}
+
+#[test]
+fn ei_cancelled_by_di() {
+    let mut gb = test_bus(vec!(0xfb, 0xf3, 0x00));
+
+    step(&mut gb).unwrap();
+    step(&mut gb).unwrap();
+    step(&mut gb).unwrap();
+
+    assert!(!gb.ime.load(Ordering::Relaxed));
+}
+
+#[test]
+fn halt() {
+    let mut gb = test_bus(vec!(0x76));
+    gb.write(0xffff, INT_TIMER);
+    gb.write(0xff0f, INT_VBLANK);
+
+    step(&mut gb).unwrap();
+
+    assert!(gb.halted.load(Ordering::Relaxed));
+    assert!(!gb.halt_bug);
+    assert_eq!(gb.cycles, 1);
+    assert_eq!(gb.pc, 0x0101);
+}
+
+#[test]
+fn halt_ime_interrupt_pending() {
+    let mut gb = test_bus(vec!(0x76));
+    gb.ime.store(true, Ordering::Relaxed);
+    gb.write(0xffff, INT_TIMER);
+    gb.write(0xff0f, INT_TIMER);
+
+    step(&mut gb).unwrap();
+
+    // The CPU halts, and then immediately wakes up to dispatch the interrupt.
+    assert!(gb.halted.load(Ordering::Relaxed));
+    assert!(!gb.halt_bug);
+}
+
+#[test]
+fn halt_bug() {
+    let mut gb = test_bus(vec!(0x76, 0x3c, 0x00));
+    gb.write(0xffff, INT_TIMER);
+    gb.write(0xff0f, INT_TIMER);
+
+    step(&mut gb).unwrap();
+
+    assert!(!gb.halted.load(Ordering::Relaxed));
+    assert!(gb.halt_bug);
+
+    // INC A gets executed twice.
+    step(&mut gb).unwrap();
+
+    assert_eq!(gb.regs[RA], 1);
+    assert_eq!(gb.pc, 0x0101);
+
+    step(&mut gb).unwrap();
+
+    assert_eq!(gb.regs[RA], 2);
+    assert_eq!(gb.pc, 0x0102);
+}
+
+#[test]
+fn halt_bug_with_argument() {
+    let mut gb = test_bus(vec!(0x76, 0x3e, 0x14));
+    gb.write(0xffff, INT_TIMER);
+    gb.write(0xff0f, INT_TIMER);
+
+    step(&mut gb).unwrap();
+    // LD A, n reads its own opcode as n, and the actual n gets executed as INC D afterwards.
+    step(&mut gb).unwrap();
+
+    assert_eq!(gb.regs[RA], 0x3e);
+    assert_eq!(gb.pc, 0x0102);
+
+    step(&mut gb).unwrap();
+
+    assert_eq!(gb.regs[RD], 0x01);
+    assert_eq!(gb.pc, 0x0103);
+}
+
+#[test]
+fn illegal_opcode_locks_up() {
+    for &opcode in [0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd].iter() {
+        let mut gb = test_bus(vec!(opcode));
+
+        step(&mut gb).unwrap();
+
+        assert!(gb.locked_up);
+        assert_eq!(gb.pc, 0x0100);
+        assert_eq!(gb.cycles, 1);
+    }
+}
+
+#[test]
+fn stop() {
+    let mut gb = test_gameboy(vec!(0x10, 0x00));
+    gb.io_ports.write(IO_DIV, 0xab);
+
+    step(&mut gb).unwrap();
+
+    assert!(gb.stopped.load(Ordering::Relaxed));
+    assert_eq!(gb.execution_state(), EXECUTION_STATE_STOPPED);
+    assert_eq!(gb.io_ports.read(IO_DIV), 0);
+    assert_eq!(gb.pc, 0x0102);
+}
+
+#[test]
+fn restore_execution_state() {
+    let mut gb = test_gameboy(vec!());
+    for &state in [EXECUTION_STATE_HALTED, EXECUTION_STATE_STOPPED, EXECUTION_STATE_RUNNING].iter() {
+        gb.set_execution_state(state);
+        assert_eq!(gb.execution_state(), state);
+    }
+    assert!(!gb.halted.load(Ordering::Relaxed));
+    assert!(!gb.stopped.load(Ordering::Relaxed));
+}
+
+#[test]
+fn stop_button_held() {
+    let mut gb = test_gameboy(vec!(0x10, 0x00));
+    gb.controller_data.store(!CONTROLLER_DATA_A, Ordering::Relaxed);
+    gb.write(0xff00, P1_P14_OUT);
+
+    step(&mut gb).unwrap();
+
+    // STOP turns into HALT instead.
+    assert!(!gb.stopped.load(Ordering::Relaxed));
+    assert_eq!(gb.execution_state(), EXECUTION_STATE_HALTED);
+    assert_eq!(gb.pc, 0x0102);
+}
+
+#[test]
+fn stop_button_held_interrupt_pending() {
+    let mut gb = test_gameboy(vec!(0x10, 0x00));
+    gb.controller_data.store(!CONTROLLER_DATA_A, Ordering::Relaxed);
+    gb.write(0xff00, P1_P14_OUT);
+    gb.io_ports.write(IO_IE, INT_HILO);
+    gb.io_ports.write(IO_IF, INT_HILO);
+
+    step(&mut gb).unwrap();
+
+    assert_eq!(gb.execution_state(), EXECUTION_STATE_RUNNING);
+    assert_eq!(gb.pc, 0x0101);
+}
+
+#[test]
+fn stop_button_held_interrupt_pending_at_0() {
+    // STOP moves the PC back a byte before the PC gets moved past the instruction, which wraps
+    // around at 0.
+    let mut gb = test_gameboy(vec!());
+    gb.controller_data.store(!CONTROLLER_DATA_A, Ordering::Relaxed);
+    gb.write(0xff00, P1_P14_OUT);
+    gb.io_ports.write(IO_IE, INT_HILO);
+    gb.io_ports.write(IO_IF, INT_HILO);
+    gb.pc = 0x0000;
+
+    crate::gameboy::cpu::stop(&mut gb);
+
+    assert_eq!(gb.execution_state(), EXECUTION_STATE_RUNNING);
+    assert_eq!(gb.pc, 0xffff);
+}
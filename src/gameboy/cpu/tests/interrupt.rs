@@ -0,0 +1,144 @@
+use std::sync::atomic::{Ordering};
+use super::utils::{test_bus};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::cpu::{*};
+use crate::gameboy::cpu::interrupt::{handle_interrupts};
+
+fn interrupt_bus(ie: u8, io_if: u8) -> FlatRam {
+    let mut gb = test_bus(vec!());
+    gb.ime.store(true, Ordering::Relaxed);
+    gb.write(0xffff, ie);
+    gb.write(0xff0f, io_if);
+    gb.pc = 0x1234;
+    gb.sp = 0xd000;
+    gb
+}
+
+fn assert_dispatched(gb: &FlatRam, vector: u16) {
+    assert!(!gb.ime.load(Ordering::Relaxed));
+    assert_eq!(gb.pc, vector);
+    assert_eq!(gb.sp, 0xcffe);
+    assert_eq!(gb.read(0xcfff), 0x12);
+    assert_eq!(gb.read(0xcffe), 0x34);
+    assert_eq!(gb.cycles, 5);
+}
+
+#[test]
+fn vblank() {
+    let mut gb = interrupt_bus(INT_VBLANK, INT_VBLANK);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0040);
+    assert_eq!(gb.read(0xff0f), 0);
+}
+
+#[test]
+fn lcdc() {
+    let mut gb = interrupt_bus(INT_LCDC, INT_LCDC);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0048);
+    assert_eq!(gb.read(0xff0f), 0);
+}
+
+#[test]
+fn timer() {
+    let mut gb = interrupt_bus(INT_TIMER, INT_TIMER);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0050);
+    assert_eq!(gb.read(0xff0f), 0);
+}
+
+#[test]
+fn serial() {
+    let mut gb = interrupt_bus(INT_SERIAL, INT_SERIAL);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0058);
+    assert_eq!(gb.read(0xff0f), 0);
+}
+
+#[test]
+fn hilo() {
+    let mut gb = interrupt_bus(INT_HILO, INT_HILO);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0060);
+    assert_eq!(gb.read(0xff0f), 0);
+}
+
+#[test]
+fn priority() {
+    let mut gb = interrupt_bus(INT_ALL, INT_TIMER | INT_LCDC | INT_HILO);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0048);
+    assert_eq!(gb.read(0xff0f), INT_TIMER | INT_HILO);
+}
+
+#[test]
+fn disabled_in_ie() {
+    let mut gb = interrupt_bus(INT_TIMER, INT_VBLANK | INT_TIMER);
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_dispatched(&gb, 0x0050);
+    assert_eq!(gb.read(0xff0f), INT_VBLANK);
+}
+
+#[test]
+fn nothing_enabled() {
+    let mut gb = interrupt_bus(0, INT_ALL);
+
+    assert!(!handle_interrupts(&mut gb));
+
+    assert_eq!(gb.pc, 0x1234);
+    assert_eq!(gb.sp, 0xd000);
+    assert_eq!(gb.cycles, 0);
+}
+
+#[test]
+fn ime_unset() {
+    let mut gb = interrupt_bus(INT_VBLANK, INT_VBLANK);
+    gb.ime.store(false, Ordering::Relaxed);
+
+    assert!(!handle_interrupts(&mut gb));
+
+    assert_eq!(gb.pc, 0x1234);
+    assert_eq!(gb.read(0xff0f), INT_VBLANK);
+}
+
+#[test]
+fn ie_overwritten_by_push() {
+    // Pushing the high byte of PC writes 0x04 to IE, which leaves only the timer enabled.
+    let mut gb = interrupt_bus(INT_VBLANK | INT_TIMER, INT_VBLANK | INT_TIMER);
+    gb.pc = 0x0400;
+    gb.sp = 0x0000;
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_eq!(gb.pc, 0x0050);
+    assert_eq!(gb.read(0xffff), INT_TIMER);
+    assert_eq!(gb.read(0xff0f), INT_VBLANK);
+}
+
+#[test]
+fn cancelled_by_push() {
+    // Pushing the high byte of PC writes 0x00 to IE, so there's nothing left to service.
+    let mut gb = interrupt_bus(INT_VBLANK, INT_VBLANK);
+    gb.pc = 0x00ff;
+    gb.sp = 0x0000;
+
+    assert!(handle_interrupts(&mut gb));
+
+    assert_eq!(gb.pc, 0x0000);
+    assert_eq!(gb.sp, 0xfffe);
+    assert_eq!(gb.read(0xff0f), INT_VBLANK);
+}
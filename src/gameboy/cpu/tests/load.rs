@@ -1,11 +1,10 @@
-use crate::gameboy::{*};
+use crate::gameboy::cpu::{*};
 use crate::gameboy::cpu::step::{step};
-use super::utils::test_cartridge;
+use super::utils::{test_bus};
 
 #[test]
 fn ld_r8_r8() {
-    let cartridge = test_cartridge(vec!(0x41));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x41));
     gb.regs[RB] = 0x12;
     gb.regs[RC] = 0x34;
 
@@ -18,8 +17,7 @@ fn ld_r8_r8() {
 
 #[test]
 fn ld_r8_d8() {
-    let cartridge = test_cartridge(vec!(0x06, 0x99));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x06, 0x99));
     gb.regs[RB] = 0x12;
 
     step(&mut gb).unwrap();
@@ -31,8 +29,7 @@ fn ld_r8_d8() {
 
 #[test]
 fn ld_hl_d8() {
-    let cartridge = test_cartridge(vec!(0x36, 0x99));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x36, 0x99));
     gb.write(0xc234, 0x05);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -46,8 +43,7 @@ fn ld_hl_d8() {
 
 #[test]
 fn ld_id_r8() {
-    let cartridge = test_cartridge(vec!(0x02));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x02));
     gb.write(0xc234, 0x05);
     gb.regs[RB] = 0xc2;
     gb.regs[RC] = 0x34;
@@ -62,8 +58,7 @@ fn ld_id_r8() {
 
 #[test]
 fn ld_r8_id() {
-    let cartridge = test_cartridge(vec!(0x0a));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x0a));
     gb.write(0xc234, 0x99);
     gb.regs[RB] = 0xc2;
     gb.regs[RC] = 0x34;
@@ -78,8 +73,7 @@ fn ld_r8_id() {
 
 #[test]
 fn ld_ra_hl_inc() {
-    let cartridge = test_cartridge(vec!(0x2a));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x2a));
     gb.write(0xc234, 0x99);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -88,15 +82,14 @@ fn ld_ra_hl_inc() {
     step(&mut gb).unwrap();
     
     assert_eq!(gb.regs[RA], 0x99);
-    assert_eq!(rr_to_u16(&mut gb, RHL), 0xc235);
+    assert_eq!(rr_to_u16(&gb, RHL), 0xc235);
     assert_eq!(gb.cycles, 2);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn ld_ra_hl_dec() {
-    let cartridge = test_cartridge(vec!(0x3a));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x3a));
     gb.write(0xc234, 0x99);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -105,15 +98,14 @@ fn ld_ra_hl_dec() {
     step(&mut gb).unwrap();
     
     assert_eq!(gb.regs[RA], 0x99);
-    assert_eq!(rr_to_u16(&mut gb, RHL), 0xc233);
+    assert_eq!(rr_to_u16(&gb, RHL), 0xc233);
     assert_eq!(gb.cycles, 2);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn ld_hl_ra_inc() {
-    let cartridge = test_cartridge(vec!(0x22));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x22));
     gb.write(0xc234, 0x05);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -122,15 +114,14 @@ fn ld_hl_ra_inc() {
     step(&mut gb).unwrap();
 
     assert_eq!(gb.read(0xc234), 0x99);
-    assert_eq!(rr_to_u16(&mut gb, RHL), 0xc235);
+    assert_eq!(rr_to_u16(&gb, RHL), 0xc235);
     assert_eq!(gb.cycles, 2);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn ld_hl_ra_dec() {
-    let cartridge = test_cartridge(vec!(0x32));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x32));
     gb.write(0xc234, 0x05);
     gb.regs[RH] = 0xc2;
     gb.regs[RL] = 0x34;
@@ -139,15 +130,14 @@ fn ld_hl_ra_dec() {
     step(&mut gb).unwrap();
 
     assert_eq!(gb.read(0xc234), 0x99);
-    assert_eq!(rr_to_u16(&mut gb, RHL), 0xc233);
+    assert_eq!(rr_to_u16(&gb, RHL), 0xc233);
     assert_eq!(gb.cycles, 2);
     assert_eq!(gb.pc, 0x0101);
 }
 
 #[test]
 fn ld_ra_nn() {
-    let cartridge = test_cartridge(vec!(0xfa, 0x34, 0xc2));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xfa, 0x34, 0xc2));
     gb.write(0xc234, 0x99);
     gb.regs[RA] = 0x05;
 
@@ -160,8 +150,7 @@ fn ld_ra_nn() {
 
 #[test]
 fn ld_nn_ra() {
-    let cartridge = test_cartridge(vec!(0xea, 0x34, 0xc2));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xea, 0x34, 0xc2));
     gb.write(0xc234, 0x05);
     gb.regs[RA] = 0x99;
 
@@ -174,8 +163,7 @@ fn ld_nn_ra() {
 
 #[test]
 fn ldh_ra_rc() {
-    let cartridge = test_cartridge(vec!(0xf2));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf2));
     gb.write(0xff00 + 0x03, 0x99);
     gb.regs[RC] = 0x03;
     gb.regs[RA] = 0x05;
@@ -189,8 +177,7 @@ fn ldh_ra_rc() {
 
 #[test]
 fn ldh_rc_ra() {
-    let cartridge = test_cartridge(vec!(0xe2));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xe2));
     gb.write(0xff00 + 0x03, 0x05);
     gb.regs[RC] = 0x03;
     gb.regs[RA] = 0x99;
@@ -204,8 +191,7 @@ fn ldh_rc_ra() {
 
 #[test]
 fn ldh_ra_n()  {
-    let cartridge = test_cartridge(vec!(0xf0, 0x03));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf0, 0x03));
     gb.write(0xff00 + 0x03, 0x99);
     gb.regs[RA] = 0x05;
 
@@ -218,8 +204,7 @@ fn ldh_ra_n()  {
 
 #[test]
 fn ldh_n_ra()  {
-    let cartridge = test_cartridge(vec!(0xe0, 0x03));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xe0, 0x03));
     gb.write(0xff00 + 0x03, 0x05);
     gb.regs[RA] = 0x99;
 
@@ -232,8 +217,7 @@ fn ldh_n_ra()  {
 
 #[test]
 fn ld_r16_d16() {
-    let cartridge = test_cartridge(vec!(0x11, 0xef, 0xbe));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x11, 0xef, 0xbe));
     gb.regs[RD] = 0x05;
     gb.regs[RE] = 0x06;
 
@@ -246,8 +230,7 @@ fn ld_r16_d16() {
 
 #[test]
 fn ld_rsp_d16() {
-    let cartridge = test_cartridge(vec!(0x31, 0xef, 0xbe));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x31, 0xef, 0xbe));
     gb.sp = 0x0506;
 
     step(&mut gb).unwrap();
@@ -259,24 +242,22 @@ fn ld_rsp_d16() {
 
 #[test]
 fn ld_nn_sp() {
-    let cartridge = test_cartridge(vec!(0x08, 0x34, 0xc2));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0x08, 0x34, 0xc2));
     gb.write(0xc234, 0x05);
     gb.write(0xc235, 0x06);
     gb.sp = 0xfffe;
 
     step(&mut gb).unwrap();
 
-    assert_eq!(gb.read(0xc234), 0xff);
-    assert_eq!(gb.read(0xc235), 0xfe);
+    assert_eq!(gb.read(0xc234), 0xfe);
+    assert_eq!(gb.read(0xc235), 0xff);
     assert_eq!(gb.cycles, 5);
     assert_eq!(gb.pc, 0x0103);
 }
 
 #[test]
 fn ld_sp_hl() {
-    let cartridge = test_cartridge(vec!(0xf9));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf9));
     gb.regs[RH] = 0xff;
     gb.regs[RL] = 0xee;
     gb.sp = 0xfffe;
@@ -290,8 +271,7 @@ fn ld_sp_hl() {
 
 #[test]
 fn push() {
-    let cartridge = test_cartridge(vec!(0xc5));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xc5));
     gb.regs[RB] = 0xbe;
     gb.regs[RC] = 0xef;
     gb.sp = 0xfffe;
@@ -307,8 +287,7 @@ fn push() {
 
 #[test]
 fn pop() {
-    let cartridge = test_cartridge(vec!(0xc1));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xc1));
     gb.regs[RB] = 0x05;
     gb.regs[RC] = 0x06;
     gb.sp = 0xfffc;
@@ -326,8 +305,7 @@ fn pop() {
 
 #[test]
 fn ld_hl_sp_r8_positive() {
-    let cartridge = test_cartridge(vec!(0xf8, 0x07));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf8, 0x07));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.sp = 0xcf00;
@@ -343,8 +321,7 @@ fn ld_hl_sp_r8_positive() {
 
 #[test]
 fn ld_hl_sp_r8_negative() {
-    let cartridge = test_cartridge(vec!(0xf8, (-7 as i8) as u8));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf8, (-7 as i8) as u8));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.sp = 0xcf00;
@@ -360,8 +337,7 @@ fn ld_hl_sp_r8_negative() {
 
 #[test]
 fn ld_hl_sp_r8_flag_c() {
-    let cartridge = test_cartridge(vec!(0xf8, 0x80));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf8, 0x80));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.sp = 0xfffd;
@@ -377,8 +353,7 @@ fn ld_hl_sp_r8_flag_c() {
 
 #[test]
 fn ld_hl_sp_r8_flag_h() {
-    let cartridge = test_cartridge(vec!(0xf8, 0x08));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf8, 0x08));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.sp = 0xff88;
@@ -394,8 +369,7 @@ fn ld_hl_sp_r8_flag_h() {
 
 #[test]
 fn ld_hl_sp_r8_flag_hc() {
-    let cartridge = test_cartridge(vec!(0xf8, 0x88));
-    let mut gb = Gameboy::new(cartridge);
+    let mut gb = test_bus(vec!(0xf8, 0x88));
     gb.regs[RH] = 0x05;
     gb.regs[RL] = 0x06;
     gb.sp = 0xc08c;
@@ -2,4 +2,12 @@
 mod load;
 #[cfg(test)]
 mod arith;
+#[cfg(test)]
+mod control;
+#[cfg(test)]
+mod interrupt;
+#[cfg(test)]
+mod timing;
+#[cfg(test)]
+mod sm83;
 mod utils;
@@ -0,0 +1,392 @@
+//! Runs the SM83 single-step JSON tests against the CPU, on a flat RAM bus.
+//! The test files aren't checked in, so the suite is ignored by default. Set SM83_TESTS_DIR to the
+//! directory holding the suite's JSON files (one per opcode), or put them in roms/sm83, and run it
+//! with `cargo test sm83_single_step -- --ignored`.
+//!
+//! The suite models the SM83's overlapping fetch and execute: when a test starts, the opcode at
+//! PC-1 has already been fetched, and the last cycle of each test is the fetch of the next opcode.
+//! The CPU here fetches at the start of step instead, so cycles are shifted by one to compare them.
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{Ordering};
+use super::utils::{test_bus};
+use crate::gameboy::cpu::{*};
+use crate::gameboy::cpu::step::{step};
+
+/// Number of failures to print for each file before giving up on it.
+const MAX_REPORTED_FAILURES: usize = 3;
+
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Result<&Json, String> {
+        match self {
+            Json::Object(fields) => fields.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .ok_or(format!("Missing field '{}'", key)),
+            _ => Err(format!("Expected an object with field '{}'", key)),
+        }
+    }
+
+    fn as_u16(&self) -> Result<u16, String> {
+        match *self {
+            Json::Number(n) if (0.0..=65535.0).contains(&n) => Ok(n as u16),
+            Json::Bool(b) => Ok(b as u16),
+            _ => Err(format!("Expected a 16-bit number, got {:?}", self)),
+        }
+    }
+
+    fn as_u8(&self) -> Result<u8, String> {
+        let n = self.as_u16()?;
+        if n > 0xff {
+            return Err(format!("Expected an 8-bit number, got {}", n));
+        }
+        Ok(n as u8)
+    }
+
+    fn as_array(&self) -> Result<&[Json], String> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => Err(format!("Expected an array, got {:?}", self)),
+        }
+    }
+}
+
+/// Just enough of a JSON parser for the test files.
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse(text: &'a str) -> Result<Json, String> {
+        let mut parser = JsonParser { bytes: text.as_bytes(), i: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.i != parser.bytes.len() {
+            return Err(format!("Trailing characters at {}", parser.i));
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.i < self.bytes.len() && self.bytes[self.i].is_ascii_whitespace() {
+            self.i += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.bytes.get(self.i) != Some(&byte) {
+            return Err(format!("Expected '{}' at {}", byte as char, self.i));
+        }
+        self.i += 1;
+        Ok(())
+    }
+
+    /// Consumes the given byte if it's next.
+    fn accept(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.bytes.get(self.i) == Some(&byte) {
+            self.i += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        if !self.bytes[self.i..].starts_with(keyword.as_bytes()) {
+            return Err(format!("Unexpected character at {}", self.i));
+        }
+        self.i += keyword.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.i) {
+            Some(b'{') => {
+                self.i += 1;
+                let mut fields = vec!();
+                if !self.accept(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((key, self.value()?));
+                        if !self.accept(b',') {
+                            break;
+                        }
+                    }
+                    self.expect(b'}')?;
+                }
+                Ok(Json::Object(fields))
+            },
+            Some(b'[') => {
+                self.i += 1;
+                let mut items = vec!();
+                if !self.accept(b']') {
+                    loop {
+                        items.push(self.value()?);
+                        if !self.accept(b',') {
+                            break;
+                        }
+                    }
+                    self.expect(b']')?;
+                }
+                Ok(Json::Array(items))
+            },
+            Some(b'"') => Ok(Json::Str(self.string()?)),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(_) => {
+                let start = self.i;
+                while self.i < self.bytes.len()
+                    && matches!(self.bytes[self.i], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+                    self.i += 1;
+                }
+                let text = std::str::from_utf8(&self.bytes[start..self.i]).unwrap();
+                text.parse().map(Json::Number).map_err(|_| format!("Bad number at {}", start))
+            },
+            None => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let start = self.i;
+        // The test files don't use escapes, so there's no need to handle them.
+        while self.i < self.bytes.len() && self.bytes[self.i] != b'"' {
+            self.i += 1;
+        }
+        let s = String::from_utf8_lossy(&self.bytes[start..self.i]).into_owned();
+        self.expect(b'"')?;
+        Ok(s)
+    }
+}
+
+const REGS: [(&str, R); 8] = [
+    ("a", RA), ("b", RB), ("c", RC), ("d", RD), ("e", RE), ("f", RF), ("h", RH), ("l", RL),
+];
+
+/// What the CPU did with the bus in a single machine cycle: an (address, value, is_write) access,
+/// or None for an internal cycle.
+type BusCycle = Option<(u16, u8, bool)>;
+
+fn load_state(gb: &mut FlatRam, state: &Json) -> Result<(), String> {
+    for &(name, r) in REGS.iter() {
+        gb.regs[r] = state.get(name)?.as_u8()?;
+    }
+    gb.sp = state.get("sp")?.as_u16()?;
+    gb.ime.store(state.get("ime")?.as_u8()? > 0, Ordering::Relaxed);
+    gb.write(0xffff, state.get("ie")?.as_u8()?);
+    for entry in state.get("ram")?.as_array()? {
+        let entry = entry.as_array()?;
+        gb.write(entry[0].as_u16()?, entry[1].as_u8()?);
+    }
+    Ok(())
+}
+
+fn check_state(gb: &FlatRam, state: &Json) -> Result<(), String> {
+    let mut errors = vec!();
+    for &(name, r) in REGS.iter() {
+        let expected = state.get(name)?.as_u8()?;
+        if gb.regs[r] != expected {
+            errors.push(format!("{}: ${:0>2x} != ${:0>2x}", name, gb.regs[r], expected));
+        }
+    }
+    // PC already points past the next opcode once a test is done.
+    let expected_pc = state.get("pc")?.as_u16()?;
+    if gb.pc.wrapping_add(1) != expected_pc {
+        errors.push(format!("pc: ${:0>4x} != ${:0>4x}", gb.pc.wrapping_add(1), expected_pc));
+    }
+    let expected_sp = state.get("sp")?.as_u16()?;
+    if gb.sp != expected_sp {
+        errors.push(format!("sp: ${:0>4x} != ${:0>4x}", gb.sp, expected_sp));
+    }
+    // The suite doesn't model EI's delay, so IME counts as set once it's been scheduled.
+    let ime = gb.ime.load(Ordering::Relaxed) || gb.ime_scheduled;
+    if ime != (state.get("ime")?.as_u8()? > 0) {
+        errors.push(format!("ime: {}", ime));
+    }
+    for entry in state.get("ram")?.as_array()? {
+        let entry = entry.as_array()?;
+        let (addr, expected) = (entry[0].as_u16()?, entry[1].as_u8()?);
+        if gb.read(addr) != expected {
+            errors.push(format!("${:0>4x}: ${:0>2x} != ${:0>2x}", addr, gb.read(addr), expected));
+        }
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors.join(", ")) }
+}
+
+fn expected_cycles(cycles: &Json) -> Result<Vec<BusCycle>, String> {
+    cycles.as_array()?.iter().map(|cycle| {
+        let cycle = match cycle {
+            Json::Null => return Ok(None),
+            _ => cycle.as_array()?,
+        };
+        let pins = match &cycle[2] {
+            Json::Str(pins) => pins.as_str(),
+            _ => "",
+        };
+        if let Json::Null = cycle[1] {
+            return Ok(None);
+        }
+        match (pins.contains('r'), pins.contains('w')) {
+            (false, false) => Ok(None),
+            (_, write) => Ok(Some((cycle[0].as_u16()?, cycle[1].as_u8()?, write))),
+        }
+    }).collect()
+}
+
+/// The bus activity of the instruction that was just stepped, shifted to line up with the suite.
+fn actual_cycles(gb: &FlatRam) -> Vec<BusCycle> {
+    let mut cycles: Vec<BusCycle> = (1..=gb.cycles)
+        .map(|cycle| gb.log.iter()
+             .find(|access| access.0 == cycle)
+             .map(|&(_, addr, value, write)| (addr, value, write)))
+        .collect();
+    // Drop the opcode fetch, which the suite considers part of the previous instruction, and fetch
+    // the next opcode instead.
+    cycles.remove(0);
+    cycles.push(Some((gb.pc, gb.read(gb.pc), false)));
+    cycles
+}
+
+fn run_case(case: &Json) -> Result<(), String> {
+    let mut gb = test_bus(vec!());
+    let initial = case.get("initial")?;
+    load_state(&mut gb, initial)?;
+    gb.pc = initial.get("pc")?.as_u16()?.wrapping_sub(1);
+
+    step(&mut gb)?;
+
+    check_state(&gb, case.get("final")?)?;
+    let expected = expected_cycles(case.get("cycles")?)?;
+    let actual = actual_cycles(&gb);
+    if actual != expected {
+        return Err(format!("cycles: {:x?} != {:x?}", actual, expected));
+    }
+    Ok(())
+}
+
+/// Runs every case in a test file, returning a description of each one that failed.
+fn run_file(path: &Path) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let cases = JsonParser::parse(&text)?;
+    let mut failures = vec!();
+    for case in cases.as_array()? {
+        let name = match case.get("name")? {
+            Json::Str(name) => name.clone(),
+            _ => "?".to_string(),
+        };
+        let result = panic::catch_unwind(|| run_case(case))
+            .unwrap_or_else(|_| Err("panicked".to_string()));
+        if let Err(err) = result {
+            failures.push(format!("{}: {}", name, err));
+        }
+    }
+    Ok(failures)
+}
+
+fn tests_dir() -> PathBuf {
+    match env::var("SM83_TESTS_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => Path::new(env!("CARGO_MANIFEST_DIR")).join("roms").join("sm83"),
+    }
+}
+
+#[test]
+#[ignore = "needs the SM83 JSON test files in SM83_TESTS_DIR or roms/sm83"]
+fn sm83_single_step() {
+    let dir = tests_dir();
+    let mut paths: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"))
+            .collect(),
+        Err(err) => panic!("Failed to read SM83 tests from {}: {}", dir.display(), err),
+    };
+    assert!(!paths.is_empty(), "No SM83 test files in {}", dir.display());
+    paths.sort();
+
+    let mut failed_files = 0;
+    for path in paths.iter() {
+        let failures = run_file(path).unwrap_or_else(|err| vec!(err));
+        if !failures.is_empty() {
+            failed_files += 1;
+            eprintln!("{}: {} failed", path.display(), failures.len());
+            for failure in failures.iter().take(MAX_REPORTED_FAILURES) {
+                eprintln!("    {}", failure);
+            }
+        }
+    }
+    assert_eq!(failed_files, 0, "{} of {} SM83 test files failed", failed_files, paths.len());
+}
+
+#[test]
+fn sm83_harness() {
+    // NOP, then PUSH BC, in the suite's format.
+    let cases = JsonParser::parse(r#"[
+        {
+            "name": "00 0000",
+            "initial": {
+                "pc": 49153, "sp": 53248, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0,
+                "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 0], [49153, 0]]
+            },
+            "final": {
+                "pc": 49154, "sp": 53248, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0,
+                "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 0], [49153, 0]]
+            },
+            "cycles": [[49153, 0, "r-m"]]
+        },
+        {
+            "name": "c5 0000",
+            "initial": {
+                "pc": 49153, "sp": 53248, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0,
+                "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 197], [49153, 0]]
+            },
+            "final": {
+                "pc": 49154, "sp": 53246, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0, "f": 0, "h": 0,
+                "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 197], [49153, 0], [53247, 18], [53246, 52]]
+            },
+            "cycles": [null, [53247, 18, "-wm"], [53246, 52, "-wm"], [49153, 0, "r-m"]]
+        }
+    ]"#).unwrap();
+
+    for case in cases.as_array().unwrap() {
+        run_case(case).unwrap();
+    }
+}
+
+#[test]
+fn sm83_harness_catches_mismatch() {
+    // NOP with the wrong final F.
+    let case = JsonParser::parse(r#"{
+        "name": "00 0001",
+        "initial": {
+            "pc": 49153, "sp": 53248, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0, "h": 0,
+            "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 0], [49153, 0]]
+        },
+        "final": {
+            "pc": 49154, "sp": 53248, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 16, "h": 0,
+            "l": 0, "ime": 0, "ie": 0, "ram": [[49152, 0], [49153, 0]]
+        },
+        "cycles": [[49153, 0, "r-m"]]
+    }"#).unwrap();
+
+    assert!(run_case(&case).is_err());
+}
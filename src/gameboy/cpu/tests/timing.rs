@@ -0,0 +1,52 @@
+use super::utils::{test_gameboy};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::cpu::{*};
+use crate::gameboy::cpu::step::{step};
+
+/// A gameboy whose timer increments TIMA every 4 machine cycles, with DIV just reset so that the
+/// first increment happens at the end of the 4th cycle.
+fn timer_gameboy(program: Vec<u8>) -> Gameboy {
+    let mut gb = test_gameboy(program);
+    gb.io_ports.write(IO_TIMA, 0);
+    gb.io_ports.write(IO_TAC, TAC_ENABLE | 0b01);
+    gb.write(0xff04, 0);
+    gb
+}
+
+#[test]
+fn read_happens_on_last_cycle() {
+    // NOP; NOP; LDH A,(TIMA)
+    let mut gb = timer_gameboy(vec!(0x00, 0x00, 0xf0, 0x05));
+    for _ in 0..3 {
+        step(&mut gb).unwrap();
+    }
+    // TIMA is incremented in the same cycle that it's read in.
+    assert_eq!(gb.regs[RA], 1);
+    assert_eq!(gb.cycles, 5);
+}
+
+#[test]
+fn write_happens_on_last_cycle() {
+    // NOP; NOP; LDH (TIMA),A
+    let mut gb = timer_gameboy(vec!(0x00, 0x00, 0xe0, 0x05));
+    gb.regs[RA] = 0x10;
+    for _ in 0..3 {
+        step(&mut gb).unwrap();
+    }
+    // The increment happens first, so the write wins.
+    assert_eq!(gb.io_ports.read(IO_TIMA), 0x10);
+}
+
+#[test]
+fn read_modify_write_accesses_separate_cycles() {
+    // NOP; NOP; INC (HL) with HL=TIMA
+    let mut gb = timer_gameboy(vec!(0x00, 0x00, 0x34));
+    gb.regs[RH] = 0xff;
+    gb.regs[RL] = 0x05;
+    for _ in 0..3 {
+        step(&mut gb).unwrap();
+    }
+    // TIMA is read after the increment in the 4th cycle, and written back in the 5th.
+    assert_eq!(gb.io_ports.read(IO_TIMA), 2);
+    assert_eq!(gb.cycles, 5);
+}
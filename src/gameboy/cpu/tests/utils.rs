@@ -1,11 +1,24 @@
 #[cfg(test)]
-use crate::gameboy::cartridge::{Cartridge, load_cartridge};
+use crate::gameboy::cpu::{Cpu, FlatRam};
+#[cfg(test)]
+use crate::gameboy::gameboy::{Gameboy};
+#[cfg(test)]
+use crate::gameboy::utils::{test_dmg};
+
+/// Flat RAM with the program at 0x0100, where PC starts, and all registers cleared so that tests
+/// can check flags without having to account for leftover state.
+#[cfg(test)]
+pub fn test_bus(program: Vec<u8>) -> FlatRam {
+    let mut bus = FlatRam::new(Cpu::new(0x0100, [0; 8]));
+    bus.ram[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+    bus
+}
 
+/// A DMG running the program from the cartridge entry point, with all registers cleared, for
+/// tests that depend on the rest of the system.
 #[cfg(test)]
-pub fn test_cartridge(bytes: Vec<u8>) -> Cartridge {
-    let mut rom = Box::new([0; 0x8000]);
-    for (i, byte) in bytes.iter().enumerate() {
-        rom[0x0100 + i] = *byte;
-    }
-    load_cartridge(&*rom, None).unwrap()
+pub fn test_gameboy(program: Vec<u8>) -> Gameboy {
+    let mut gb = test_dmg(&program);
+    gb.regs = [0; 8];
+    gb
 }
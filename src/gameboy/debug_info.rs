@@ -27,7 +27,6 @@ impl Clone for DebugInfoCpu {
 pub struct DebugInfoPpu {
     pub expected_time_micros: Arc<AtomicU64>,
     pub actual_time_micros: Arc<AtomicU64>,
-    pub oam_time_nanos: Arc<AtomicU64>,
 }
 
 impl DebugInfoPpu {
@@ -35,7 +34,6 @@ impl DebugInfoPpu {
         Self {
             expected_time_micros: Arc::new(AtomicU64::new(0)),
             actual_time_micros: Arc::new(AtomicU64::new(0)),
-            oam_time_nanos: Arc::new(AtomicU64::new(0)),
         }
     }
 }
@@ -45,7 +43,6 @@ impl Clone for DebugInfoPpu {
         Self {
             expected_time_micros: self.expected_time_micros.clone(),
             actual_time_micros: self.actual_time_micros.clone(),
-            oam_time_nanos: self.oam_time_nanos.clone(),
         }
     }
 }
@@ -0,0 +1,264 @@
+use std::fs;
+use crate::gameboy::cgb_palette::{rgb555_to_rgb888};
+use crate::gameboy::model::{cgb_title_checksum};
+use crate::gameboy::ppu::{PALETTE_GREY, PALETTE_RED, PALETTE_GREEN, PALETTE_BLUE};
+
+/// Built-in palettes, which can be picked by name.
+pub const PALETTE_NAMES: [&str; 5] = ["grey", "red", "green", "blue", "auto"];
+
+/// Colors for the 4 shades that BGP, OBP0 and OBP1 map color indexes to, outside of CGB mode.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DmgPalette {
+    pub bg: [(u8,u8,u8); 4],
+    pub obj0: [(u8,u8,u8); 4],
+    pub obj1: [(u8,u8,u8); 4],
+}
+
+impl DmgPalette {
+    /// Uses the same colors for the background and both object palettes.
+    pub const fn uniform(colors: [(u8,u8,u8); 4]) -> Self {
+        Self { bg: colors, obj0: colors, obj1: colors }
+    }
+
+    /// Looks up a palette by name. "auto" picks the palette the CGB boot ROM would use for the
+    /// given ROM, and anything that isn't a known name is loaded as a palette file.
+    pub fn new(name: &str, rom: &[u8], color_correction: bool) -> Result<Self, String> {
+        match name {
+            "grey" => Ok(Self::uniform(PALETTE_GREY)),
+            "red" => Ok(Self::uniform(PALETTE_RED)),
+            "green" => Ok(Self::uniform(PALETTE_GREEN)),
+            "blue" => Ok(Self::uniform(PALETTE_BLUE)),
+            "auto" => Ok(Self::cgb_boot(rom, color_correction)),
+            _ => Self::load(name),
+        }
+    }
+
+    /// Reads a palette file, which has `bg`, `obj0` and `obj1` lines with 4 colors each, from
+    /// lightest to darkest shade, e.g. `bg = #e0f8d0 #88c070 #346856 #081820`. The object
+    /// palettes default to the background colors if they're left out, and `#` followed by a space
+    /// starts a comment.
+    pub fn load(filepath: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(filepath)
+            .map_err(|e| format!("Failed to open palette file '{filepath}': {e}"))?;
+        Self::parse(&text).map_err(|e| format!("{}:{}", filepath, e))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let (mut bg, mut obj0, mut obj1) = (None, None, None);
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split("# ").next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (layer, colors) = line.split_once('=')
+                .ok_or(format!("{}: Expected '<bg, obj0 or obj1> = <4 colors>', got '{}'", i + 1, line))?;
+            let colors = parse_colors(colors).map_err(|e| format!("{}: {}", i + 1, e))?;
+            match layer.trim() {
+                "bg" => bg = Some(colors),
+                "obj0" => obj0 = Some(colors),
+                "obj1" => obj1 = Some(colors),
+                layer => return Err(format!("{}: Unknown palette '{}'", i + 1, layer)),
+            }
+        }
+        let bg = bg.ok_or(": Missing bg colors")?;
+        Ok(Self {
+            bg,
+            obj0: obj0.unwrap_or(bg),
+            obj1: obj1.unwrap_or(bg),
+        })
+    }
+
+    /// The palette that the CGB boot ROM picks when running a DMG game. Games published by
+    /// Nintendo are looked up by the sum of their title bytes, with the 4th letter of the title
+    /// telling apart games with the same sum. Every other game gets the default palette.
+    pub fn cgb_boot(rom: &[u8], color_correction: bool) -> Self {
+        let combination = cgb_boot_combination(rom);
+        let (obj0, obj1, bg) = PALETTE_COMBINATIONS[combination as usize];
+        let colors = |offset: u8| {
+            let mut colors = [(0, 0, 0); 4];
+            for (i, color) in colors.iter_mut().enumerate() {
+                *color = rgb555_to_rgb888(BOOT_PALETTE_COLORS[offset as usize + i], color_correction);
+            }
+            colors
+        };
+        Self {
+            bg: colors(bg),
+            obj0: colors(obj0),
+            obj1: colors(obj1),
+        }
+    }
+}
+
+/// Parses 4 colors written as `#rrggbb` and separated by spaces.
+fn parse_colors(text: &str) -> Result<[(u8,u8,u8); 4], String> {
+    let colors = text.split_whitespace().map(|color| {
+        let hex = color.strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .ok_or(format!("Expected a color like #ffffff, got '{color}'"))?;
+        let value = u32::from_str_radix(hex, 16)
+            .map_err(|e| format!("Failed to parse color {color}: {e}"))?;
+        Ok(((value >> 16) as u8, (value >> 8) as u8, value as u8))
+    }).collect::<Result<Vec<_>, String>>()?;
+    if colors.len() != 4 {
+        return Err(format!("Expected 4 colors, got {}", colors.len()));
+    }
+    Ok([colors[0], colors[1], colors[2], colors[3]])
+}
+
+/// Index into PALETTE_COMBINATIONS of the palettes the CGB boot ROM uses for the given ROM.
+fn cgb_boot_combination(rom: &[u8]) -> u8 {
+    let checksum = match cgb_title_checksum(rom) {
+        Some(checksum) => checksum,
+        None => return 0,
+    };
+    let ix = match TITLE_CHECKSUMS.iter().position(|&c| c == checksum) {
+        Some(ix) => ix,
+        None => return 0,
+    };
+    if ix < FIRST_DUPLICATE_CHECKSUM {
+        return PALETTE_PER_CHECKSUM[ix];
+    }
+
+    // The duplicated checksums are listed once, but each one can stand for a few games, which
+    // come every DUPLICATE_CHECKSUMS entries in the 4th letter and palette tables.
+    let fourth_letter = rom[0x137];
+    let mut dup_ix = ix - FIRST_DUPLICATE_CHECKSUM;
+    while dup_ix < DUPS_4TH_LETTER.len() {
+        if DUPS_4TH_LETTER[dup_ix] == fourth_letter {
+            return PALETTE_PER_CHECKSUM[FIRST_DUPLICATE_CHECKSUM + dup_ix];
+        }
+        dup_ix += DUPLICATE_CHECKSUMS;
+    }
+    0
+}
+
+// The rest of this file is data from the CGB boot ROM.
+
+/// Sums of the title bytes of games with their own palettes. The last DUPLICATE_CHECKSUMS entries
+/// are shared by several games each.
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xd1, 0xdb, 0xf2, 0x3c, 0x8c, 0x92, 0x3d, 0x5c, 0x58, 0xc9, 0x3e, 0x70,
+    0x1d, 0x59, 0x69, 0x19, 0x35, 0xa8, 0x14, 0xaa, 0x75, 0x95, 0x99, 0x34, 0x6f, 0x15, 0xff, 0x97,
+    0x4b, 0x90, 0x17, 0x10, 0x39, 0xf7, 0xf6, 0xa2, 0x49, 0x4e, 0x43, 0x68, 0xe0, 0x8b, 0xf0, 0xce,
+    0x0c, 0x29, 0xe8, 0xb7, 0x86, 0x9a, 0x52, 0x01, 0x9d, 0x71, 0x9c, 0xbd, 0x5d, 0x6d, 0x67, 0x3f,
+    0x6b,
+    0xb3, 0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4,
+];
+const FIRST_DUPLICATE_CHECKSUM: usize = 65;
+const DUPLICATE_CHECKSUMS: usize = 14;
+
+/// 4th letter of the title of each game with a duplicated checksum.
+const DUPS_4TH_LETTER: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// Index into PALETTE_COMBINATIONS for each title checksum, followed by one for each entry in
+/// DUPS_4TH_LETTER.
+const PALETTE_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 34, 23, 18, 29,
+    28,
+];
+
+/// OBJ0, OBJ1 and BG palettes for each combination, as offsets into BOOT_PALETTE_COLORS counted
+/// in colors. Most of these are whole palettes, but a few start partway into one.
+const PALETTE_COMBINATIONS: [(u8, u8, u8); 51] = [
+    (16, 16, 116), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0),
+    (108, 108, 108), (20, 20, 20), (48, 48, 48), (104, 104, 104), (64, 32, 32), (16, 112, 112),
+    (16, 8, 8), (12, 16, 16), (16, 116, 116), (112, 16, 112), (8, 68, 8), (64, 64, 32),
+    (16, 16, 28), (16, 16, 72), (16, 16, 80), (76, 76, 36), (15, 15, 44), (68, 68, 8), (16, 16, 8),
+    (16, 16, 12), (112, 112, 0), (12, 12, 0), (0, 0, 4), (72, 88, 72), (80, 88, 80), (96, 88, 96),
+    (64, 88, 32), (68, 16, 52), (111, 0, 56), (111, 16, 60), (76, 88, 36), (64, 112, 40),
+    (16, 92, 112), (68, 88, 8), (16, 0, 8), (16, 112, 12), (112, 12, 0), (12, 112, 16),
+    (84, 112, 16), (12, 112, 0), (100, 12, 112), (0, 112, 32), (16, 12, 112), (112, 12, 24),
+    (16, 112, 116),
+];
+
+/// 30 palettes of 4 colors each, in the same 15-bit format as CGB palette RAM.
+const BOOT_PALETTE_COLORS: [u16; 120] = [
+    0x7fff, 0x32bf, 0x00d0, 0x0000,
+    0x639f, 0x4279, 0x15b0, 0x04cb,
+    0x7fff, 0x6e31, 0x454a, 0x0000,
+    0x7fff, 0x1bef, 0x0200, 0x0000,
+    0x7fff, 0x421f, 0x1cf2, 0x0000,
+    0x7fff, 0x5294, 0x294a, 0x0000,
+    0x7fff, 0x03ff, 0x012f, 0x0000,
+    0x7fff, 0x03ef, 0x01d6, 0x0000,
+    0x7fff, 0x42b5, 0x3dc8, 0x0000,
+    0x7e74, 0x03ff, 0x0180, 0x0000,
+    0x67ff, 0x77ac, 0x1a13, 0x2d6b,
+    0x7ed6, 0x4bff, 0x2175, 0x0000,
+    0x53ff, 0x4a5f, 0x7e52, 0x0000,
+    0x4fff, 0x7ed2, 0x3a4c, 0x1ce0,
+    0x03ed, 0x7fff, 0x255f, 0x0000,
+    0x036a, 0x021f, 0x03ff, 0x7fff,
+    0x7fff, 0x01df, 0x0112, 0x0000,
+    0x231f, 0x035f, 0x00f2, 0x0009,
+    0x7fff, 0x03ea, 0x011f, 0x0000,
+    0x299f, 0x001a, 0x000c, 0x0000,
+    0x7fff, 0x027f, 0x001f, 0x0000,
+    0x7fff, 0x03e0, 0x0206, 0x0120,
+    0x7fff, 0x7eeb, 0x001f, 0x7c00,
+    0x7fff, 0x3fff, 0x7e00, 0x001f,
+    0x7fff, 0x03ff, 0x001f, 0x0000,
+    0x03ff, 0x001f, 0x000c, 0x0000,
+    0x7fff, 0x033f, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037f, 0x7fff,
+    0x7fff, 0x7e8c, 0x7c00, 0x0000,
+    0x7fff, 0x1bef, 0x6180, 0x0000,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    /// A ROM header from Nintendo with the given title.
+    fn rom(title: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14b] = 0x01;
+        rom
+    }
+
+    #[test]
+    fn parse_file() {
+        let palette = DmgPalette::parse("
+            # Shades of green
+            bg = #e0f8d0 #88c070 #346856 #081820
+            obj1 = #ffffff #ff0000 #800000 #000000
+        ").unwrap();
+        assert_eq!(palette.bg, [(0xe0, 0xf8, 0xd0), (0x88, 0xc0, 0x70), (0x34, 0x68, 0x56), (0x08, 0x18, 0x20)]);
+        assert_eq!(palette.obj0, palette.bg);
+        assert_eq!(palette.obj1[1], (0xff, 0, 0));
+
+        assert!(DmgPalette::parse("obj0 = #ffffff #aaaaaa #555555 #000000").is_err());
+        assert!(DmgPalette::parse("bg = #ffffff #aaaaaa #555555").is_err());
+        assert!(DmgPalette::parse("bg = #ffffff #aaaaaa #555555 black").is_err());
+        assert!(DmgPalette::parse("win = #ffffff #aaaaaa #555555 #000000").is_err());
+    }
+
+    #[test]
+    fn boot_palette_lookup() {
+        // Games that aren't from Nintendo get the default palette.
+        let mut tetris = rom(b"TETRIS");
+        assert_eq!(cgb_boot_combination(&tetris), 3);
+        tetris[0x14b] = 0x02;
+        assert_eq!(cgb_boot_combination(&tetris), 0);
+
+        // Games with the same checksum are told apart by the 4th letter of their title.
+        assert_eq!(cgb_boot_combination(&rom(b"POKEMON BLUE")), 11);
+        assert_eq!(cgb_boot_combination(&rom(b"POKAMON BLUI")), 41);
+        assert_eq!(cgb_boot_combination(&rom(b"POKZMON BLUA")), 0);
+    }
+
+    #[test]
+    fn default_boot_palette() {
+        let palette = DmgPalette::cgb_boot(&rom(b"NOT A REAL GAME"), false);
+        assert_eq!(palette.bg, [(0xff, 0xff, 0xff), (0x7b, 0xff, 0x31), (0x00, 0x63, 0xc6), (0, 0, 0)]);
+        assert_eq!(palette.obj0, [(0xff, 0xff, 0xff), (0xff, 0x84, 0x84), (0x94, 0x39, 0x39), (0, 0, 0)]);
+        assert_eq!(palette.obj0, palette.obj1);
+    }
+}
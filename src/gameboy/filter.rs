@@ -0,0 +1,527 @@
+/// Pixel art upscaling filters, which run on the CPU. Frames are filtered on their way to the
+/// screen, screenshots and video recordings.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Filter {
+    None,
+    /// AdvMAME2x: each pixel becomes a 2x2 square, with corners taking on the color of
+    /// neighbours that meet diagonally. Edges stay sharp, since no colors are mixed.
+    Scale2x,
+    /// AdvMAME3x, the same idea as Scale2x for a 3x3 square.
+    Scale3x,
+    /// Scale2x applied twice.
+    Scale4x,
+    /// Maxim Stepin's hq2x: which of a pixel's 8 neighbours differ from it in YUV makes up a
+    /// pattern, and the pattern picks how each sub-pixel blends the pixel with its neighbours.
+    Hq2x,
+    /// hq3x, the same idea as hq2x for a 3x3 square.
+    Hq3x,
+    /// Hyllian's 2xBR, which looks further out than the other filters to follow shallow and steep
+    /// edges, and blends along them.
+    Xbr,
+}
+
+/// Filter names for the command line, in the same order as the filters.
+const FILTERS: [(&str, Filter); 7] = [
+    ("none", Filter::None),
+    ("scale2x", Filter::Scale2x),
+    ("scale3x", Filter::Scale3x),
+    ("scale4x", Filter::Scale4x),
+    ("hq2x", Filter::Hq2x),
+    ("hq3x", Filter::Hq3x),
+    ("xbr", Filter::Xbr),
+];
+
+/// Largest differences in Y, U and V for two colors to be considered the same by hqx and xBR.
+const Y_THRESHOLD: i32 = 48;
+const U_THRESHOLD: i32 = 7;
+const V_THRESHOLD: i32 = 6;
+
+impl Filter {
+    pub fn new(name: &str) -> Result<Self, String> {
+        FILTERS.iter()
+            .find(|(filter_name, _)| *filter_name == name)
+            .map(|&(_, filter)| filter)
+            .ok_or(format!("Unknown filter '{name}'"))
+    }
+
+    /// How many times larger the output is in each direction.
+    pub fn factor(&self) -> u32 {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x | Filter::Hq2x | Filter::Xbr => 2,
+            Filter::Scale3x | Filter::Hq3x => 3,
+            Filter::Scale4x => 4,
+        }
+    }
+
+    /// Filters an image stored row by row. The result is `factor()` times as wide and as high.
+    pub fn apply(&self, width: u32, pixels: &[(u8, u8, u8)]) -> Vec<(u8, u8, u8)> {
+        if *self == Filter::None {
+            return pixels.to_vec();
+        }
+        let image = Image::new(width as usize, pixels);
+        match self {
+            Filter::None => unreachable!(),
+            Filter::Scale2x => scale2x(&image),
+            Filter::Scale3x => scale3x(&image),
+            Filter::Scale4x => {
+                let scaled = scale2x(&image);
+                scale2x(&Image::new(width as usize * 2, &scaled))
+            },
+            Filter::Hq2x => hq2x(&image),
+            Filter::Hq3x => hq3x(&image),
+            Filter::Xbr => xbr(&image),
+        }
+    }
+}
+
+/// An image being filtered, with the YUV values of its pixels worked out up front since hqx and
+/// xBR compare every pixel with its neighbours many times over.
+struct Image<'a> {
+    width: usize,
+    height: usize,
+    pixels: &'a [(u8, u8, u8)],
+    yuv: Vec<(i32, i32, i32)>,
+}
+
+impl<'a> Image<'a> {
+    fn new(width: usize, pixels: &'a [(u8, u8, u8)]) -> Self {
+        Self {
+            width,
+            height: pixels.len() / width,
+            pixels,
+            yuv: pixels.iter().map(|&color| yuv(color)).collect(),
+        }
+    }
+
+    /// Index of the pixel at the given position. Positions outside the image are moved to the
+    /// nearest edge, so the edges are treated as if they continued on.
+    fn ix(&self, x: isize, y: isize) -> usize {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        y * self.width + x
+    }
+
+    fn at(&self, x: isize, y: isize) -> (u8, u8, u8) {
+        self.pixels[self.ix(x, y)]
+    }
+
+    fn similar(&self, a: usize, b: usize) -> bool {
+        let ((ya, ua, va), (yb, ub, vb)) = (self.yuv[a], self.yuv[b]);
+        (ya - yb).abs() <= Y_THRESHOLD && (ua - ub).abs() <= U_THRESHOLD && (va - vb).abs() <= V_THRESHOLD
+    }
+
+    fn distance(&self, a: usize, b: usize) -> i32 {
+        let ((ya, ua, va), (yb, ub, vb)) = (self.yuv[a], self.yuv[b]);
+        Y_THRESHOLD * (ya - yb).abs() + U_THRESHOLD * (ua - ub).abs() + V_THRESHOLD * (va - vb).abs()
+    }
+}
+
+fn yuv((r, g, b): (u8, u8, u8)) -> (i32, i32, i32) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    (
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    )
+}
+
+/// Moves a color towards another one by alpha/256 of the way.
+fn blend(from: (u8, u8, u8), to: (u8, u8, u8), alpha: u32) -> (u8, u8, u8) {
+    let mix = |a: u8, b: u8| ((a as u32 * (256 - alpha) + b as u32 * alpha + 128) / 256) as u8;
+    (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
+
+/// Rotates an offset a quarter turn counterclockwise the given number of times. The corner
+/// filters are written for the bottom right corner of a pixel, and rotated to handle the others.
+fn rotate(x: isize, y: isize, quarter_turns: u32) -> (isize, isize) {
+    (0..quarter_turns).fold((x, y), |(x, y), _| (y, -x))
+}
+
+/// Copies the filtered pixels for each source pixel, as factor by factor blocks in row order, to
+/// the output image.
+fn assemble(image: &Image, factor: usize, filter_pixel: impl Fn(isize, isize, &mut [(u8, u8, u8)])) -> Vec<(u8, u8, u8)> {
+    let out_width = image.width * factor;
+    let mut out = vec![(0, 0, 0); image.pixels.len() * factor * factor];
+    let mut block = vec![(0, 0, 0); factor * factor];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            filter_pixel(x as isize, y as isize, &mut block);
+            for (row, block_row) in block.chunks(factor).enumerate() {
+                let start = (y * factor + row) * out_width + x * factor;
+                out[start..start + factor].copy_from_slice(block_row);
+            }
+        }
+    }
+    out
+}
+
+/// Neighbours are named as in the Scale2x description:
+///   A B C
+///   D E F
+///   G H I
+fn scale2x(image: &Image) -> Vec<(u8, u8, u8)> {
+    assemble(image, 2, |x, y, block| {
+        let (b, d, e, f, h) = (image.at(x, y - 1), image.at(x - 1, y), image.at(x, y), image.at(x + 1, y), image.at(x, y + 1));
+        block.fill(e);
+        if b != h && d != f {
+            if d == b { block[0] = d; }
+            if b == f { block[1] = f; }
+            if d == h { block[2] = d; }
+            if h == f { block[3] = f; }
+        }
+    })
+}
+
+fn scale3x(image: &Image) -> Vec<(u8, u8, u8)> {
+    assemble(image, 3, |x, y, block| {
+        let (a, b, c) = (image.at(x - 1, y - 1), image.at(x, y - 1), image.at(x + 1, y - 1));
+        let (d, e, f) = (image.at(x - 1, y), image.at(x, y), image.at(x + 1, y));
+        let (g, h, i) = (image.at(x - 1, y + 1), image.at(x, y + 1), image.at(x + 1, y + 1));
+        block.fill(e);
+        if b != h && d != f {
+            if d == b { block[0] = d; }
+            if (d == b && e != c) || (b == f && e != a) { block[1] = b; }
+            if b == f { block[2] = f; }
+            if (d == b && e != g) || (d == h && e != a) { block[3] = d; }
+            if (b == f && e != i) || (h == f && e != c) { block[5] = f; }
+            if d == h { block[6] = d; }
+            if (d == h && e != i) || (h == f && e != g) { block[7] = h; }
+            if h == f { block[8] = f; }
+        }
+    })
+}
+
+/// The neighbourhood of a pixel for hqx, numbered
+///   0 1 2
+///   3 4 5
+///   6 7 8
+/// The hqx rules are written for the top left of the pixel. They handle the other corners and
+/// sides by reading the neighbourhood in a mirrored or rotated order.
+struct HqNeighbourhood<'a> {
+    image: &'a Image<'a>,
+    /// Image indices of the pixels, in the order the rule reads them.
+    w: [usize; 9],
+    /// Bit n is set if neighbour n (not counting the pixel itself) differs from the pixel.
+    pattern: u8,
+}
+
+impl<'a> HqNeighbourhood<'a> {
+    fn new(image: &'a Image<'a>, x: isize, y: isize, order: [usize; 9]) -> Self {
+        let w = order.map(|n| image.ix(x + (n % 3) as isize - 1, y + (n / 3) as isize - 1));
+        let pattern = [0, 1, 2, 3, 5, 6, 7, 8].iter().enumerate()
+            .filter(|&(_, &n)| !image.similar(w[4], w[n]))
+            .fold(0, |pattern, (bit, _)| pattern | 1 << bit);
+        Self { image, w, pattern }
+    }
+
+    /// Whether the neighbours in any of the masks differ from the pixel exactly where the bits
+    /// that go with the mask are set.
+    fn matches(&self, patterns: &[(u8, u8)]) -> bool {
+        patterns.iter().any(|&(mask, bits)| self.pattern & mask == bits)
+    }
+
+    fn differ(&self, a: usize, b: usize) -> bool {
+        !self.image.similar(self.w[a], self.w[b])
+    }
+
+    /// The weighted sum of the given pixels, shifted right. The weights add up to 1 << shift.
+    fn mix(&self, shift: u32, weights: &[(usize, u32)]) -> (u8, u8, u8) {
+        let sum = |channel: fn((u8, u8, u8)) -> u8| {
+            let total: u32 = weights.iter().map(|&(n, weight)| channel(self.image.pixels[self.w[n]]) as u32 * weight).sum();
+            (total >> shift) as u8
+        };
+        (sum(|c| c.0), sum(|c| c.1), sum(|c| c.2))
+    }
+
+    fn color(&self, n: usize) -> (u8, u8, u8) {
+        self.image.pixels[self.w[n]]
+    }
+}
+
+/// The order to read the neighbourhood in for each sub-pixel of hq2x, in row order.
+const HQ2X_ORDERS: [[usize; 9]; 4] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 1, 0, 5, 4, 3, 8, 7, 6],
+    [6, 7, 8, 3, 4, 5, 0, 1, 2],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+];
+
+/// The sub-pixels of hq3x handled together, a corner and the side after it going clockwise,
+/// and the order to read the neighbourhood in for them.
+const HQ3X_ORDERS: [([usize; 2], [usize; 9]); 4] = [
+    ([0, 1], [0, 1, 2, 3, 4, 5, 6, 7, 8]),
+    ([2, 5], [2, 5, 8, 1, 4, 7, 0, 3, 6]),
+    ([8, 7], [8, 7, 6, 5, 4, 3, 2, 1, 0]),
+    ([6, 3], [6, 3, 0, 7, 4, 1, 8, 5, 2]),
+];
+
+// The hqx rules below are the compact form of the original 256 case lookup tables, as used by
+// FFmpeg's hqx filter, which gives the same results as the tables.
+
+fn hq2x(image: &Image) -> Vec<(u8, u8, u8)> {
+    assemble(image, 2, |x, y, block| {
+        for (sub_pixel, &order) in block.iter_mut().zip(HQ2X_ORDERS.iter()) {
+            *sub_pixel = hq2x_top_left(&HqNeighbourhood::new(image, x, y, order));
+        }
+    })
+}
+
+fn hq2x_top_left(n: &HqNeighbourhood) -> (u8, u8, u8) {
+    if n.matches(&[(0xbf, 0x37), (0xdb, 0x13)]) && n.differ(1, 5) {
+        n.mix(2, &[(4, 3), (3, 1)])
+    } else if n.matches(&[(0xdb, 0x49), (0xef, 0x6d)]) && n.differ(7, 3) {
+        n.mix(2, &[(4, 3), (1, 1)])
+    } else if n.matches(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && n.differ(3, 1) {
+        n.color(4)
+    } else if n.matches(&[
+        (0x6f, 0x2a), (0x5b, 0x0a), (0xbf, 0x3a), (0xdf, 0x5a), (0x9f, 0x8a), (0xcf, 0x8a), (0xef, 0x4e),
+        (0x3f, 0x0e), (0xfb, 0x5a), (0xbb, 0x8a), (0x7f, 0x5a), (0xaf, 0x8a), (0xeb, 0x8a),
+    ]) && n.differ(3, 1) {
+        n.mix(2, &[(4, 3), (0, 1)])
+    } else if n.matches(&[(0x0b, 0x08)]) {
+        n.mix(2, &[(4, 2), (0, 1), (1, 1)])
+    } else if n.matches(&[(0x0b, 0x02)]) {
+        n.mix(2, &[(4, 2), (0, 1), (3, 1)])
+    } else if n.matches(&[(0x2f, 0x2f)]) {
+        n.mix(4, &[(4, 14), (3, 1), (1, 1)])
+    } else if n.matches(&[(0xbf, 0x37), (0xdb, 0x13)]) {
+        n.mix(3, &[(4, 5), (1, 2), (3, 1)])
+    } else if n.matches(&[(0xdb, 0x49), (0xef, 0x6d)]) {
+        n.mix(3, &[(4, 5), (3, 2), (1, 1)])
+    } else if n.matches(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+        n.mix(2, &[(4, 3), (3, 1)])
+    } else if n.matches(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+        n.mix(2, &[(4, 3), (1, 1)])
+    } else if n.matches(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+        n.mix(3, &[(4, 2), (3, 3), (1, 3)])
+    } else if n.matches(&[(0xfb, 0x6a), (0x6f, 0x6e), (0x3f, 0x3e), (0xfb, 0xfa), (0xdf, 0xde), (0xdf, 0x1e)]) {
+        n.mix(2, &[(4, 3), (0, 1)])
+    } else if n.matches(&[
+        (0x0a, 0x00), (0x4f, 0x4b), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0xee, 0x0a), (0x7e, 0x0a),
+        (0xeb, 0x4b), (0x3b, 0x1b),
+    ]) {
+        n.mix(2, &[(4, 2), (3, 1), (1, 1)])
+    } else {
+        n.mix(3, &[(4, 6), (3, 1), (1, 1)])
+    }
+}
+
+fn hq3x(image: &Image) -> Vec<(u8, u8, u8)> {
+    assemble(image, 3, |x, y, block| {
+        block[4] = image.at(x, y);
+        for &([corner, side], order) in HQ3X_ORDERS.iter() {
+            let n = HqNeighbourhood::new(image, x, y, order);
+            block[corner] = hq3x_top_left(&n);
+            block[side] = hq3x_top(&n);
+        }
+    })
+}
+
+fn hq3x_top_left(n: &HqNeighbourhood) -> (u8, u8, u8) {
+    if n.matches(&[(0xbf, 0x37), (0xdb, 0x13)]) && n.differ(1, 5) {
+        n.mix(2, &[(4, 1), (3, 3)])
+    } else if n.matches(&[(0xdb, 0x49), (0xef, 0x6d)]) && n.differ(7, 3) {
+        n.mix(2, &[(4, 1), (1, 3)])
+    } else if n.matches(&[(0x0b, 0x0b), (0xfe, 0x4a), (0xfe, 0x1a)]) && n.differ(3, 1) {
+        n.color(4)
+    } else if n.matches(&[
+        (0x6f, 0x2a), (0x5b, 0x0a), (0xbf, 0x3a), (0xdf, 0x5a), (0x9f, 0x8a), (0xcf, 0x8a), (0xef, 0x4e),
+        (0x3f, 0x0e), (0xfb, 0x5a), (0xbb, 0x8a), (0x7f, 0x5a), (0xaf, 0x8a), (0xeb, 0x8a),
+    ]) && n.differ(3, 1) {
+        n.mix(2, &[(4, 3), (0, 1)])
+    } else if n.matches(&[(0x4b, 0x09), (0x8b, 0x89), (0x1f, 0x19), (0x3b, 0x19)]) {
+        n.mix(2, &[(4, 3), (1, 1)])
+    } else if n.matches(&[(0x1b, 0x03), (0x4f, 0x43), (0x8b, 0x83), (0x6b, 0x43)]) {
+        n.mix(2, &[(4, 3), (3, 1)])
+    } else if n.matches(&[(0x7e, 0x2a), (0xef, 0xab), (0xbf, 0x8f), (0x7e, 0x0e)]) {
+        n.mix(1, &[(3, 1), (1, 1)])
+    } else if n.matches(&[
+        (0x4f, 0x4b), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0xee, 0x0a), (0x7e, 0x0a), (0xeb, 0x4b),
+        (0x3b, 0x1b),
+    ]) {
+        n.mix(4, &[(4, 2), (3, 7), (1, 7)])
+    } else if n.matches(&[
+        (0x0b, 0x08), (0xf9, 0x68), (0xf3, 0x62), (0x6d, 0x6c), (0x67, 0x66), (0x3d, 0x3c), (0x37, 0x36),
+        (0xf9, 0xf8), (0xdd, 0xdc), (0xf3, 0xf2), (0xd7, 0xd6), (0xdd, 0x1c), (0xd7, 0x16), (0x0b, 0x02),
+    ]) {
+        n.mix(2, &[(4, 3), (0, 1)])
+    } else {
+        n.mix(2, &[(4, 2), (3, 1), (1, 1)])
+    }
+}
+
+fn hq3x_top(n: &HqNeighbourhood) -> (u8, u8, u8) {
+    if n.matches(&[(0xfe, 0xde), (0x9e, 0x16), (0xda, 0x12), (0x17, 0x16), (0x5b, 0x12), (0xbb, 0x12)])
+        && n.differ(1, 5)
+        || n.matches(&[(0x0f, 0x0b), (0x5e, 0x0a), (0xfb, 0x7b), (0x3b, 0x0b), (0xbe, 0x0a), (0x7a, 0x0a)])
+        && n.differ(3, 1) {
+        n.color(4)
+    } else if n.matches(&[(0xbf, 0x8f), (0x7e, 0x0e), (0xbf, 0x37), (0xdb, 0x13)]) {
+        n.mix(2, &[(1, 3), (4, 1)])
+    } else if n.matches(&[(0x02, 0x00), (0x7c, 0x28), (0xed, 0xa9), (0xf5, 0xb4), (0xd9, 0x90)]) {
+        n.mix(2, &[(4, 3), (1, 1)])
+    } else if n.matches(&[
+        (0x4f, 0x4b), (0xfb, 0x7b), (0xfe, 0x7e), (0x9f, 0x1b), (0x2f, 0x0b), (0xbe, 0x0a), (0x7e, 0x0a),
+        (0xfb, 0x4b), (0xfb, 0xdb), (0xfe, 0xde), (0xfe, 0x56), (0x57, 0x56), (0x97, 0x16), (0x3f, 0x1e),
+        (0xdb, 0x12), (0xbb, 0x12),
+    ]) {
+        n.mix(3, &[(4, 7), (1, 1)])
+    } else {
+        n.color(4)
+    }
+}
+
+/// Index in a factor by factor block of the sub-pixel at the given offset from the middle, where
+/// -1 and 1 are the first and last row or column.
+fn block_ix(factor: usize, (x, y): (isize, isize)) -> usize {
+    let to_ix = |offset: isize| if offset < 0 { 0 } else if offset > 0 { factor - 1 } else { factor / 2 };
+    to_ix(y) * factor + to_ix(x)
+}
+
+/// Neighbours of the bottom right corner are named as in Hyllian's description of xBR:
+///       A1 B1 C1
+///    A0 A  B  C  C4
+///    D0 D  E  F  F4
+///    G0 G  H  I  I4
+///       G5 H5 I5
+fn xbr(image: &Image) -> Vec<(u8, u8, u8)> {
+    assemble(image, 2, |x, y, block| {
+        block.fill(image.at(x, y));
+        for quarter_turns in 0..4 {
+            let p = |dx, dy| {
+                let (dx, dy) = rotate(dx, dy, quarter_turns);
+                image.ix(x + dx, y + dy)
+            };
+            let sub_pixels = [(1, 1), (-1, 1), (1, -1)].map(|(sx, sy)| block_ix(2, rotate(sx, sy, quarter_turns)));
+            xbr_corner(image, block, sub_pixels, p);
+        }
+    })
+}
+
+/// Blends the sub-pixels at the bottom right corner, and the ones left of and above it, along an
+/// edge running across the corner.
+fn xbr_corner(image: &Image, block: &mut [(u8, u8, u8)], [corner, left, up]: [usize; 3], p: impl Fn(isize, isize) -> usize) {
+    let (pb, pc, pd, pe, pf) = (p(0, -1), p(1, -1), p(-1, 0), p(0, 0), p(1, 0));
+    let (pg, ph, pi) = (p(-1, 1), p(0, 1), p(1, 1));
+    let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+    let df = |a, b| image.distance(a, b);
+    let eq = |a, b| image.similar(a, b);
+    let ne = |a: usize, b: usize| image.pixels[a] != image.pixels[b];
+
+    if !(ne(pe, ph) && ne(pe, pf)) {
+        return;
+    }
+    // Weighted differences across and along the edge, which decide whether it runs from
+    // top right to bottom left through the corner.
+    let e = df(pe, pc) + df(pe, pg) + df(pi, h5) + df(pi, f4) + 4 * df(ph, pf);
+    let i = df(ph, pd) + df(ph, i5) + df(pf, i4) + df(pf, pb) + 4 * df(pe, pi);
+    let px = image.pixels[if df(pe, pf) <= df(pe, ph) { pf } else { ph }];
+
+    let is_edge = (!eq(pf, pb) && !eq(ph, pd))
+        || (eq(pe, pi) && !eq(pf, i4) && !eq(ph, i5))
+        || eq(pe, pg)
+        || eq(pe, pc);
+    if e < i && is_edge {
+        let ke = df(pf, pg);
+        let ki = df(ph, pc);
+        let shallow = ke * 2 <= ki && ne(pe, pg) && ne(pd, pg);
+        let steep = ke >= ki * 2 && ne(pe, pc) && ne(pb, pc);
+        if shallow && steep {
+            block[corner] = blend(block[corner], px, 224);
+            block[left] = blend(block[left], px, 64);
+            block[up] = block[left];
+        } else if shallow {
+            block[corner] = blend(block[corner], px, 192);
+            block[left] = blend(block[left], px, 64);
+        } else if steep {
+            block[corner] = blend(block[corner], px, 192);
+            block[up] = blend(block[up], px, 64);
+        } else {
+            block[corner] = blend(block[corner], px, 128);
+        }
+    } else if e <= i {
+        block[corner] = blend(block[corner], px, 128);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use crate::gameboy::png::{scale_pixels};
+
+    const W: (u8, u8, u8) = (0xff, 0xff, 0xff);
+    const K: (u8, u8, u8) = (0, 0, 0);
+
+    /// A black diagonal line from the top left to the bottom right on white.
+    fn diagonal() -> Vec<(u8, u8, u8)> {
+        (0..16).map(|i| if i % 4 == i / 4 { K } else { W }).collect()
+    }
+
+    #[test]
+    fn sizes() {
+        let pixels = diagonal();
+        for &(_, filter) in FILTERS.iter() {
+            let factor = filter.factor() as usize;
+            assert_eq!(filter.apply(4, &pixels).len(), 16 * factor * factor, "{:?}", filter);
+        }
+        assert_eq!(Filter::new("hq3x"), Ok(Filter::Hq3x));
+        assert!(Filter::new("hq4x").is_err());
+    }
+
+    #[test]
+    fn flat_image_unchanged() {
+        let pixels = vec![(0x12, 0x34, 0x56); 16];
+        for &(_, filter) in FILTERS.iter() {
+            let factor = filter.factor() as usize;
+            assert_eq!(filter.apply(4, &pixels), vec![(0x12, 0x34, 0x56); 16 * factor * factor], "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn scale2x_fills_in_diagonals() {
+        let scaled = Filter::Scale2x.apply(4, &diagonal());
+        // Pixel (1, 0) is white, with black neighbours on the left and below, so its bottom left
+        // corner turns black and joins up the line.
+        assert_eq!(&scaled[2..4], &[W, W]);
+        assert_eq!(&scaled[8 + 2..8 + 4], &[K, W]);
+        // The black pixels themselves are left alone.
+        assert_eq!(&scaled[0..2], &[K, K]);
+    }
+
+    #[test]
+    fn hqx_isolated_pixel() {
+        let mut pixels = vec![W; 9];
+        pixels[4] = K;
+        // Every neighbour differs, so hq2x darkens each corner by 14/16.
+        let scaled = Filter::Hq2x.apply(3, &pixels);
+        let dot = (31, 31, 31);
+        assert_eq!(&scaled[6 * 2 + 2..6 * 2 + 4], &[dot, dot]);
+        assert_eq!(&scaled[6 * 3 + 2..6 * 3 + 4], &[dot, dot]);
+        // hq3x keeps the pixel's color apart from the corners, which get half of it.
+        let scaled = Filter::Hq3x.apply(3, &pixels);
+        let corner = (127, 127, 127);
+        assert_eq!(&scaled[9 * 3 + 3..9 * 3 + 6], &[corner, K, corner]);
+        assert_eq!(&scaled[9 * 4 + 3..9 * 4 + 6], &[K, K, K]);
+        assert_eq!(&scaled[9 * 5 + 3..9 * 5 + 6], &[corner, K, corner]);
+    }
+
+    #[test]
+    fn hqx_keeps_straight_edges_sharp() {
+        let pixels: Vec<_> = (0..16).map(|i| if i < 8 { K } else { W }).collect();
+        for &filter in [Filter::Hq2x, Filter::Hq3x].iter() {
+            assert_eq!(filter.apply(4, &pixels), scale_pixels(4, &pixels, filter.factor()), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn smoothing_filters_blend_diagonals() {
+        for &filter in [Filter::Hq2x, Filter::Xbr].iter() {
+            let scaled = filter.apply(4, &diagonal());
+            // The bottom left corner of pixel (1, 0) is between black and white.
+            let (r, _, _) = scaled[8 + 2];
+            assert!(r > 0 && r < 0xff, "{:?}: {:?}", filter, scaled[8 + 2]);
+            assert_eq!(scaled[3], W, "{:?}", filter);
+        }
+    }
+}
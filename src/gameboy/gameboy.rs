@@ -1,52 +1,80 @@
 use std::fmt;
-use std::sync::{Arc, Mutex, Condvar};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use crate::gameboy::cartridge::{*};
-
-/// 8-bit register.
-pub type R = usize;
-/// 16-bit register, i.e. a pair of 8-bit registers used together.
-pub type RR = (usize, usize);
-
-pub const RB: R = 0;
-pub const RC: R = 1;
-pub const RD: R = 2;
-pub const RE: R = 3;
-pub const RH: R = 4;
-pub const RL: R = 5;
-pub const RF: R = 6;
-pub const RA: R = 7;
-
-pub const RAF: RR = (RA, RF);
-pub const RBC: RR = (RB, RC);
-pub const RDE: RR = (RD, RE);
-pub const RHL: RR = (RH, RL);
-
-pub const FLAG_Z: u8 = 0b10000000;
-pub const FLAG_N: u8 = 0b01000000;
-pub const FLAG_H: u8 = 0b00100000;
-pub const FLAG_C: u8 = 0b00010000;
+use crate::gameboy::model::{*};
+use crate::gameboy::hdma::{*};
+use crate::gameboy::cgb_palette::{*};
+use crate::gameboy::sgb::{*};
+use crate::gameboy::ppu::{*};
+use crate::gameboy::timer::{*};
+use crate::gameboy::joypad::{*};
+use crate::gameboy::movie::{*};
+use crate::gameboy::speed::{*};
+use crate::gameboy::capture::{*};
+use crate::gameboy::bess::{*};
+use crate::gameboy::scheduler::{tick};
+use crate::gameboy::cpu::{Cpu, Bus, RA, RB, RC, RD, RE, RF, RH, RL, FLAG_Z, FLAG_N, FLAG_H, FLAG_C};
 
 // IO port/register aliases, relative to 0xff00.
 pub const IO_P1: usize   = 0x00;
+pub const IO_SB: usize   = 0x01;
+pub const IO_SC: usize   = 0x02;
+pub const IO_DIV: usize  = 0x04;
 pub const IO_TIMA: usize = 0x05;
 pub const IO_TMA: usize  = 0x06;
 pub const IO_TAC: usize  = 0x07;
 pub const IO_IF: usize   = 0x0f;
+pub const IO_NR10: usize = 0x10;
+pub const IO_NR11: usize = 0x11;
+pub const IO_NR12: usize = 0x12;
+pub const IO_NR13: usize = 0x13;
+pub const IO_NR14: usize = 0x14;
+pub const IO_NR21: usize = 0x16;
+pub const IO_NR22: usize = 0x17;
+pub const IO_NR23: usize = 0x18;
+pub const IO_NR24: usize = 0x19;
+pub const IO_NR30: usize = 0x1a;
+pub const IO_NR31: usize = 0x1b;
+pub const IO_NR32: usize = 0x1c;
+pub const IO_NR33: usize = 0x1d;
+pub const IO_NR34: usize = 0x1e;
+pub const IO_NR41: usize = 0x20;
+pub const IO_NR42: usize = 0x21;
+pub const IO_NR43: usize = 0x22;
+pub const IO_NR44: usize = 0x23;
+pub const IO_NR50: usize = 0x24;
+pub const IO_NR51: usize = 0x25;
+pub const IO_NR52: usize = 0x26;
 pub const IO_LCDC: usize = 0x40; 
 pub const IO_STAT: usize = 0x41;
 pub const IO_SCY: usize  = 0x42; 
 pub const IO_SCX: usize  = 0x43; 
 pub const IO_LY: usize   = 0x44;
 pub const IO_LYC: usize  = 0x45;
+pub const IO_DMA: usize  = 0x46;
 pub const IO_BGP: usize  = 0x47;
 pub const IO_OBP0: usize = 0x48;
 pub const IO_OBP1: usize = 0x49;
 pub const IO_WY: usize   = 0x4a;
 pub const IO_WX: usize   = 0x4b;
+// CGB-only registers.
+pub const IO_KEY1: usize  = 0x4d;
+pub const IO_VBK: usize   = 0x4f;
+pub const IO_HDMA1: usize = 0x51;
+pub const IO_HDMA2: usize = 0x52;
+pub const IO_HDMA3: usize = 0x53;
+pub const IO_HDMA4: usize = 0x54;
+pub const IO_HDMA5: usize = 0x55;
+pub const IO_BCPS: usize  = 0x68;
+pub const IO_BCPD: usize  = 0x69;
+pub const IO_OCPS: usize  = 0x6a;
+pub const IO_OCPD: usize  = 0x6b;
+pub const IO_SVBK: usize  = 0x70;
 // In the memory map this is at 0xffff, but to simplify things internally we store this at the end
 // of the io_ports array
-pub const IO_IE: usize   = 0x4c;
+pub const IO_IE: usize   = 0x80;
 
 pub const P1_OUT: u8     = 0b0011_0000;
 pub const P1_P14_OUT: u8 = 0b0001_0000;
@@ -55,6 +83,9 @@ pub const P1_P15_OUT: u8 = 0b0010_0000;
 pub const TAC_CLOCK_SELECT: u8 = 0b0000_0011;
 pub const TAC_ENABLE: u8       = 0b0000_0100;
 
+pub const KEY1_SPEED: u8   = 0b1000_0000;
+pub const KEY1_PREPARE: u8 = 0b0000_0001;
+
 pub const CONTROLLER_DATA_P14: u8   = 0b1111_0000;
 pub const CONTROLLER_DATA_P15: u8   = 0b0000_1111;
 pub const CONTROLLER_DATA_A: u8     = 0b0000_0001;
@@ -93,13 +124,18 @@ pub const INT_LCDC: u8        = 0b0000_0010;
 pub const INT_TIMER: u8       = 0b0000_0100;
 pub const INT_SERIAL: u8      = 0b0000_1000;
 pub const INT_HILO: u8        = 0b0001_0000;
+/// All interrupt flags. The top 3 bits of IF and IE don't correspond to any interrupt.
+pub const INT_ALL: u8         = 0b0001_1111;
+
+/// How many machine cycles the CPU is paused for while switching between normal and double speed.
+const SPEED_SWITCH_CYCLES: u64 = 2050;
 
 pub struct IoPorts {
-    io_ports: [AtomicU8; 0x4d],
+    io_ports: [AtomicU8; 0x81],
 }
 
 impl IoPorts {
-    pub fn new(io_ports: [AtomicU8; 0x4d]) -> Self {
+    pub fn new(io_ports: [AtomicU8; 0x81]) -> Self {
         Self {
             io_ports
         }
@@ -124,10 +160,6 @@ impl IoPorts {
     pub fn xor(&self, port: usize, value: u8) {
         self.io_ports[port].fetch_xor(value, Ordering::Relaxed);
     }
-
-    pub fn add(&self, port: usize, value: u8) {
-        self.io_ports[port].fetch_add(value, Ordering::Relaxed);
-    }
 }
 
 pub struct Debug {
@@ -149,47 +181,82 @@ impl Debug {
 }
 
 pub struct Gameboy {
-    wram: Box<[u8; 0x2000]>,
-    pub vram: Arc<Mutex<[u8; 0x2000]>>,
+    /// Mapped over the start of the cartridge ROM until the game writes to 0xff50.
+    boot_rom: Option<Vec<u8>>,
+    /// Whether CGB features are enabled. This is only the case when running a game that supports
+    /// the CGB on a CGB; otherwise the CGB runs in DMG compatibility mode.
+    pub cgb_mode: bool,
+    /// 8 banks of 0x1000 bytes each. Bank 0 is always mapped to 0xc000-0xcfff, and the bank
+    /// selected by SVBK is mapped to 0xd000-0xdfff. Only banks 0 and 1 are used outside of CGB mode.
+    wram: Box<[u8; 0x8000]>,
+    /// 2 banks of 0x2000 bytes each, with the bank mapped to 0x8000-0x9fff selected by VBK. Only
+    /// bank 0 is used outside of CGB mode.
+    pub vram: Arc<Mutex<[u8; 0x4000]>>,
     pub oam: Arc<Mutex<[u8; 0xa0]>>,
     pub io_ports: Arc<IoPorts>,
     hram: Box<[u8; 0x7f]>,
     cartridge: Cartridge,
 
     pub cycles: u64, 
-    pub pc: u16,
-    pub sp: u16,
-    pub regs: [u8; 8], 
-    pub ime: Arc<AtomicBool>,
-    pub halted: Arc<AtomicBool>,
-    pub stopped: Arc<AtomicBool>,
+    pub cpu: Cpu,
+    /// Whether the CPU is running in CGB double speed mode.
+    pub double_speed: Arc<AtomicBool>,
+    pub hdma: Hdma,
+    pub ppu: Ppu,
+    pub timer: Timer,
 
     pub debug: Debug,
 
-    /// CPU can wait on this variable to sleep until interrupted.
-    pub interrupt_received: Arc<(Mutex<bool>, Condvar)>,
-    /// Timer can wait on this variable to sleep until timer is enabled.
-    pub timer_enabled: Arc<(Mutex<bool>, Condvar)>,
     /// Which buttons are currently being pressed.
     /// Like the actual Gameboy P1 register, 1 means not pressed and 0 means pressed.
     pub controller_data: Arc<AtomicU8>,
-    /// Pixel data to be drawn to the screen.
+    /// The P1 input lines as of the last tick, used to catch the falling edges that request the
+    /// joypad interrupt.
+    pub joypad_prev_lines: u8,
+    /// The input movie being recorded or played back, if any.
+    pub movie: Option<MovieRunner>,
+    pub speed: SpeedControl,
+    pub capture: FrameCapture,
+    /// The last complete frame, which the PPU replaces at the start of every VBlank. Lines are
+    /// drawn to a separate buffer in the meantime, so this never holds parts of two frames.
     pub screen: Arc<Mutex<[[(u8, u8, u8); 160]; 144]>>,
+    /// Set whenever a new frame is put in `screen`. The frontend clears it when it presents the
+    /// frame, so that each frame is presented once.
+    pub frame_ready: Arc<AtomicBool>,
+    /// Super Gameboy state, only present when emulating an SGB.
+    pub sgb: Option<Arc<Mutex<Sgb>>>,
+    /// CGB background and object palette RAM.
+    pub cgb_palettes: Arc<Mutex<CgbPalettes>>,
+    /// Set by the PPU whenever it enters HBlank, used to pace HBlank DMA transfers.
+    pub hblank_started: Arc<AtomicBool>,
 }
 
 impl Gameboy {
-    pub fn new(cartridge: Cartridge) -> Self {
+    /// Creates a Gameboy that starts executing from the boot ROM if one is given. Otherwise, the
+    /// CPU and IO registers are set up the way the boot ROM for the given model would have left
+    /// them, and execution starts at the cartridge entry point.
+    pub fn new(cartridge: Cartridge, model: Model, boot_rom: Option<Vec<u8>>) -> Self {
         const ZERO_AU8: AtomicU8 = AtomicU8::new(0);
-        let io_ports = IoPorts::new([ZERO_AU8; 0x4d]);
-        io_ports.write(IO_P1, 0xcf); // not sure if this is accurate but BGB seems to do it
-        io_ports.write(IO_LCDC, 0x91);
-        io_ports.write(IO_BGP, 0xfc);
-        io_ports.write(IO_OBP0, 0xff);
-        io_ports.write(IO_OBP1, 0xff);
+        let io_ports = IoPorts::new([ZERO_AU8; 0x81]);
+
+        let header: Vec<u8> = (0x0000..0x0150).map(|addr| cartridge.read_rom(addr)).collect();
+        let cgb_mode = model == Model::Cgb && header[0x143] & 0x80 > 0;
+
+        let (pc, regs) = if boot_rom.is_some() {
+            (0x0000, [0; 8])
+        } else {
+            for (port, value) in post_boot_io_ports(model) {
+                io_ports.write(port, value);
+            }
+            (0x0100, post_boot_regs(model, &header))
+        };
+        let timer = Timer::new(io_ports.read(IO_DIV));
 
         Self {
-            wram: Box::new([0; 0x2000]),
-            vram: Arc::new(Mutex::new([0; 0x2000])),
+            boot_rom,
+            cgb_mode,
+            wram: Box::new([0; 0x8000]),
+            vram: Arc::new(Mutex::new([0; 0x4000])),
             oam: Arc::new(Mutex::new([0; 0xa0])),
             io_ports: Arc::new(io_ports),
             hram: Box::new([0; 0x7f]),
@@ -198,37 +265,46 @@ impl Gameboy {
             debug: Debug::new(),
 
             cycles: 0,
-            pc: 0x0100, 
-            sp: 0xfffe,
-            regs: [0; 8],
-            ime: Arc::new(AtomicBool::new(false)),
-            halted: Arc::new(AtomicBool::new(false)),
-            stopped: Arc::new(AtomicBool::new(false)),
-
-            interrupt_received: Arc::new((Mutex::new(false), Condvar::new())),
-            timer_enabled: Arc::new((Mutex::new(false), Condvar::new())),
+            cpu: Cpu::new(pc, regs),
+            double_speed: Arc::new(AtomicBool::new(false)),
+            hdma: Hdma::new(),
+            ppu: Ppu::new(cgb_mode),
+            timer,
+
             controller_data: Arc::new(AtomicU8::new(0xff)),
+            joypad_prev_lines: 0b0000_1111,
+            movie: None,
+            speed: SpeedControl::new(),
+            capture: FrameCapture::new(),
             screen: Arc::new(Mutex::new([[(0,0,0); 160]; 144])),
+            frame_ready: Arc::new(AtomicBool::new(false)),
+            sgb: if model == Model::Sgb { Some(Arc::new(Mutex::new(Sgb::new()))) } else { None },
+            cgb_palettes: Arc::new(Mutex::new(CgbPalettes::new())),
+            hblank_started: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
+        if let Some(value) = self.read_boot_rom(addr) {
+            return value;
+        }
+
         match addr {
             0x0000..=0x7fff => {
                 self.cartridge.read_rom(addr)
             },
             0x8000..=0x9fff => {
                 let vram = self.vram.lock().unwrap();
-                vram[(addr - 0x8000) as usize]
+                vram[self.vram_offset(addr)]
             },
             0xa000..=0xbfff => {
                 self.cartridge.read_ram(addr)
             },
             0xc000..=0xdfff => {
-                self.wram[(addr - 0xc000) as usize] 
+                self.wram[self.wram_offset(addr)]
             },
             0xe000..=0xfdff => {
-                self.wram[(addr - 0xe000) as usize]
+                self.wram[self.wram_offset(addr - 0x2000)]
             },
             0xfe00..=0xfe9f => {
                 let oam = self.oam.lock().unwrap();
@@ -238,10 +314,15 @@ impl Gameboy {
                 println!("Warning: attempt to read from invalid memory ${addr:0>4x}");
                 0xff
             },
-            0xff00..=0xff4b => {
+            0xff00 => read_p1(self),
+            0xff01..=0xff4b => {
                 let port = (addr - 0xff00) as usize;
                 self.io_ports.read(port)
             },
+            0xff4d | 0xff4f | 0xff51..=0xff55 | 0xff68..=0xff6b | 0xff70 if self.cgb_mode => {
+                self.read_cgb_register((addr - 0xff00) as usize)
+            },
+            0xff50 => 0xff,
             0xff4c..=0xff7f => {
                 println!("Warning: attempt to read from invalid memory ${addr:0>4x}");
                 0xff
@@ -261,17 +342,18 @@ impl Gameboy {
                 self.cartridge.write_rom(addr, value)
             },
             0x8000..=0x9fff => {
+                let offset = self.vram_offset(addr);
                 let mut vram = self.vram.lock().unwrap();
-                vram[(addr - 0x8000) as usize] = value
+                vram[offset] = value
             },
             0xa000..=0xbfff => {
                 self.cartridge.write_ram(addr, value)
             },
             0xc000..=0xdfff => {
-                self.wram[(addr - 0xc000) as usize] = value
+                self.wram[self.wram_offset(addr)] = value
             },
             0xe000..=0xfdff => {
-                self.wram[(addr - 0xe000) as usize] = value
+                self.wram[self.wram_offset(addr - 0x2000)] = value
             },
             0xfe00..=0xfe9f => {
                 let mut oam = self.oam.lock().unwrap();
@@ -283,34 +365,31 @@ impl Gameboy {
             0xff00..=0xff4b => {
                 let port = (addr - 0xff00) as usize;
                 match port {
-                    IO_P1 => {
-                        let output_select = value & P1_OUT;
-                        let cont_data = self.controller_data.load(Ordering::Relaxed);
-                        let output =
-                            if output_select & P1_P15_OUT == 0 {
-                                cont_data & CONTROLLER_DATA_P15
-                            } else if output_select & P1_P14_OUT == 0 {
-                                (cont_data & CONTROLLER_DATA_P14) >> 4
-                            } else {
-                                // TODO does P1 actually output 1s here if no output is selected?
-                                0b0000_1111
-                            };
-
-                        self.io_ports.write(IO_P1, output_select | output);
-                    },
-                    IO_TAC => {
-                        self.io_ports.write(IO_TAC, value);
-
-                        if value & TAC_ENABLE > 0 {
-                            let (mutex, cvar) = &*self.timer_enabled;
-                            let mut enabled = mutex.lock().unwrap();
-                            *enabled = true;
-                            cvar.notify_one();
-                        }
+                    IO_P1 => write_p1(self, value),
+                    IO_DIV => write_div(self),
+                    IO_TIMA => write_tima(self, value),
+                    IO_TMA => write_tma(self, value),
+                    IO_TAC => write_tac(self, value),
+                    IO_STAT => {
+                        // The mode and LY=LYC bits are read-only.
+                        let read_only = STAT_MODE | STAT_LYC_SET;
+                        let stat = (self.io_ports.read(IO_STAT) & read_only) | (value & !read_only);
+                        self.io_ports.write(IO_STAT, stat);
                     },
+                    // LY is read-only.
+                    IO_LY => {},
                     _ => self.io_ports.write(port, value),
                 }
             },
+            0xff4d | 0xff4f | 0xff51..=0xff55 | 0xff68..=0xff6b | 0xff70 if self.cgb_mode => {
+                self.write_cgb_register((addr - 0xff00) as usize, value)
+            },
+            0xff50 => {
+                if value & 1 > 0 && self.boot_rom.is_some() {
+                    // Once unmapped, the boot ROM can't be mapped back in.
+                    self.boot_rom = None;
+                }
+            },
             0xff4c..=0xff7f => {
                 println!("Warning: attempt to write to invalid memory ${addr:0>4x}")
             },
@@ -322,6 +401,107 @@ impl Gameboy {
             },
         }
     }
+
+    /// The state the CPU is in, encoded the way the BESS CORE block stores it.
+    pub fn execution_state(&self) -> u8 {
+        if self.stopped.load(Ordering::Relaxed) {
+            EXECUTION_STATE_STOPPED
+        } else if self.halted.load(Ordering::Relaxed) {
+            EXECUTION_STATE_HALTED
+        } else {
+            EXECUTION_STATE_RUNNING
+        }
+    }
+
+    /// Puts the CPU in the state from a BESS CORE block. Unknown states are treated as running.
+    pub fn set_execution_state(&mut self, execution_state: u8) {
+        self.halted.store(execution_state == EXECUTION_STATE_HALTED, Ordering::Relaxed);
+        self.stopped.store(execution_state == EXECUTION_STATE_STOPPED, Ordering::Relaxed);
+    }
+
+    fn read_cgb_register(&self, port: usize) -> u8 {
+        match port {
+            IO_KEY1 => {
+                let speed = if self.double_speed.load(Ordering::Relaxed) { KEY1_SPEED } else { 0 };
+                0b0111_1110 | speed | self.io_ports.read(IO_KEY1)
+            },
+            IO_VBK => 0b1111_1110 | self.io_ports.read(IO_VBK),
+            IO_HDMA5 => self.hdma.hdma5(),
+            IO_BCPS | IO_OCPS => 0b0100_0000 | self.io_ports.read(port),
+            IO_BCPD | IO_OCPD => {
+                // Palette RAM can't be accessed while the PPU is drawing.
+                if self.io_ports.read(IO_STAT) & STAT_MODE == STAT_MODE_TRANSFER {
+                    return 0xff;
+                }
+                let palettes = self.cgb_palettes.lock().unwrap();
+                if port == IO_BCPD {
+                    palettes.bg[(self.io_ports.read(IO_BCPS) & CPS_INDEX) as usize]
+                } else {
+                    palettes.obj[(self.io_ports.read(IO_OCPS) & CPS_INDEX) as usize]
+                }
+            },
+            IO_SVBK => 0b1111_1000 | self.io_ports.read(IO_SVBK),
+            // HDMA1-HDMA4 are write-only.
+            _ => 0xff,
+        }
+    }
+
+    fn write_cgb_register(&mut self, port: usize, value: u8) {
+        match port {
+            IO_KEY1 => self.io_ports.write(IO_KEY1, value & KEY1_PREPARE),
+            IO_VBK => self.io_ports.write(IO_VBK, value & 0b0000_0001),
+            IO_HDMA1 => self.hdma.src = (self.hdma.src & 0x00ff) | ((value as u16) << 8),
+            IO_HDMA2 => self.hdma.src = (self.hdma.src & 0xff00) | ((value & 0xf0) as u16),
+            IO_HDMA3 => self.hdma.dst = (self.hdma.dst & 0x00ff) | (((value & 0x1f) as u16) << 8),
+            IO_HDMA4 => self.hdma.dst = (self.hdma.dst & 0xff00) | ((value & 0xf0) as u16),
+            IO_HDMA5 => start_hdma(self, value),
+            IO_BCPS | IO_OCPS => self.io_ports.write(port, value & (CPS_INCREMENT | CPS_INDEX)),
+            IO_BCPD | IO_OCPD => {
+                let cps_port = if port == IO_BCPD { IO_BCPS } else { IO_OCPS };
+                let cps = self.io_ports.read(cps_port);
+                let ix = (cps & CPS_INDEX) as usize;
+                if self.io_ports.read(IO_STAT) & STAT_MODE != STAT_MODE_TRANSFER {
+                    let mut palettes = self.cgb_palettes.lock().unwrap();
+                    if port == IO_BCPD { palettes.bg[ix] = value; } else { palettes.obj[ix] = value; }
+                }
+                // The index is incremented even if the write itself was blocked.
+                if cps & CPS_INCREMENT > 0 {
+                    let next_ix = (cps.wrapping_add(1)) & CPS_INDEX;
+                    self.io_ports.write(cps_port, CPS_INCREMENT | next_ix);
+                }
+            },
+            IO_SVBK => self.io_ports.write(IO_SVBK, value & 0b0000_0111),
+            _ => panic!("Invalid CGB register ${:0>4x}", 0xff00 + port),
+        }
+    }
+
+    /// Offset into the VRAM array for an address in 0x8000-0x9fff, taking the selected VRAM bank
+    /// into account.
+    fn vram_offset(&self, addr: u16) -> usize {
+        let bank = (self.io_ports.read(IO_VBK) & 1) as usize;
+        bank * 0x2000 + (addr - 0x8000) as usize
+    }
+
+    /// Offset into the WRAM array for an address in 0xc000-0xdfff, taking the selected WRAM bank
+    /// into account.
+    fn wram_offset(&self, addr: u16) -> usize {
+        if addr < 0xd000 {
+            (addr - 0xc000) as usize
+        } else {
+            // Selecting bank 0 selects bank 1 instead.
+            let bank = usize::max(1, (self.io_ports.read(IO_SVBK) & 0b111) as usize);
+            bank * 0x1000 + (addr - 0xd000) as usize
+        }
+    }
+
+    fn read_boot_rom(&self, addr: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        match addr {
+            0x0000..=0x00ff => Some(boot_rom[addr as usize]),
+            0x0200..=0x08ff if boot_rom.len() > 0x100 => Some(boot_rom[addr as usize]),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Gameboy {
@@ -334,7 +514,7 @@ impl fmt::Display for Gameboy {
                 "A: {:0>2X}, B: {:0>2X}, D: {:0>2X}, H: {:0>2X}\n",
                 "F: {:0>2X}, C: {:0>2X}, E: {:0>2X}, L: {:0>2X}\n",
                 "LY: {:0>2X}, LCDC: {:0>2X}, STAT: {:0>2X}\n",
-                "P1: {:0>2X}",
+                "P1: {:0>2X}, state: {}",
             ),
             self.pc, self.sp, self.cycles,
             (self.regs[RF] & FLAG_Z) >> 7, (self.regs[RF] & FLAG_N) >> 6,
@@ -342,14 +522,71 @@ impl fmt::Display for Gameboy {
             self.regs[RA], self.regs[RB], self.regs[RD], self.regs[RH],
             self.regs[RF], self.regs[RC], self.regs[RE], self.regs[RL],
             self.io_ports.read(IO_LY), self.io_ports.read(IO_LCDC), self.io_ports.read(IO_STAT),
-            self.io_ports.read(IO_P1),
+            read_p1(self),
+            match self.execution_state() {
+                EXECUTION_STATE_HALTED => "halted",
+                EXECUTION_STATE_STOPPED => "stopped",
+                _ => if self.locked_up { "locked up" } else { "running" },
+            },
         )
     }
 }
 
-/// Converts a register pair alias into a u16.
-pub fn rr_to_u16(gb: &Gameboy, reg_pair: RR) -> u16 {
-    let upper = (gb.regs[reg_pair.0] as u16) << 8;
-    let lower = gb.regs[reg_pair.1] as u16;
-    upper ^ lower
+impl Deref for Gameboy {
+    type Target = Cpu;
+
+    fn deref(&self) -> &Cpu {
+        &self.cpu
+    }
+}
+
+impl DerefMut for Gameboy {
+    fn deref_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+impl Bus for Gameboy {
+    fn read(&self, addr: u16) -> u8 {
+        Gameboy::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        Gameboy::write(self, addr, value)
+    }
+
+    fn tick(&mut self, m_cycles: u64) {
+        tick(self, m_cycles)
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.io_ports.read(IO_IE) & self.io_ports.read(IO_IF) & INT_ALL
+    }
+
+    fn acknowledge_interrupt(&mut self, flag: u8) {
+        self.io_ports.and(IO_IF, !flag);
+    }
+
+    fn joypad_lines(&self) -> u8 {
+        joypad_lines(self)
+    }
+
+    fn stop(&mut self) -> bool {
+        write_div(self);
+        if self.cgb_mode && self.io_ports.read(IO_KEY1) & KEY1_PREPARE > 0 {
+            // In CGB mode, STOP is how the game actually performs a speed switch it prepared via
+            // KEY1.
+            let double_speed = self.double_speed.load(Ordering::Relaxed);
+            self.double_speed.store(!double_speed, Ordering::Relaxed);
+            self.io_ports.and(IO_KEY1, !KEY1_PREPARE);
+            tick(self, SPEED_SWITCH_CYCLES);
+            return false;
+        }
+        blank_lcd(self);
+        true
+    }
 }
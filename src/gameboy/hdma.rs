@@ -0,0 +1,93 @@
+use std::sync::atomic::{Ordering};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::scheduler::{tick};
+
+/// Number of machine cycles it takes to copy a single 0x10-byte block, in normal speed mode.
+const BLOCK_CYCLES: u64 = 8;
+
+/// CGB VRAM DMA controller, configured through HDMA1-HDMA5.
+pub struct Hdma {
+    /// Source address, set by HDMA1 (high) and HDMA2 (low). The bottom 4 bits are always 0.
+    pub src: u16,
+    /// Destination offset into VRAM, set by HDMA3 (high) and HDMA4 (low). The bottom 4 bits are
+    /// always 0.
+    pub dst: u16,
+    /// Number of 0x10-byte blocks that still need to be copied.
+    pub blocks_left: u8,
+    /// Whether an HBlank transfer is currently in progress.
+    pub hblank_active: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            src: 0x0000,
+            dst: 0x0000,
+            blocks_left: 0,
+            hblank_active: false,
+        }
+    }
+
+    /// The value read back from HDMA5.
+    /// Bit 7 is 0 while an HBlank transfer is running, and the bottom bits hold the number of
+    /// blocks left minus 1. Once a transfer completes this reads 0xff.
+    pub fn hdma5(&self) -> u8 {
+        if self.hblank_active {
+            (self.blocks_left - 1) & 0x7f
+        } else if self.blocks_left == 0 {
+            0xff
+        } else {
+            0x80 | ((self.blocks_left - 1) & 0x7f)
+        }
+    }
+}
+
+/// Handles a write to HDMA5, which either starts a general-purpose transfer, starts an HBlank
+/// transfer, or cancels an HBlank transfer that's already in progress.
+pub fn start_hdma(gb: &mut Gameboy, value: u8) {
+    if gb.hdma.hblank_active && value & 0x80 == 0 {
+        gb.hdma.hblank_active = false;
+        return;
+    }
+
+    gb.hdma.blocks_left = (value & 0x7f) + 1;
+
+    if value & 0x80 > 0 {
+        // Only count HBlanks that start after the transfer was requested.
+        gb.hblank_started.store(false, Ordering::Relaxed);
+        gb.hdma.hblank_active = true;
+    } else {
+        // General-purpose transfers copy everything at once, and the CPU is paused until the copy
+        // is done.
+        while gb.hdma.blocks_left > 0 {
+            copy_block(gb);
+        }
+    }
+}
+
+/// Copies the next block of an HBlank transfer if the PPU has entered HBlank since the last block
+/// was copied.
+pub fn run_hdma_hblank(gb: &mut Gameboy) {
+    if !gb.hdma.hblank_active || !gb.hblank_started.swap(false, Ordering::Relaxed) {
+        return;
+    }
+
+    copy_block(gb);
+    if gb.hdma.blocks_left == 0 {
+        gb.hdma.hblank_active = false;
+    }
+}
+
+fn copy_block(gb: &mut Gameboy) {
+    for i in 0..0x10 {
+        let value = gb.read(gb.hdma.src.wrapping_add(i));
+        gb.write(0x8000 | (gb.hdma.dst.wrapping_add(i) & 0x1fff), value);
+    }
+    gb.hdma.src = gb.hdma.src.wrapping_add(0x10);
+    gb.hdma.dst = gb.hdma.dst.wrapping_add(0x10) & 0x1ff0;
+    gb.hdma.blocks_left -= 1;
+
+    // The copy takes the same amount of real time in both speed modes, so twice as many CPU
+    // cycles in double speed mode.
+    tick(gb, if gb.double_speed.load(Ordering::Relaxed) { BLOCK_CYCLES * 2 } else { BLOCK_CYCLES });
+}
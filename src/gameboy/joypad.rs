@@ -0,0 +1,146 @@
+use std::sync::atomic::{Ordering};
+use crate::gameboy::gameboy::{*};
+
+/// The P1 input lines for the button groups currently selected in P1. A line is low while one of
+/// the buttons connected to it is pressed. With both groups selected, a line is low if a button
+/// from either group is pressed.
+pub fn joypad_lines(gb: &Gameboy) -> u8 {
+    let output_select = gb.io_ports.read(IO_P1) & P1_OUT;
+    let cont_data = match &gb.movie {
+        Some(runner) => runner.input,
+        None => gb.controller_data.load(Ordering::Relaxed),
+    };
+    let (joypad_id, current_player) = match &gb.sgb {
+        Some(sgb) => {
+            let sgb = sgb.lock().unwrap();
+            (sgb.joypad_id(), sgb.current_player)
+        },
+        None => (None, 0),
+    };
+    if current_player != 0 && output_select != P1_OUT {
+        // Only player 1's controller is hooked up.
+        return 0b0000_1111;
+    }
+    if output_select == P1_OUT {
+        // In SGB multiplayer mode, this is how games tell which controller is being read.
+        // TODO does P1 actually output 1s here if no output is selected?
+        return joypad_id.unwrap_or(0b0000_1111);
+    }
+
+    let mut lines = 0b0000_1111;
+    if output_select & P1_P15_OUT == 0 {
+        lines &= cont_data & CONTROLLER_DATA_P15;
+    }
+    if output_select & P1_P14_OUT == 0 {
+        lines &= (cont_data & CONTROLLER_DATA_P14) >> 4;
+    }
+    lines
+}
+
+/// P1 as the game sees it, computed from the buttons being pressed at the time of the read.
+pub fn read_p1(gb: &Gameboy) -> u8 {
+    // The top 2 bits aren't connected to anything and always read as 1.
+    0b1100_0000 | (gb.io_ports.read(IO_P1) & P1_OUT) | joypad_lines(gb)
+}
+
+pub fn write_p1(gb: &mut Gameboy, value: u8) {
+    if let Some(sgb) = &gb.sgb {
+        sgb.lock().unwrap().write_p1(value);
+    }
+    gb.io_ports.write(IO_P1, value & P1_OUT);
+}
+
+/// Requests the joypad interrupt whenever one of the input lines goes from high to low, either
+/// because a button was pressed or because the game selected a group with a button held down.
+pub fn run_joypad(gb: &mut Gameboy) {
+    let lines = joypad_lines(gb);
+    if gb.joypad_prev_lines & !lines > 0 {
+        gb.io_ports.or(IO_IF, INT_HILO);
+    }
+    gb.joypad_prev_lines = lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use crate::gameboy::utils::{test_dmg};
+
+    fn test_gameboy(select: u8) -> Gameboy {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_IF, 0);
+        write_p1(&mut gb, select);
+        run_joypad(&mut gb);
+        gb
+    }
+
+    fn press(gb: &Gameboy, buttons: u8) {
+        gb.controller_data.store(!buttons, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn read_is_live() {
+        let gb = test_gameboy(P1_P14_OUT);
+        assert_eq!(read_p1(&gb), 0b1101_1111);
+
+        // Nothing is written to P1 in between, but the next read still sees the button.
+        press(&gb, CONTROLLER_DATA_B);
+        assert_eq!(read_p1(&gb), 0b1101_1101);
+
+        press(&gb, 0);
+        assert_eq!(read_p1(&gb), 0b1101_1111);
+    }
+
+    #[test]
+    fn groups() {
+        let mut gb = test_gameboy(P1_P14_OUT);
+        press(&gb, CONTROLLER_DATA_A | CONTROLLER_DATA_DOWN);
+        assert_eq!(read_p1(&gb) & 0x0f, 0b1110);
+
+        write_p1(&mut gb, P1_P15_OUT);
+        assert_eq!(read_p1(&gb) & 0x0f, 0b0111);
+
+        write_p1(&mut gb, P1_OUT);
+        assert_eq!(read_p1(&gb) & 0x0f, 0b1111);
+    }
+
+    #[test]
+    fn both_groups_selected() {
+        let gb = test_gameboy(0);
+        press(&gb, CONTROLLER_DATA_A | CONTROLLER_DATA_UP);
+        // A is on line 0 and Up is on line 2.
+        assert_eq!(read_p1(&gb), 0b1100_1010);
+    }
+
+    #[test]
+    fn interrupt_on_press() {
+        let mut gb = test_gameboy(P1_P14_OUT);
+        press(&gb, CONTROLLER_DATA_ST);
+        run_joypad(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_HILO, INT_HILO);
+
+        // Holding the button down doesn't request it again.
+        gb.io_ports.write(IO_IF, 0);
+        run_joypad(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_HILO, 0);
+    }
+
+    #[test]
+    fn no_interrupt_for_unselected_group() {
+        let mut gb = test_gameboy(P1_P14_OUT);
+        press(&gb, CONTROLLER_DATA_LEFT);
+        run_joypad(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_HILO, 0);
+    }
+
+    #[test]
+    fn interrupt_on_select() {
+        let mut gb = test_gameboy(P1_OUT);
+        press(&gb, CONTROLLER_DATA_RIGHT);
+        run_joypad(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_HILO, 0);
+
+        write_p1(&mut gb, P1_P15_OUT);
+        run_joypad(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_HILO, INT_HILO);
+    }
+}
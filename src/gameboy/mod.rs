@@ -2,18 +2,38 @@ mod gameboy;
 mod cpu;
 mod ppu;
 mod timer;
+mod joypad;
 mod cartridge;
 mod debug;
 mod debug_info;
 mod utils;
 mod bess;
+mod model;
+mod hdma;
+mod cgb_palette;
+mod dmg_palette;
+mod sgb;
+mod scheduler;
+mod sha1;
+mod movie;
+mod speed;
+mod png;
+mod capture;
+mod video;
+mod filter;
 
 pub use gameboy::{*};
 pub use cpu::{*};
-pub use ppu::{*};
 pub use timer::{*};
 pub use cartridge::{*};
 pub use debug::{*};
 pub use debug_info::{*};
 pub use utils::{*};
 pub use bess::{*};
+pub use model::{*};
+pub use dmg_palette::{*};
+pub use sgb::{*};
+pub use movie::{*};
+pub use speed::{*};
+pub use video::{*};
+pub use filter::{*};
@@ -0,0 +1,215 @@
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::cpu::{RA, RB, RC, RD, RE, RF, RH, RL, FLAG_Z, FLAG_H, FLAG_C};
+
+/// The Gameboy hardware revision being emulated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Model {
+    /// Early original Gameboy, which shipped with a different boot ROM.
+    Dmg0,
+    /// Original Gameboy.
+    Dmg,
+    /// Gameboy Pocket.
+    Mgb,
+    /// Super Gameboy.
+    Sgb,
+    /// Gameboy Color.
+    Cgb,
+}
+
+impl Model {
+    pub fn new(name: &str) -> Result<Self, String> {
+        match name {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!("Unknown model '{name}'")),
+        }
+    }
+
+    /// The size in bytes of this model's boot ROM.
+    /// The CGB boot ROM is mapped to both 0x0000-0x00ff and 0x0200-0x08ff, with the cartridge
+    /// header showing through in between.
+    pub fn boot_rom_size(&self) -> usize {
+        match self {
+            Model::Cgb => 0x900,
+            _ => 0x100,
+        }
+    }
+}
+
+/// The sum of the title bytes in the cartridge header, which the CGB boot ROM uses to pick a
+/// colorization palette for DMG games. Only games published by Nintendo get one.
+pub fn cgb_title_checksum(header: &[u8]) -> Option<u8> {
+    let is_nintendo = header[0x14b] == 0x01
+        || (header[0x14b] == 0x33 && header[0x144..0x146] == *b"01");
+    if is_nintendo {
+        Some(header[0x134..0x144].iter().fold(0u8, |sum, x| sum.wrapping_add(*x)))
+    } else {
+        None
+    }
+}
+
+/// Register values left behind by the boot ROM, in the same order as Cpu::regs.
+/// Some models derive a few of these from the cartridge header, which is why the header bytes are
+/// passed in.
+pub fn post_boot_regs(model: Model, header: &[u8]) -> [u8; 8] {
+    let header_checksum = header[0x14d];
+    // DMG and MGB boot ROMs leave H and C set unless the header checksum happens to be 0.
+    let hc = if header_checksum == 0 { 0 } else { FLAG_H | FLAG_C };
+
+    let mut regs = [0; 8];
+    let (a, f, b, c, d, e, h, l) = match model {
+        Model::Dmg0 => (0x01, 0x00, 0xff, 0x13, 0x00, 0xc1, 0x84, 0x03),
+        Model::Dmg => (0x01, FLAG_Z | hc, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d),
+        Model::Mgb => (0xff, FLAG_Z | hc, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d),
+        Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xc0, 0x60),
+        Model::Cgb => {
+            if header[0x143] & 0x80 > 0 {
+                (0x11, FLAG_Z, 0x00, 0x00, 0xff, 0x56, 0x00, 0x0d)
+            } else {
+                // In DMG compatibility mode, the boot ROM leaves behind some of the state it used
+                // to pick a colorization palette for the game.
+                let b = cgb_title_checksum(header).unwrap_or(0x00);
+                let (h, l) = if b == 0x43 || b == 0x58 { (0x99, 0x1a) } else { (0x00, 0x7c) };
+                (0x11, FLAG_Z, b, 0x00, 0x00, 0x08, h, l)
+            }
+        },
+    };
+    regs[RA] = a;
+    regs[RF] = f;
+    regs[RB] = b;
+    regs[RC] = c;
+    regs[RD] = d;
+    regs[RE] = e;
+    regs[RH] = h;
+    regs[RL] = l;
+    regs
+}
+
+/// IO register values left behind by the boot ROM. Registers not listed here are left at 0.
+pub fn post_boot_io_ports(model: Model) -> Vec<(usize, u8)> {
+    let mut io_ports = vec!(
+        (IO_P1, 0xcf),
+        (IO_SB, 0x00),
+        (IO_SC, 0x7e),
+        (IO_DIV, 0xab),
+        (IO_TIMA, 0x00),
+        (IO_TMA, 0x00),
+        (IO_TAC, 0xf8),
+        (IO_IF, 0xe1),
+        (IO_NR10, 0x80),
+        (IO_NR11, 0xbf),
+        (IO_NR12, 0xf3),
+        (IO_NR13, 0xff),
+        (IO_NR14, 0xbf),
+        (IO_NR21, 0x3f),
+        (IO_NR22, 0x00),
+        (IO_NR23, 0xff),
+        (IO_NR24, 0xbf),
+        (IO_NR30, 0x7f),
+        (IO_NR31, 0xff),
+        (IO_NR32, 0x9f),
+        (IO_NR33, 0xff),
+        (IO_NR34, 0xbf),
+        (IO_NR41, 0xff),
+        (IO_NR42, 0x00),
+        (IO_NR43, 0x00),
+        (IO_NR44, 0xbf),
+        (IO_NR50, 0x77),
+        (IO_NR51, 0xf3),
+        (IO_NR52, 0xf1),
+        (IO_LCDC, 0x91),
+        (IO_STAT, 0x85),
+        (IO_SCY, 0x00),
+        (IO_SCX, 0x00),
+        (IO_LY, 0x00),
+        (IO_LYC, 0x00),
+        (IO_DMA, 0xff),
+        (IO_BGP, 0xfc),
+        // OBP0 and OBP1 are left uninitialized by the boot ROM, 0xff is as good a value as any.
+        (IO_OBP0, 0xff),
+        (IO_OBP1, 0xff),
+        (IO_WY, 0x00),
+        (IO_WX, 0x00),
+        (IO_IE, 0x00),
+    );
+
+    // The differences between models are small enough that it's clearer to list them as
+    // overrides of the DMG values.
+    let overrides: &[(usize, u8)] = match model {
+        Model::Dmg0 => &[(IO_DIV, 0x18), (IO_STAT, 0x81)],
+        Model::Dmg | Model::Mgb => &[],
+        Model::Sgb => &[(IO_DIV, 0x00), (IO_NR52, 0xf0)],
+        Model::Cgb => &[(IO_SC, 0x7f), (IO_DIV, 0x00), (IO_DMA, 0x00)],
+    };
+    for &(port, value) in overrides {
+        if let Some(entry) = io_ports.iter_mut().find(|(p, _)| *p == port) {
+            entry.1 = value;
+        }
+    }
+
+    io_ports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    fn header(checksum: u8) -> Vec<u8> {
+        let mut header = vec!(0; 0x150);
+        header[0x14d] = checksum;
+        header
+    }
+
+    #[test]
+    fn dmg_regs() {
+        let regs = post_boot_regs(Model::Dmg, &header(0x3b));
+        assert_eq!(regs[RA], 0x01);
+        assert_eq!(regs[RF], FLAG_Z | FLAG_H | FLAG_C);
+        assert_eq!(regs[RB], 0x00);
+        assert_eq!(regs[RC], 0x13);
+        assert_eq!(regs[RD], 0x00);
+        assert_eq!(regs[RE], 0xd8);
+        assert_eq!(regs[RH], 0x01);
+        assert_eq!(regs[RL], 0x4d);
+    }
+
+    #[test]
+    fn dmg_regs_zero_checksum() {
+        let regs = post_boot_regs(Model::Dmg, &header(0x00));
+        assert_eq!(regs[RF], FLAG_Z);
+    }
+
+    #[test]
+    fn cgb_regs_dmg_mode() {
+        let mut header = header(0x00);
+        header[0x14b] = 0x01;
+        header[0x134] = 0x40;
+        header[0x135] = 0x03;
+        let regs = post_boot_regs(Model::Cgb, &header);
+        assert_eq!(regs[RA], 0x11);
+        assert_eq!(regs[RB], 0x43);
+        assert_eq!(regs[RH], 0x99);
+        assert_eq!(regs[RL], 0x1a);
+    }
+
+    #[test]
+    fn cgb_regs_cgb_mode() {
+        let mut header = header(0x00);
+        header[0x143] = 0x80;
+        let regs = post_boot_regs(Model::Cgb, &header);
+        assert_eq!(regs[RA], 0x11);
+        assert_eq!(regs[RD], 0xff);
+        assert_eq!(regs[RE], 0x56);
+        assert_eq!(regs[RL], 0x0d);
+    }
+
+    #[test]
+    fn io_port_overrides() {
+        let io_ports = post_boot_io_ports(Model::Dmg0);
+        assert!(io_ports.contains(&(IO_DIV, 0x18)));
+        assert!(io_ports.contains(&(IO_LCDC, 0x91)));
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs::{self, File};
+use std::io::{Write};
+use std::sync::atomic::{Ordering};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::sha1::{sha1};
+
+/// Identifies a movie file, followed by the format version.
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 32;
+
+/// A recording of the buttons held on every frame since power on.
+///
+/// Movie files (.gbm) are laid out as follows, with all numbers little-endian:
+///
+/// | Offset | Size | Contents                                                            |
+/// |--------|------|---------------------------------------------------------------------|
+/// | 0x00   | 4    | "GBMV"                                                              |
+/// | 0x04   | 4    | Format version (1)                                                  |
+/// | 0x08   | 20   | SHA-1 of the ROM the movie was recorded with                        |
+/// | 0x1c   | 4    | Size of the start state, or 0 if there isn't one                    |
+/// | 0x20   | n    | BESS file the game was started from                                 |
+/// | 0x20+n | ...  | One byte per frame until the end of the file, in the format of Gameboy::controller_data |
+pub struct Movie {
+    pub rom_sha1: [u8; 20],
+    pub start_state: Option<Vec<u8>>,
+    pub inputs: Vec<u8>,
+}
+
+impl Movie {
+    pub fn new(rom: &[u8], start_state: Option<Vec<u8>>) -> Self {
+        Self {
+            rom_sha1: sha1(rom),
+            start_state,
+            inputs: vec!(),
+        }
+    }
+
+    pub fn load(filepath: &str) -> Result<Self, String> {
+        let bytes = fs::read(filepath)
+            .map_err(|e| format!("Failed to open movie file '{filepath}': {e}"))?;
+        Self::parse(&bytes)
+            .map_err(|e| format!("Failed to read movie file '{filepath}': {e}"))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[0x00..0x04] != MAGIC {
+            return Err("not a movie file".to_string());
+        }
+        let version = u32::from_le_bytes([bytes[0x04], bytes[0x05], bytes[0x06], bytes[0x07]]);
+        if version != VERSION {
+            return Err(format!("unsupported version {}", version));
+        }
+        let mut rom_sha1 = [0; 20];
+        rom_sha1.copy_from_slice(&bytes[0x08..0x1c]);
+        let state_size = u32::from_le_bytes([bytes[0x1c], bytes[0x1d], bytes[0x1e], bytes[0x1f]]) as usize;
+        if bytes.len() < HEADER_SIZE + state_size {
+            return Err("start state is cut off".to_string());
+        }
+        let start_state = if state_size > 0 {
+            Some(bytes[HEADER_SIZE..HEADER_SIZE + state_size].to_vec())
+        } else {
+            None
+        };
+        Ok(Self {
+            rom_sha1,
+            start_state,
+            inputs: bytes[HEADER_SIZE + state_size..].to_vec(),
+        })
+    }
+
+    /// Everything in the file that comes before the inputs.
+    fn header_bytes(&self) -> Vec<u8> {
+        let start_state: &[u8] = self.start_state.as_deref().unwrap_or(&[]);
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + start_state.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_sha1);
+        bytes.extend_from_slice(&(start_state.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(start_state);
+        bytes
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header_bytes();
+        bytes.extend_from_slice(&self.inputs);
+        bytes
+    }
+
+    /// Makes sure the movie is played back with the ROM it was recorded with.
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), String> {
+        if sha1(rom) != self.rom_sha1 {
+            return Err("Movie was recorded with a different ROM".to_string());
+        }
+        Ok(())
+    }
+}
+
+enum MovieMode {
+    /// Each frame's input is appended to the file as it happens, so nothing is lost if the
+    /// emulator is closed mid-recording.
+    Recording(File),
+    /// Index of the next frame to play back.
+    Playing(usize),
+}
+
+/// A movie that's being recorded or played back. While one is, the buttons the game sees only
+/// change at the start of each frame, so the same inputs always land on the same frames.
+pub struct MovieRunner {
+    pub movie: Movie,
+    mode: MovieMode,
+    /// Buttons held during the current frame.
+    pub input: u8,
+}
+
+impl MovieRunner {
+    pub fn record(movie: Movie, filepath: &str) -> Result<Self, String> {
+        let mut file = File::create(filepath)
+            .map_err(|e| format!("Failed to create movie file '{filepath}': {e}"))?;
+        file.write_all(&movie.to_bytes())
+            .map_err(|e| format!("Failed to write movie file '{filepath}': {e}"))?;
+        Ok(Self {
+            movie,
+            mode: MovieMode::Recording(file),
+            input: 0xff,
+        })
+    }
+
+    pub fn play(movie: Movie) -> Self {
+        Self {
+            movie,
+            mode: MovieMode::Playing(0),
+            input: 0xff,
+        }
+    }
+
+    /// Moves on to the next frame, taking its input either from the movie or from the live
+    /// controller data. Returns false once there's nothing left to play back.
+    fn next_frame(&mut self, controller_data: u8) -> bool {
+        match &mut self.mode {
+            MovieMode::Recording(file) => {
+                self.input = controller_data;
+                self.movie.inputs.push(controller_data);
+                if let Err(err) = file.write_all(&[controller_data]) {
+                    eprintln!("Failed to write to movie file: {}", err);
+                }
+                true
+            },
+            MovieMode::Playing(frame) => {
+                match self.movie.inputs.get(*frame) {
+                    Some(&input) => {
+                        self.input = input;
+                        *frame += 1;
+                        true
+                    },
+                    None => false,
+                }
+            },
+        }
+    }
+}
+
+/// Called at the start of every frame. Once playback reaches the end of the movie, control goes
+/// back to the live controller data.
+pub fn run_movie_frame(gb: &mut Gameboy) {
+    let controller_data = gb.controller_data.load(Ordering::Relaxed);
+    if let Some(runner) = &mut gb.movie {
+        if !runner.next_frame(controller_data) {
+            println!("Movie finished after {} frames", runner.movie.inputs.len());
+            gb.movie = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use crate::gameboy::utils::{test_dmg};
+
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie::new(b"rom", Some(vec!(1, 2, 3)));
+        movie.inputs = vec!(0xff, 0xfe, 0x7f);
+
+        let parsed = Movie::parse(&movie.to_bytes()).unwrap();
+
+        assert_eq!(parsed.rom_sha1, sha1(b"rom"));
+        assert_eq!(parsed.start_state, Some(vec!(1, 2, 3)));
+        assert_eq!(parsed.inputs, vec!(0xff, 0xfe, 0x7f));
+        assert!(parsed.check_rom(b"rom").is_ok());
+        assert!(parsed.check_rom(b"other rom").is_err());
+    }
+
+    #[test]
+    fn no_start_state() {
+        let movie = Movie::new(b"rom", None);
+        let parsed = Movie::parse(&movie.to_bytes()).unwrap();
+        assert_eq!(parsed.start_state, None);
+        assert!(parsed.inputs.is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Movie::parse(b"GBMV").is_err());
+        let mut bytes = Movie::new(b"rom", Some(vec!(1, 2, 3))).to_bytes();
+        bytes.truncate(HEADER_SIZE + 2);
+        assert!(Movie::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn playback() {
+        let mut gb = test_dmg(&[]);
+        let mut movie = Movie::new(b"rom", None);
+        movie.inputs = vec!(!CONTROLLER_DATA_A, !CONTROLLER_DATA_ST);
+        gb.movie = Some(MovieRunner::play(movie));
+        // Live input is ignored while a movie is playing.
+        gb.controller_data.store(!CONTROLLER_DATA_B, Ordering::Relaxed);
+
+        run_movie_frame(&mut gb);
+        assert_eq!(gb.movie.as_ref().unwrap().input, !CONTROLLER_DATA_A);
+        run_movie_frame(&mut gb);
+        assert_eq!(gb.movie.as_ref().unwrap().input, !CONTROLLER_DATA_ST);
+        run_movie_frame(&mut gb);
+        assert!(gb.movie.is_none());
+    }
+}
@@ -0,0 +1,347 @@
+/// The 8 bytes every PNG file starts with.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+/// IHDR color type for 8 bit RGB pixels.
+const COLOR_TYPE_RGB: u8 = 2;
+
+/// How far back LZ77 looks for matches, which is the furthest deflate allows.
+const WINDOW_SIZE: usize = 0x8000;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried before settling for the best match
+/// found so far.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+/// Base lengths for deflate length codes 257-285, followed by how many extra bits each one has.
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base distances for deflate distance codes 0-29, followed by how many extra bits each one has.
+const DIST_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Encodes 8 bit RGB pixels, stored row by row, as a PNG file.
+pub fn encode_png(width: u32, height: u32, pixels: &[(u8, u8, u8)]) -> Vec<u8> {
+    assert_eq!(pixels.len(), (width * height) as usize, "Expected {} pixels", width * height);
+
+    // Every row starts with the filter type it was encoded with, which is always 0 (none) here.
+    let mut raw = Vec::with_capacity(pixels.len() * 3 + height as usize);
+    for row in pixels.chunks(width as usize) {
+        raw.push(0);
+        for &(r, g, b) in row {
+            raw.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Bit depth, color type, compression method, filter method, interlace method.
+    ihdr.extend_from_slice(&[8, COLOR_TYPE_RGB, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib_compress(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Scales pixels up by an integer factor, repeating each pixel in a factor by factor square.
+pub fn scale_pixels(width: u32, pixels: &[(u8, u8, u8)], factor: u32) -> Vec<(u8, u8, u8)> {
+    let mut scaled = Vec::with_capacity(pixels.len() * (factor * factor) as usize);
+    for row in pixels.chunks(width as usize) {
+        for _ in 0..factor {
+            for &pixel in row {
+                for _ in 0..factor {
+                    scaled.push(pixel);
+                }
+            }
+        }
+    }
+    scaled
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    // The CRC covers the chunk type and data, but not the length.
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 > 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Wraps deflate compressed data in the zlib format that PNG uses.
+fn zlib_compress(bytes: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window and no preset dictionary, with the check bits set so that the
+    // header is a multiple of 31.
+    let mut out = vec!(0x78, 0x01);
+    out.extend_from_slice(&deflate(bytes));
+    out.extend_from_slice(&adler32(bytes).to_be_bytes());
+    out
+}
+
+/// Writes bits starting from the least significant bit of each byte, the way deflate does.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    bit_count: u8,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { out: vec!(), bits: 0, bit_count: 0 }
+    }
+
+    /// Writes the lowest `count` bits of value, least significant bit first.
+    fn write(&mut self, value: u32, count: u8) {
+        self.bits |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Huffman codes are the exception, and get written most significant bit first.
+    fn write_code(&mut self, code: u32, count: u8) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Writes a symbol from the literal/length alphabet using the fixed Huffman codes.
+fn write_lit_len(writer: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, dist: usize) {
+    let code = LENGTH_BASES.iter().rposition(|&base| base as usize <= length).unwrap();
+    write_lit_len(writer, 257 + code as u16);
+    writer.write((length - LENGTH_BASES[code] as usize) as u32, LENGTH_EXTRA_BITS[code]);
+
+    let code = DIST_BASES.iter().rposition(|&base| base as usize <= dist).unwrap();
+    writer.write_code(code as u32, 5);
+    writer.write((dist - DIST_BASES[code] as usize) as u32, DIST_EXTRA_BITS[code]);
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses bytes as a single deflate block with the fixed Huffman codes, finding repeats with
+/// hash chains. Screens are mostly made of repeated tiles and runs of the same color, so this
+/// gets most of the way there without building Huffman tables.
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    // BFINAL, then BTYPE 1 for fixed Huffman codes.
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    // The most recent position for each hash, and for each position the previous one with the
+    // same hash, both offset by 1 so that 0 means none.
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut prev = vec![0usize; bytes.len()];
+    let insert = |head: &mut [usize], prev: &mut [usize], pos: usize| {
+        if pos + MIN_MATCH <= bytes.len() {
+            let h = hash(&bytes[pos..]);
+            prev[pos] = head[h];
+            head[h] = pos + 1;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if pos + MIN_MATCH <= bytes.len() {
+            let max_len = MAX_MATCH.min(bytes.len() - pos);
+            let mut candidate = head[hash(&bytes[pos..])];
+            let mut chain = 0;
+            while candidate > 0 && chain < MAX_CHAIN {
+                let start = candidate - 1;
+                if pos - start > WINDOW_SIZE {
+                    break;
+                }
+                let len = bytes[start..].iter().zip(bytes[pos..pos + max_len].iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - start;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[start];
+                chain += 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            write_match(&mut writer, best_len, best_dist);
+            for i in pos..pos + best_len {
+                insert(&mut head, &mut prev, i);
+            }
+            pos += best_len;
+        } else {
+            write_lit_len(&mut writer, bytes[pos] as u16);
+            insert(&mut head, &mut prev, pos);
+            pos += 1;
+        }
+    }
+
+    // End of block.
+    write_lit_len(&mut writer, 256);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    /// Reads bits in the same order BitWriter writes them.
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, count: u8) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = (self.bytes[self.pos / 8] >> (self.pos % 8)) & 1;
+                value |= (bit as u32) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        fn read_code(&mut self, count: u8) -> u32 {
+            let mut code = 0;
+            for _ in 0..count {
+                code = (code << 1) | self.read(1);
+            }
+            code
+        }
+
+        fn read_lit_len(&mut self) -> u32 {
+            let code = self.read_code(7);
+            if code <= 0b001_0111 {
+                return code + 256;
+            }
+            let code = (code << 1) | self.read(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => code - 0xc0 + 280,
+                _ => ((code << 1) | self.read(1)) - 0x190 + 144,
+            }
+        }
+    }
+
+    /// Decompresses a single fixed Huffman block, which is all deflate ever writes.
+    fn inflate(bytes: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { bytes, pos: 0 };
+        assert_eq!(reader.read(3), 0b011);
+        let mut out = vec!();
+        loop {
+            let symbol = reader.read_lit_len();
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let code = (symbol - 257) as usize;
+                    let len = LENGTH_BASES[code] as usize + reader.read(LENGTH_EXTRA_BITS[code]) as usize;
+                    let code = reader.read_code(5) as usize;
+                    let dist = DIST_BASES[code] as usize + reader.read(DIST_EXTRA_BITS[code]) as usize;
+                    for _ in 0..len {
+                        out.push(out[out.len() - dist]);
+                    }
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let mut bytes = b"abcabcabcabcd".to_vec();
+        bytes.extend_from_slice(&[0x55; 1000]);
+        bytes.extend((0..=255).cycle().take(40000));
+        let compressed = deflate(&bytes);
+        assert!(compressed.len() < bytes.len() / 10, "{}", compressed.len());
+        assert_eq!(inflate(&compressed), bytes);
+    }
+
+    #[test]
+    fn png_layout() {
+        let png = encode_png(2, 1, &[(0xff, 0, 0), (0, 0, 0xff)]);
+        assert_eq!(&png[0..8], &PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        let idat_len = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let zlib = &png[41..41 + idat_len];
+        assert_eq!(inflate(&zlib[2..zlib.len() - 4]), vec!(0, 0xff, 0, 0, 0, 0, 0xff));
+    }
+
+    #[test]
+    fn scale() {
+        let pixels = [(1, 1, 1), (2, 2, 2)];
+        assert_eq!(scale_pixels(2, &pixels, 2), vec!(
+            (1, 1, 1), (1, 1, 1), (2, 2, 2), (2, 2, 2),
+            (1, 1, 1), (1, 1, 1), (2, 2, 2), (2, 2, 2),
+        ));
+    }
+}
@@ -1,27 +1,50 @@
-use std::sync::{Arc, Mutex, Condvar};
+use std::collections::{VecDeque};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
 use std::time::{Duration, Instant};
-use std::num::{Wrapping};
 use crate::gameboy::gameboy::{*};
 use crate::gameboy::debug_info::{DebugInfoPpu};
-use crate::gameboy::utils::{sleep_precise};
+use crate::gameboy::cgb_palette::{CgbPalettes};
+use crate::gameboy::sgb::{Sgb};
+use crate::gameboy::dmg_palette::{DmgPalette};
 
 pub const PALETTE_GREY: [(u8,u8,u8); 4] = [(255,255,255), (127,127,127), (63,63,63), (0,0,0)];
 pub const PALETTE_RED: [(u8,u8,u8); 4] = [(255,0,0), (127,0,0), (63,0,0), (0,0,0)];
 pub const PALETTE_GREEN: [(u8,u8,u8); 4] = [(0,255,0), (0,127,0), (0,63,0), (0,0,0)];
 pub const PALETTE_BLUE: [(u8,u8,u8); 4] = [(0,0,255), (0,0,127), (0,0,63), (0,0,0)];
 
-const OAM_TIME: Duration = Duration::from_nanos(19_000);
-const DRAW_TIME: Duration = Duration::from_nanos(41_000);
-const HBLANK_TIME: Duration = Duration::from_nanos(48_600);
-const LINE_TIME: Duration = Duration::from_nanos(108_718);
-const FRAME_TIME: Duration  = Duration::from_nanos(16_750_000);
+/// Dots (4 MHz PPU clock ticks) per scanline, including HBlank.
+const LINE_DOTS: u16 = 456;
+/// Dots spent scanning OAM at the start of each visible line. Each OAM entry takes 2 dots.
+const OAM_SCAN_DOTS: u16 = 80;
+/// Scanlines per frame, including the 10 lines of VBlank.
+const LINES_PER_FRAME: u8 = 154;
+/// Dots into line 153 after which LY already reads 0.
+const LY_153_DOTS: u16 = 4;
+/// Dots per frame, which is how often frames go by even while the LCD is off.
+const FRAME_DOTS: u32 = LINE_DOTS as u32 * LINES_PER_FRAME as u32;
+pub const FRAME_TIME: Duration  = Duration::from_nanos(16_750_000);
+
+/// Dots the fetcher spends reading a tile number and the two bytes of tile data for one row.
+const FETCH_DOTS: u8 = 6;
+/// Dots it takes to fetch an object's tile data, during which no pixels are shifted out.
+const OBJ_FETCH_DOTS: u8 = 6;
+/// At most 10 objects can be drawn per scanline.
+const MAX_OBJS_PER_LINE: usize = 10;
 
 const OBJ_PRIORITY: u8 = 0b1000_0000;
 const OBJ_Y_FLIP: u8   = 0b0100_0000;
 const OBJ_X_FLIP: u8   = 0b0010_0000;
 const OBJ_PALETTE: u8  = 0b0001_0000;
+const OBJ_CGB_BANK: u8    = 0b0000_1000;
+const OBJ_CGB_PALETTE: u8 = 0b0000_0111;
+
+// CGB tile map attributes, stored in VRAM bank 1 at the same offset as the tile number.
+const BG_ATTR_PRIORITY: u8 = 0b1000_0000;
+const BG_ATTR_Y_FLIP: u8   = 0b0100_0000;
+const BG_ATTR_X_FLIP: u8   = 0b0010_0000;
+const BG_ATTR_BANK: u8     = 0b0000_1000;
+const BG_ATTR_PALETTE: u8  = 0b0000_0111;
 
 #[derive(Debug, Copy, Clone)]
 struct ObjAttr {
@@ -42,313 +65,787 @@ impl ObjAttr {
     }
 }
 
+/// A background or window pixel waiting in the BG FIFO.
+#[derive(Debug, Copy, Clone)]
+struct BgPixel {
+    color_ix: u8,
+    /// CGB tile map attributes of the tile this pixel came from.
+    attr: u8,
+}
+
+/// An object pixel waiting in the object FIFO.
+#[derive(Debug, Copy, Clone)]
+struct ObjPixel {
+    color_ix: u8,
+    flags: u8,
+    oam_ix: u8,
+}
+
+/// Fetches one row of 8 background or window pixels at a time to feed the BG FIFO.
+#[derive(Debug, Copy, Clone)]
+struct Fetcher {
+    /// Dots spent on the current fetch. Once this reaches FETCH_DOTS, the row is pushed to the BG
+    /// FIFO as soon as the FIFO is empty.
+    dots: u8,
+    /// Tile column to fetch next, relative to the left edge of the screen or window.
+    tile_x: u8,
+    /// Whether tiles are being fetched from the window rather than the background.
+    window: bool,
+    /// Whether the current fetch will be thrown away. The first fetch of every line is.
+    discard: bool,
+    tile_number: u8,
+    attr: u8,
+    data_low: u8,
+    data_high: u8,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Self {
+        Self {
+            dots: 0,
+            tile_x: 0,
+            window,
+            discard: !window,
+            tile_number: 0,
+            attr: 0,
+            data_low: 0,
+            data_high: 0,
+        }
+    }
+}
+
 pub struct Ppu {
-    pub vram: Arc<Mutex<[u8; 0x2000]>>, 
-    pub oam: Arc<Mutex<[u8; 0xa0]>>, 
-    pub io_ports: Arc<IoPorts>,
-    pub screen: Arc<Mutex<[[(u8,u8,u8); 160]; 144]>>,
-    pub ime: Arc<AtomicBool>,
-    pub interrupt_received: Arc<(Mutex<bool>, Condvar)>,
-    pub palette: [(u8,u8,u8); 4],
-    pub step_mode: Arc<AtomicBool>,
+    /// Colors used outside of CGB mode.
+    pub palette: DmgPalette,
+    /// Set by the UI to switch palettes, which happens at the start of the next frame.
+    pub palette_request: Arc<Mutex<Option<DmgPalette>>>,
+    /// Whether to adjust CGB colors to look like they would on a real CGB LCD.
+    pub color_correction: bool,
+    pub debug_info: DebugInfoPpu,
+    cgb_mode: bool,
+
+    lcd_on: bool,
+    /// The current scanline, which is mirrored to LY.
+    ly: u8,
+    /// Dot within the current scanline.
+    dot: u16,
+    mode: u8,
+    /// All STAT interrupt sources ORed together. An interrupt is only requested when this goes from
+    /// low to high.
+    stat_line: bool,
+    frame_start: Instant,
+    /// Set whenever a frame starts, which is when VBlank starts, or every FRAME_DOTS dots while
+    /// the LCD is off. Whoever acts on it clears it.
+    pub frame_started: bool,
+    /// Dots run since the LCD was turned off or the last frame started, whichever came last.
+    lcd_off_dots: u32,
+
+    /// Objects found on this line during the OAM scan, along with their OAM index. During mode 3
+    /// these are sorted by x coordinate, which is the order they get fetched in.
+    line_objs: Vec<(u8, ObjAttr)>,
+    /// Index into line_objs of the next object to fetch.
+    next_obj: usize,
+    /// Dots left until the object currently being fetched is loaded into the object FIFO.
+    obj_fetch_dots: u8,
+
+    fetcher: Fetcher,
+    bg_fifo: VecDeque<BgPixel>,
+    obj_fifo: VecDeque<ObjPixel>,
+    /// X coordinate of the next pixel to be shifted out to the LCD.
+    lx: u8,
+    /// Pixels still to be dropped from the BG FIFO instead of being shifted out. This is how fine
+    /// scrolling with SCX works, and is also used when the window starts left of the screen.
+    discard_pixels: u8,
+    /// Whether LY has matched WY at some point this frame. The window isn't drawn until it has.
+    wy_triggered: bool,
+    /// Row of the window to draw next. This only advances on lines where the window was drawn, so
+    /// if the window is disabled and then re-enabled later on, it continues from where it left off.
+    window_line: u8,
+
+    /// Colors of the current line, which are copied to the back buffer when the line is done.
+    line: [(u8,u8,u8); 160],
+    /// Shades of the current line before palette lookup, used by the SGB to colorize the screen.
+    line_shades: [u8; 160],
+    /// The frame being drawn, which is copied to the screen when VBlank starts.
+    back_buffer: Box<[[(u8,u8,u8); 160]; 144]>,
 }
 
 impl Ppu {
-    pub fn new(gb: &Gameboy, palette: [(u8, u8, u8); 4]) -> Self {
+    pub fn new(cgb_mode: bool) -> Self {
         Self {
-            vram: gb.vram.clone(),
-            oam: gb.oam.clone(),
-            io_ports: gb.io_ports.clone(),
-            screen: gb.screen.clone(),
-            ime: gb.ime.clone(),
-            interrupt_received: Arc::clone(&gb.interrupt_received),
-            palette: palette,
-            step_mode: gb.debug.step_mode.clone(),
+            palette: DmgPalette::uniform(PALETTE_GREY),
+            palette_request: Arc::new(Mutex::new(None)),
+            color_correction: false,
+            debug_info: DebugInfoPpu::new(),
+            cgb_mode,
+
+            lcd_on: false,
+            ly: 0,
+            dot: 0,
+            mode: STAT_MODE_HBLANK,
+            stat_line: false,
+            frame_start: Instant::now(),
+            frame_started: false,
+            lcd_off_dots: 0,
+
+            line_objs: Vec::with_capacity(MAX_OBJS_PER_LINE),
+            next_obj: 0,
+            obj_fetch_dots: 0,
+
+            fetcher: Fetcher::new(false),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(16),
+            lx: 0,
+            discard_pixels: 0,
+            wy_triggered: false,
+            window_line: 0,
+
+            line: [(0,0,0); 160],
+            line_shades: [0; 160],
+            back_buffer: Box::new([[(0,0,0); 160]; 144]),
         }
     }
 }
 
-pub fn run_ppu(ppu: &mut Ppu, debug_info: DebugInfoPpu) {
-    let (mutex, cvar) = &*ppu.interrupt_received;
-    let io_ports = &ppu.io_ports;
-
-    let mut obj_attrs: Vec<(usize, ObjAttr)> = Vec::with_capacity(40);
-    // This is to get around having to init the vec with dummy data that we just overwrite in the
-    // first run of the PPU.
-    unsafe { obj_attrs.set_len(40); }
-    
-    // Give the CPU a bit to start up
-    thread::sleep(Duration::from_nanos(1000));
-
-    loop {
-        let frame_start = Instant::now();
-
-        let lcd_is_off = io_ports.read(IO_LCDC) & LCDC_ON == 0; // LCD should only be turned off in VBlank
-        let wy = io_ports.read(IO_WY) as usize; // WY is only checked once per frame
-
-        let mut curr_window_line = 0;
-
-        io_ports.write(IO_LY, 0);
-
-        wait_if_debug_break(ppu);
-
-        for y in 0..144 {
-            let oam_start = Instant::now();
-            io_ports.and(IO_STAT, !STAT_MODE);
-            io_ports.or(IO_STAT, STAT_MODE_OAM);
-            let int_on_m10 =
-                io_ports.read(IO_IE) & INT_LCDC > 0 && io_ports.read(IO_STAT) & STAT_INT_M10 > 0;
-            if ppu.ime.load(Ordering::Relaxed) && int_on_m10 {
-                io_ports.or(IO_IF, INT_LCDC);
-                let mut interrupted = mutex.lock().unwrap();
-                *interrupted = true;
-                cvar.notify_one();
+/// Everything outside of the PPU itself that the PPU touches while running.
+struct PpuBus<'a> {
+    vram: &'a [u8; 0x4000],
+    oam: &'a [u8; 0xa0],
+    io_ports: &'a IoPorts,
+    cgb_palettes: &'a CgbPalettes,
+    screen: &'a Mutex<[[(u8,u8,u8); 160]; 144]>,
+    sgb: Option<&'a Mutex<Sgb>>,
+    hblank_started: &'a AtomicBool,
+    frame_ready: &'a AtomicBool,
+}
+
+/// Runs the PPU for the given number of dots.
+pub fn run_ppu(gb: &mut Gameboy, dots: u64) {
+    let vram = gb.vram.lock().unwrap();
+    let oam = gb.oam.lock().unwrap();
+    let cgb_palettes = gb.cgb_palettes.lock().unwrap();
+    let bus = PpuBus {
+        vram: &vram,
+        oam: &oam,
+        io_ports: &gb.io_ports,
+        cgb_palettes: &cgb_palettes,
+        screen: &gb.screen,
+        sgb: gb.sgb.as_deref(),
+        hblank_started: &gb.hblank_started,
+        frame_ready: &gb.frame_ready,
+    };
+
+    for _ in 0..dots {
+        step_dot(&mut gb.ppu, &bus);
+    }
+}
+
+fn step_dot(ppu: &mut Ppu, bus: &PpuBus) {
+    if bus.io_ports.read(IO_LCDC) & LCDC_ON == 0 {
+        if ppu.lcd_on {
+            turn_lcd_off(ppu, bus);
+        }
+        ppu.lcd_off_dots += 1;
+        if ppu.lcd_off_dots == FRAME_DOTS {
+            ppu.lcd_off_dots = 0;
+            ppu.frame_started = true;
+            present_frame(ppu, bus);
+        }
+        return;
+    }
+    if !ppu.lcd_on {
+        ppu.lcd_on = true;
+        ppu.dot = 0;
+        ppu.frame_start = Instant::now();
+        set_ly(ppu, bus, 0);
+        start_oam_scan(ppu, bus);
+    }
+
+    match ppu.mode {
+        STAT_MODE_OAM => {
+            if ppu.dot & 1 == 0 {
+                scan_obj(ppu, bus, (ppu.dot / 2) as usize);
             }
-            let oam = ppu.oam.lock().unwrap();
-            for (i, j) in (0..160).step_by(4).enumerate() {
-                obj_attrs[i] = (j, ObjAttr::new(&oam[j..j+4]));
+            if ppu.dot + 1 == OAM_SCAN_DOTS {
+                start_transfer(ppu, bus);
             }
-            // Objects with smaller x coords have priority. If x coords are equal, the object that
-            // comes earlier in OAM has priority.
-            obj_attrs.sort_by(|(a_i, a_attr), (b_i, b_attr)| {
-                if a_attr.x != b_attr.x { 
-                    a_attr.x.cmp(&b_attr.x)
-                } else {
-                    a_i.cmp(b_i)
-                }
-            });
-            let lcdc = io_ports.read(IO_LCDC);
-            // Up to 10 objects can be drawn per scanline.
-            let mut obj_attrs_line: Vec<ObjAttr> = Vec::with_capacity(10);
-            {
-                for (_, obj) in obj_attrs.iter() {
-                    let obj_y = obj.y as usize;
-
-                    let y_in_range =
-                        if lcdc & LCDC_OBJ_SIZE > 0 {
-                            y >= (Wrapping(obj_y) - Wrapping(16)).0 && y < obj_y
-                        } else {
-                            y >= (Wrapping(obj_y) - Wrapping(16)).0 && y < (Wrapping(obj_y) - Wrapping(8)).0
-                        };
-
-                    if y_in_range {
-                        obj_attrs_line.push(*obj);
-                        if obj_attrs_line.len() == 10 { break; }
-                    }
-                }
+        },
+        STAT_MODE_TRANSFER => {
+            step_transfer(ppu, bus);
+            if ppu.lx == 160 {
+                start_hblank(ppu, bus);
             }
-            // Higher-priority objects should come later so that they will be drawn on top of
-            // lower-priority objects.
-            obj_attrs_line.reverse();
-            wait_if_debug_break(ppu);
-            sleep_precise(OAM_TIME.checked_sub(oam_start.elapsed()).unwrap_or(Duration::ZERO));
-            debug_info.oam_time_nanos.store(oam_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
-            wait_if_debug_break(ppu);
-            drop(oam);
-
-            let draw_start = Instant::now();
-            io_ports.and(IO_STAT, !STAT_MODE);
-            io_ports.or(IO_STAT, STAT_MODE_TRANSFER);
-            let scx = io_ports.read(IO_SCX);
-            let scy = io_ports.read(IO_SCY);
-            let wx = io_ports.read(IO_WX) as usize;
-            let bgp = io_ports.read(IO_BGP);
-            let obp0 = io_ports.read(IO_OBP0);
-            let obp1 = io_ports.read(IO_OBP1);
-            let vram = &ppu.vram.lock().unwrap();
-            let bg_tile_data = 
-                if lcdc & LCDC_TILE_DATA > 0 { 
-                    &vram[0x0000..0x1000] 
-                } else {
-                    &vram[0x0800..0x1800]
-                };
-            let obj_tile_data = &vram[0x0000..0x1000];
-            let bg_tile_map = 
-                if lcdc & LCDC_BG_TILE_MAP > 0 {
-                    &vram[0x1c00..0x2000]
-                } else {
-                    &vram[0x1800..0x1c00]
-                };
-            let win_tile_map =
-                if lcdc & LCDC_WIN_TILE_MAP > 0 {
-                    &vram[0x1c00..0x2000]
-                } else {
-                    &vram[0x1800..0x1c00]
-                };
-
-            let mut screen = ppu.screen.lock().unwrap();
-            for x in 0..160 {
-                wait_if_debug_break(ppu);
-                screen[y][x] = ppu.palette[0];
-
-                if lcd_is_off {
-                    continue;
-                }
+        },
+        _ => {},
+    }
 
-                if lcdc & LCDC_BG_DISP > 0 {  
-                    let scrolled_x = (Wrapping(x as u8) + Wrapping(scx)).0;
-                    let scrolled_y = (Wrapping(y as u8) + Wrapping(scy)).0;
-                    let current_tile_ix = (scrolled_y as usize / 8)*32 + (scrolled_x as usize / 8);
-                    let tile_data_ix = 
-                        if lcdc & LCDC_TILE_DATA > 0 {
-                            bg_tile_map[current_tile_ix] as usize
-                        } else {
-                            (Wrapping(bg_tile_map[current_tile_ix]) + Wrapping(128)).0 as usize
-                        };
-                    let row_ix = (scrolled_y % 8) as usize;
-                    let col_ix = (scrolled_x % 8) as usize;
-                    let row_start = (tile_data_ix * 16) + (row_ix * 2);
-                    let row = &bg_tile_data[row_start..row_start+2];
-                    let col_mask = 1 << (7 - col_ix);
-                    let high_bit = (row[1] & col_mask) >> (7 - col_ix);
-                    let low_bit = (row[0] & col_mask) >> (7 - col_ix);
-                    let palette_ix = 2*high_bit + low_bit;
-                    let bgp_mask = 0b11 << (palette_ix * 2);
-                    let bgp_palette_ix = (bgp & bgp_mask) >> (palette_ix * 2);
-                    screen[y][x] = ppu.palette[bgp_palette_ix as usize];
-                }
+    ppu.dot += 1;
+    if ppu.ly == LINES_PER_FRAME - 1 && ppu.dot == LY_153_DOTS {
+        // LY only reads 153 very briefly, and then reads 0 for the rest of the line.
+        bus.io_ports.write(IO_LY, 0);
+    }
+    if ppu.dot == LINE_DOTS {
+        ppu.dot = 0;
+        let ly = (ppu.ly + 1) % LINES_PER_FRAME;
+        set_ly(ppu, bus, ly);
+        if ly == 0 {
+            ppu.wy_triggered = false;
+            ppu.window_line = 0;
+        }
+        if ly < 144 {
+            start_oam_scan(ppu, bus);
+        } else if ly == 144 {
+            start_vblank(ppu, bus);
+        }
+    }
+
+    update_stat(ppu, bus);
+}
+
+fn set_mode(ppu: &mut Ppu, bus: &PpuBus, mode: u8) {
+    ppu.mode = mode;
+    bus.io_ports.and(IO_STAT, !STAT_MODE);
+    bus.io_ports.or(IO_STAT, mode);
+}
+
+fn set_ly(ppu: &mut Ppu, bus: &PpuBus, ly: u8) {
+    ppu.ly = ly;
+    bus.io_ports.write(IO_LY, ly);
+}
+
+/// Compares LY to LYC and updates the STAT interrupt line.
+/// Since the interrupt is only requested on a rising edge, one source becoming active while
+/// another is already active doesn't request a second interrupt. For example, with both the
+/// HBlank and VBlank sources enabled, there's no STAT interrupt at the start of VBlank because the
+/// line is still high from the HBlank on line 143.
+fn update_stat(ppu: &mut Ppu, bus: &PpuBus) {
+    let io_ports = bus.io_ports;
+    let lyc_match = io_ports.read(IO_LY) == io_ports.read(IO_LYC);
+    if lyc_match {
+        io_ports.or(IO_STAT, STAT_LYC_SET);
+    } else {
+        io_ports.and(IO_STAT, !STAT_LYC_SET);
+    }
+
+    let stat = io_ports.read(IO_STAT);
+    let stat_line = (stat & STAT_INT_LYC > 0 && lyc_match)
+        || (stat & STAT_INT_M00 > 0 && ppu.mode == STAT_MODE_HBLANK)
+        || (stat & STAT_INT_M01 > 0 && ppu.mode == STAT_MODE_VBLANK)
+        || (stat & STAT_INT_M10 > 0 && ppu.mode == STAT_MODE_OAM);
+    if stat_line && !ppu.stat_line {
+        io_ports.or(IO_IF, INT_LCDC);
+    }
+    ppu.stat_line = stat_line;
+}
+
+fn turn_lcd_off(ppu: &mut Ppu, bus: &PpuBus) {
+    ppu.lcd_on = false;
+    ppu.dot = 0;
+    ppu.stat_line = false;
+    ppu.lcd_off_dots = 0;
+    set_ly(ppu, bus, 0);
+    set_mode(ppu, bus, STAT_MODE_HBLANK);
+    fill_screen(&mut *ppu.back_buffer, ppu.palette.bg[0]);
+}
+
+/// Blanks the LCD while the system is stopped. The PPU itself is frozen along with everything
+/// else, and carries on from where it was once the system wakes up.
+pub fn blank_lcd(gb: &Gameboy) {
+    fill_screen(&mut *gb.screen.lock().unwrap(), gb.ppu.palette.bg[0]);
+    gb.frame_ready.store(true, Ordering::Relaxed);
+}
+
+/// Hands the finished frame over to the screen. The back buffer keeps its contents, since the SGB
+/// can freeze the screen and have the next frame keep showing parts of this one.
+fn present_frame(ppu: &Ppu, bus: &PpuBus) {
+    *bus.screen.lock().unwrap() = *ppu.back_buffer;
+    bus.frame_ready.store(true, Ordering::Relaxed);
+}
+
+fn fill_screen(screen: &mut [[(u8,u8,u8); 160]], color: (u8,u8,u8)) {
+    for line in screen.iter_mut() {
+        line.fill(color);
+    }
+}
+
+fn start_oam_scan(ppu: &mut Ppu, bus: &PpuBus) {
+    set_mode(ppu, bus, STAT_MODE_OAM);
+    ppu.line_objs.clear();
+    if ppu.ly == bus.io_ports.read(IO_WY) {
+        ppu.wy_triggered = true;
+    }
+}
+
+/// Checks whether the object in the given OAM slot is on the current line.
+fn scan_obj(ppu: &mut Ppu, bus: &PpuBus, oam_ix: usize) {
+    if ppu.line_objs.len() == MAX_OBJS_PER_LINE {
+        return;
+    }
+    let obj = ObjAttr::new(&bus.oam[oam_ix*4..oam_ix*4+4]);
+    let height = if bus.io_ports.read(IO_LCDC) & LCDC_OBJ_SIZE > 0 { 16 } else { 8 };
+    // Object y coordinates are offset by 16 so that objects can be partly above the screen.
+    let y = ppu.ly as u16 + 16;
+    if y >= obj.y as u16 && y < obj.y as u16 + height {
+        ppu.line_objs.push((oam_ix as u8, obj));
+    }
+}
+
+fn start_transfer(ppu: &mut Ppu, bus: &PpuBus) {
+    set_mode(ppu, bus, STAT_MODE_TRANSFER);
+    // Objects get fetched as the screen is drawn from left to right. The sort is stable, so
+    // objects with equal x coordinates stay in OAM order.
+    ppu.line_objs.sort_by_key(|(_, obj)| obj.x);
+    ppu.next_obj = 0;
+    ppu.obj_fetch_dots = 0;
+    ppu.fetcher = Fetcher::new(false);
+    ppu.bg_fifo.clear();
+    ppu.obj_fifo.clear();
+    ppu.lx = 0;
+    ppu.discard_pixels = bus.io_ports.read(IO_SCX) % 8;
+}
+
+/// Runs a single dot of mode 3. Mode 3 lasts until all 160 pixels of the line have been shifted
+/// out, which takes longer with fine scrolling, the window, and objects on the line.
+fn step_transfer(ppu: &mut Ppu, bus: &PpuBus) {
+    let lcdc = bus.io_ports.read(IO_LCDC);
+
+    if ppu.obj_fetch_dots > 0 {
+        ppu.obj_fetch_dots -= 1;
+        if ppu.obj_fetch_dots == 0 {
+            fetch_obj(ppu, bus, lcdc);
+        }
+        return;
+    }
+
+    if ppu.discard_pixels == 0 && !ppu.fetcher.window && lcdc & LCDC_WIN_DISP > 0 && ppu.wy_triggered {
+        let wx = bus.io_ports.read(IO_WX) as u16;
+        if ppu.lx as u16 + 7 == wx || (wx < 7 && ppu.lx == 0) {
+            // Switching to the window throws away whatever background pixels were left and
+            // restarts the fetcher.
+            ppu.bg_fifo.clear();
+            ppu.fetcher = Fetcher::new(true);
+            ppu.discard_pixels = 7u8.saturating_sub(wx as u8);
+        }
+    }
 
-                if lcdc & LCDC_WIN_DISP > 0 {
-                    if x + 7 >= wx && x + 7 <= 166 && y >= wy && y <= 143 {
-                        let window_x = x - (wx - 7);
-                        let current_tile_ix = (curr_window_line / 8)*32 + (window_x as usize / 8);
-                        let tile_data_ix = 
-                            if lcdc & LCDC_TILE_DATA > 0 {
-                                win_tile_map[current_tile_ix] as usize
-                            } else {
-                                (Wrapping(win_tile_map[current_tile_ix]) + Wrapping(128)).0 as usize
-                            };
-                        let row_ix = curr_window_line % 8;
-                        let col_ix = window_x % 8;
-                        let row_start = (tile_data_ix * 16) + (row_ix * 2);
-                        let row = &bg_tile_data[row_start..row_start+2];
-                        let col_mask = 1 << (7 - col_ix);
-                        let high_bit = (row[1] & col_mask) >> (7 - col_ix);
-                        let low_bit = (row[0] & col_mask) >> (7 - col_ix);
-                        let palette_ix = 2*high_bit + low_bit;
-                        let bgp_mask = 0b11 << (palette_ix * 2);
-                        let bgp_palette_ix = (bgp & bgp_mask) >> (palette_ix * 2);
-                        screen[y][x] = ppu.palette[bgp_palette_ix as usize];
-
-                        // If the window gets disabled during HBlank and then re-enabled later on,
-                        // we want to continue drawing from where we left off
-                        if x == 159 {
-                            curr_window_line += 1;
-                        }
-                    } 
+    let mut obj_pending = false;
+    if ppu.discard_pixels == 0 {
+        while let Some((_, obj)) = ppu.line_objs.get(ppu.next_obj) {
+            if obj.x > ppu.lx + 8 {
+                break;
+            }
+            if lcdc & LCDC_OBJ_DISP > 0 {
+                obj_pending = true;
+                break;
+            }
+            // Objects that were passed while objects were disabled never get fetched.
+            ppu.next_obj += 1;
+        }
+    }
+
+    if obj_pending && !ppu.bg_fifo.is_empty() && ppu.fetcher.dots >= FETCH_DOTS - 2 {
+        // The BG fetcher has to get as far as the last byte of the tile it's working on before
+        // the object can be fetched, and then it's paused until the object fetch is done.
+        ppu.obj_fetch_dots = OBJ_FETCH_DOTS - 1;
+        return;
+    }
+
+    step_fetcher(ppu, bus, lcdc);
+
+    if obj_pending {
+        return;
+    }
+    if let Some(bg) = ppu.bg_fifo.pop_front() {
+        if ppu.discard_pixels > 0 {
+            ppu.discard_pixels -= 1;
+        } else {
+            let obj = ppu.obj_fifo.pop_front();
+            output_pixel(ppu, bus, lcdc, bg, obj);
+            ppu.lx += 1;
+        }
+    }
+}
+
+fn step_fetcher(ppu: &mut Ppu, bus: &PpuBus, lcdc: u8) {
+    let io_ports = bus.io_ports;
+    let fetcher = &mut ppu.fetcher;
+
+    if fetcher.dots == FETCH_DOTS {
+        if ppu.bg_fifo.is_empty() {
+            for i in 0..8 {
+                let bit = if fetcher.attr & BG_ATTR_X_FLIP > 0 { i } else { 7 - i };
+                let color_ix = (((fetcher.data_high >> bit) & 1) << 1) | ((fetcher.data_low >> bit) & 1);
+                ppu.bg_fifo.push_back(BgPixel { color_ix, attr: fetcher.attr });
+            }
+            fetcher.tile_x = fetcher.tile_x.wrapping_add(1);
+            fetcher.dots = 0;
+        }
+        return;
+    }
+
+    fetcher.dots += 1;
+    // Each step of the fetch takes 2 dots.
+    match fetcher.dots {
+        2 => {
+            let (map_start, row, col) = if fetcher.window {
+                let map_start = if lcdc & LCDC_WIN_TILE_MAP > 0 { 0x1c00 } else { 0x1800 };
+                (map_start, ppu.window_line / 8, fetcher.tile_x)
+            } else {
+                let map_start = if lcdc & LCDC_BG_TILE_MAP > 0 { 0x1c00 } else { 0x1800 };
+                let scrolled_y = ppu.ly.wrapping_add(io_ports.read(IO_SCY));
+                let scrolled_col = (io_ports.read(IO_SCX) / 8).wrapping_add(fetcher.tile_x);
+                (map_start, scrolled_y / 8, scrolled_col)
+            };
+            let map_ix = map_start + (row as usize % 32) * 32 + (col as usize % 32);
+            fetcher.tile_number = bus.vram[map_ix];
+            // In CGB mode, tile attributes are stored in VRAM bank 1.
+            fetcher.attr = if ppu.cgb_mode { bus.vram[0x2000 + map_ix] } else { 0 };
+        },
+        4 | 6 => {
+            let row = if fetcher.window {
+                ppu.window_line % 8
+            } else {
+                ppu.ly.wrapping_add(io_ports.read(IO_SCY)) % 8
+            };
+            let row = if fetcher.attr & BG_ATTR_Y_FLIP > 0 { 7 - row } else { row };
+            let tile_start = if lcdc & LCDC_TILE_DATA > 0 {
+                fetcher.tile_number as usize * 16
+            } else {
+                // Tile numbers are signed, relative to 0x9000.
+                0x0800 + (fetcher.tile_number ^ 0x80) as usize * 16
+            };
+            let bank_start = if fetcher.attr & BG_ATTR_BANK > 0 { 0x2000 } else { 0x0000 };
+            let addr = bank_start + tile_start + row as usize * 2;
+            if fetcher.dots == 4 {
+                fetcher.data_low = bus.vram[addr];
+            } else {
+                fetcher.data_high = bus.vram[addr + 1];
+                if fetcher.discard {
+                    fetcher.discard = false;
+                    fetcher.dots = 0;
                 }
+            }
+        },
+        _ => {},
+    }
+}
 
-                if lcdc & LCDC_OBJ_DISP > 0 {
-                    for obj in obj_attrs_line.iter() {
-                        let obj_x = obj.x as usize;
-                        let obj_y = obj.y as usize;
-                        let x_in_range = x >= obj_x - 8 && x < obj_x;
-                        if !x_in_range {
-                            continue;
-                        }
-
-                        let row_ix = y - (obj_y - 16);
-                        let col_ix = x - (obj_x - 8);
-                        let row_ix = 
-                            if obj.flags & OBJ_Y_FLIP > 0 {
-                                if lcdc & LCDC_OBJ_SIZE > 0 { 
-                                    15 - row_ix
-                                } else {
-                                    7 - row_ix
-                                }
-                            } else {
-                                row_ix
-                            };
-                        let col_ix =
-                            if obj.flags & OBJ_X_FLIP > 0 {
-                                7 - col_ix
-                            } else {
-                                col_ix
-                            };
-                        let tile_number = 
-                            if lcdc & LCDC_OBJ_SIZE > 0 {
-                                (obj.tile_number & 0b1111_1110) as usize
-                            } else {
-                                obj.tile_number as usize
-                            };
-                        let row_start = (tile_number * 16) + (row_ix * 2);
-                        let row = &obj_tile_data[row_start..row_start+2];
-                        let col_mask = 1 << (7 - col_ix);
-                        let high_bit = (row[1] & col_mask) >> (7 - col_ix);
-                        let low_bit = (row[0] & col_mask) >> (7 - col_ix);
-                        let palette_ix = 2*high_bit + low_bit;
-                        if palette_ix == 0 {
-                            continue;
-                        }
-                        let obp_mask = 0b11 << (palette_ix * 2);
-                        let obp_reg = if obj.flags & OBJ_PALETTE > 0 { obp1 } else { obp0 };
-                        let obp_palette_ix = (obp_reg & obp_mask) >> (palette_ix * 2);
-                        let priority = obj.flags & OBJ_PRIORITY > 0;
-                        if priority && screen[y][x] != ppu.palette[0] {
-                            continue;
-                        }
-                        screen[y][x] = ppu.palette[obp_palette_ix as usize];
-                    }
+/// Loads the next object on the line into the object FIFO.
+fn fetch_obj(ppu: &mut Ppu, bus: &PpuBus, lcdc: u8) {
+    let (oam_ix, obj) = ppu.line_objs[ppu.next_obj];
+    ppu.next_obj += 1;
+
+    let height = if lcdc & LCDC_OBJ_SIZE > 0 { 16 } else { 8 };
+    let row = (ppu.ly as usize + 16 - obj.y as usize) % height;
+    let row = if obj.flags & OBJ_Y_FLIP > 0 { height - 1 - row } else { row };
+    let tile_number = if height == 16 { obj.tile_number & 0b1111_1110 } else { obj.tile_number };
+    let bank_start = if ppu.cgb_mode && obj.flags & OBJ_CGB_BANK > 0 { 0x2000 } else { 0x0000 };
+    let addr = bank_start + tile_number as usize * 16 + row * 2;
+    let data_low = bus.vram[addr];
+    let data_high = bus.vram[addr + 1];
+
+    // Objects that are partly off the left edge of the screen only have their visible pixels
+    // loaded.
+    let skip = (ppu.lx + 8).saturating_sub(obj.x);
+    for i in skip..8 {
+        let bit = if obj.flags & OBJ_X_FLIP > 0 { i } else { 7 - i };
+        let color_ix = (((data_high >> bit) & 1) << 1) | ((data_low >> bit) & 1);
+        let pixel = ObjPixel { color_ix, flags: obj.flags, oam_ix };
+        match ppu.obj_fifo.get_mut((i - skip) as usize) {
+            Some(existing) => {
+                // Objects that were fetched earlier have a smaller x coordinate or come earlier in
+                // OAM, so they have priority. In CGB mode, only the OAM position matters.
+                let replace = existing.color_ix == 0
+                    || (ppu.cgb_mode && color_ix != 0 && oam_ix < existing.oam_ix);
+                if replace {
+                    *existing = pixel;
                 }
+            },
+            None => ppu.obj_fifo.push_back(pixel),
+        }
+    }
+}
+
+/// Mixes a BG pixel with an object pixel and writes the result to the current line.
+/// Palettes are looked up as each pixel is shifted out, so palette writes in the middle of a line
+/// take effect from the next pixel on. Object priority is decided by the BG color index that comes
+/// with each pixel rather than by its final color, since BGP can map any index to any shade.
+fn output_pixel(ppu: &mut Ppu, bus: &PpuBus, lcdc: u8, bg: BgPixel, obj: Option<ObjPixel>) {
+    let io_ports = bus.io_ports;
+    let x = ppu.lx as usize;
+
+    // Outside of CGB mode, clearing LCDC bit 0 blanks the background and window. In CGB mode it
+    // instead makes objects always draw on top of the background.
+    let bg_enabled = lcdc & LCDC_BG_DISP > 0 || ppu.cgb_mode;
+    let bg_color_ix = if bg_enabled { bg.color_ix } else { 0 };
+
+    let obj = obj.filter(|obj| {
+        let priority = obj.flags & OBJ_PRIORITY > 0;
+        let bg_on_top = if ppu.cgb_mode {
+            lcdc & LCDC_BG_DISP > 0 && bg_color_ix != 0 && (priority || bg.attr & BG_ATTR_PRIORITY > 0)
+        } else {
+            priority && bg_color_ix != 0
+        };
+        obj.color_ix != 0 && lcdc & LCDC_OBJ_DISP > 0 && !bg_on_top
+    });
+
+    let (color, shade) = match obj {
+        Some(obj) if ppu.cgb_mode => {
+            let color = bus.cgb_palettes.obj_color(
+                obj.flags & OBJ_CGB_PALETTE, obj.color_ix, ppu.color_correction);
+            (color, 0)
+        },
+        Some(obj) => {
+            let obp = if obj.flags & OBJ_PALETTE > 0 { io_ports.read(IO_OBP1) } else { io_ports.read(IO_OBP0) };
+            let shade = (obp >> (obj.color_ix * 2)) & 0b11;
+            let palette = if obj.flags & OBJ_PALETTE > 0 { &ppu.palette.obj1 } else { &ppu.palette.obj0 };
+            (palette[shade as usize], shade)
+        },
+        None if ppu.cgb_mode => {
+            let color = bus.cgb_palettes.bg_color(
+                bg.attr & BG_ATTR_PALETTE, bg_color_ix, ppu.color_correction);
+            (color, 0)
+        },
+        None if bg_enabled => {
+            let shade = (io_ports.read(IO_BGP) >> (bg_color_ix * 2)) & 0b11;
+            (ppu.palette.bg[shade as usize], shade)
+        },
+        None => (ppu.palette.bg[0], 0),
+    };
+    ppu.line[x] = color;
+    ppu.line_shades[x] = shade;
+}
+
+fn start_hblank(ppu: &mut Ppu, bus: &PpuBus) {
+    set_mode(ppu, bus, STAT_MODE_HBLANK);
+    if ppu.fetcher.window {
+        ppu.window_line += 1;
+    }
+
+    let y = ppu.ly as usize;
+    let screen = &mut ppu.back_buffer;
+    if let Some(sgb) = bus.sgb {
+        // On the SGB, the LCD output is colorized afterwards using the shade of each pixel.
+        let mut sgb = sgb.lock().unwrap();
+        sgb.lcd[y] = ppu.line_shades;
+        for x in 0..160 {
+            // If the SGB has frozen the screen, keep showing what was there before.
+            if let Some(color) = sgb.screen_color(x, y, ppu.line_shades[x]) {
+                screen[y][x] = color;
             }
-            wait_if_debug_break(ppu);
-            sleep_precise(DRAW_TIME.checked_sub(draw_start.elapsed()).unwrap_or(Duration::ZERO));
-            wait_if_debug_break(ppu);
-            drop(vram);
-            drop(screen);
-
-            // HBlank
-
-            io_ports.and(IO_STAT, !STAT_MODE);
-            io_ports.or(IO_STAT, STAT_MODE_HBLANK);
-            let int_on_hblank = io_ports.read(IO_STAT) & STAT_INT_M00 > 0;
-            let int_on_lyc_incident = io_ports.read(IO_STAT) & STAT_INT_LYC > 0;
-            let lyc_incident = io_ports.read(IO_STAT) & STAT_LYC_SET > 0;
-            if ppu.ime.load(Ordering::Relaxed) && io_ports.read(IO_IE) & INT_LCDC > 0 && (int_on_hblank || (int_on_lyc_incident && lyc_incident)) {
-                io_ports.or(IO_IF, INT_LCDC);
-                let mut interrupted = mutex.lock().unwrap();
-                *interrupted = true;
-                cvar.notify_one();
+        }
+    } else {
+        screen[y] = ppu.line;
+    }
+
+    bus.hblank_started.store(true, Ordering::Relaxed);
+}
+
+fn start_vblank(ppu: &mut Ppu, bus: &PpuBus) {
+    set_mode(ppu, bus, STAT_MODE_VBLANK);
+    if let Some(sgb) = bus.sgb {
+        sgb.lock().unwrap().vblank();
+    }
+
+    bus.io_ports.or(IO_IF, INT_VBLANK);
+    ppu.frame_started = true;
+    present_frame(ppu, bus);
+
+    let elapsed = ppu.frame_start.elapsed();
+    ppu.frame_start = Instant::now();
+    ppu.debug_info.actual_time_micros.store(elapsed.as_micros() as u64, Ordering::Relaxed);
+    ppu.debug_info.expected_time_micros.store(FRAME_TIME.as_micros() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use crate::gameboy::utils::{test_dmg};
+
+    /// Runs the first line of a frame and returns how many dots mode 3 took.
+    fn mode3_length(gb: &mut Gameboy) -> u16 {
+        let mut dots = 0;
+        for _ in 0..LINE_DOTS {
+            run_ppu(gb, 1);
+            if gb.ppu.mode == STAT_MODE_TRANSFER {
+                dots += 1;
             }
-            wait_if_debug_break(ppu);
-            sleep_precise(HBLANK_TIME);
-            wait_if_debug_break(ppu);
+        }
+        dots
+    }
 
-            io_ports.add(IO_LY, 1);
-            if io_ports.read(IO_LY) == io_ports.read(IO_LYC) {
-                io_ports.or(IO_STAT, STAT_LYC_SET);
-            } else {
-                io_ports.and(IO_STAT, !STAT_LYC_SET);
+    #[test]
+    fn mode3_minimum_length() {
+        let mut gb = test_dmg(&[]);
+        assert_eq!(mode3_length(&mut gb), 172);
+    }
+
+    #[test]
+    fn mode3_fine_scroll_penalty() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_SCX, 0x0b);
+        assert_eq!(mode3_length(&mut gb), 172 + 3);
+    }
+
+    #[test]
+    fn mode3_window_penalty() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_LCDC, LCDC_ON | LCDC_WIN_DISP | LCDC_BG_DISP);
+        gb.io_ports.write(IO_WY, 0);
+        gb.io_ports.write(IO_WX, 7 + 80);
+        assert_eq!(mode3_length(&mut gb), 172 + 6);
+    }
+
+    #[test]
+    fn mode3_obj_penalty() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_LCDC, LCDC_ON | LCDC_OBJ_DISP | LCDC_BG_DISP);
+        {
+            let mut oam = gb.oam.lock().unwrap();
+            // One object at the start of a tile, and one that's 5 pixels into a tile.
+            oam[0..4].copy_from_slice(&[16, 8 + 40, 0, 0]);
+            oam[4..8].copy_from_slice(&[16, 8 + 85, 0, 0]);
+        }
+        // 6 dots per object, plus up to 5 dots waiting for the BG fetcher depending on how far
+        // into the tile the object is.
+        assert_eq!(mode3_length(&mut gb), 172 + 11 + 6);
+    }
+
+    #[test]
+    fn objs_limited_per_line() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_LCDC, LCDC_ON | LCDC_OBJ_DISP | LCDC_BG_DISP);
+        {
+            let mut oam = gb.oam.lock().unwrap();
+            for i in 0..12 {
+                oam[i*4..i*4+4].copy_from_slice(&[16, 8 + 8 * i as u8, 0, 0]);
             }
         }
-        
-        // VBlank
-
-        io_ports.and(IO_STAT, !STAT_MODE);
-        io_ports.or(IO_STAT, STAT_MODE_VBLANK);
-        let int_on_vblank = io_ports.read(IO_IE) & INT_VBLANK > 0;
-        let int_on_m01 = (io_ports.read(IO_IE) & INT_LCDC > 0) && (io_ports.read(IO_STAT) & STAT_INT_M01 > 0);
-        if ppu.ime.load(Ordering::Relaxed) && (int_on_vblank || int_on_m01) {
-            if int_on_vblank { io_ports.or(IO_IF, INT_VBLANK); } else { io_ports.or(IO_IF, INT_LCDC); }
-            let mut interrupted = mutex.lock().unwrap();
-            *interrupted = true;
-            cvar.notify_one();
+        run_ppu(&mut gb, OAM_SCAN_DOTS as u64);
+        assert_eq!(gb.ppu.line_objs.len(), MAX_OBJS_PER_LINE);
+        assert_eq!(gb.ppu.line_objs[9].0, 9);
+    }
+
+    /// Runs the PPU until it's at the given dot of the given line.
+    fn run_to(gb: &mut Gameboy, ly: u8, dot: u16) {
+        run_ppu(gb, 1);
+        while gb.ppu.ly != ly || gb.ppu.dot != dot {
+            run_ppu(gb, 1);
         }
-        for _ in 0..10 {
-            wait_if_debug_break(ppu);
-            sleep_precise(LINE_TIME);
-            io_ports.add(IO_LY, 1);
+    }
+
+    fn stat_test_gameboy(stat: u8, lyc: u8) -> Gameboy {
+        let gb = test_dmg(&[]);
+        gb.io_ports.write(IO_IF, 0);
+        gb.io_ports.write(IO_STAT, stat);
+        gb.io_ports.write(IO_LYC, lyc);
+        gb
+    }
+
+    #[test]
+    fn lyc_interrupt_during_vblank() {
+        let mut gb = stat_test_gameboy(STAT_INT_LYC, 150);
+        run_to(&mut gb, 149, 100);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_LCDC, 0);
+        run_to(&mut gb, 150, 1);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_LCDC, INT_LCDC);
+        assert_eq!(gb.io_ports.read(IO_STAT) & STAT_LYC_SET, STAT_LYC_SET);
+    }
+
+    #[test]
+    fn ly_reads_0_early_on_line_153() {
+        let mut gb = stat_test_gameboy(STAT_INT_LYC, 0);
+        run_to(&mut gb, 153, 1);
+        assert_eq!(gb.io_ports.read(IO_LY), 153);
+        gb.io_ports.write(IO_IF, 0);
+        run_to(&mut gb, 153, LY_153_DOTS + 1);
+        assert_eq!(gb.io_ports.read(IO_LY), 0);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_LCDC, INT_LCDC);
+
+        // The line is still high when line 0 actually starts, so there's no second interrupt.
+        gb.io_ports.write(IO_IF, 0);
+        run_to(&mut gb, 0, 1);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_LCDC, 0);
+    }
+
+    #[test]
+    fn stat_interrupt_blocked_while_line_high() {
+        let mut gb = stat_test_gameboy(STAT_INT_M00 | STAT_INT_M01, 0xff);
+        run_to(&mut gb, 143, 300);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_LCDC, INT_LCDC);
+        gb.io_ports.write(IO_IF, 0);
+        run_to(&mut gb, 144, 1);
+        assert_eq!(gb.io_ports.read(IO_STAT) & STAT_MODE, STAT_MODE_VBLANK);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_LCDC, 0);
+    }
+
+    #[test]
+    fn bg_pixels_use_scroll_and_palette() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_BGP, 0b11_10_01_00);
+        gb.io_ports.write(IO_SCX, 4);
+        {
+            let mut vram = gb.vram.lock().unwrap();
+            // Tile 1 is solid color 3, and the second tile of the first row of the map uses it.
+            vram[0x10..0x20].fill(0xff);
+            vram[0x1801] = 1;
         }
+        run_ppu(&mut gb, LINE_DOTS as u64);
+        let screen = &gb.ppu.back_buffer;
+        assert_eq!(screen[0][3], PALETTE_GREY[0]);
+        assert_eq!(screen[0][4], PALETTE_GREY[3]);
+        assert_eq!(screen[0][11], PALETTE_GREY[3]);
+        assert_eq!(screen[0][12], PALETTE_GREY[0]);
+    }
 
-        let elapsed = frame_start.elapsed();
-        let expected = FRAME_TIME;
-        debug_info.actual_time_micros.store(elapsed.as_micros() as u64, Ordering::Relaxed);
-        debug_info.expected_time_micros.store(expected.as_micros() as u64, Ordering::Relaxed);
+    #[test]
+    fn obj_priority_uses_bg_color_index() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_LCDC, LCDC_ON | LCDC_TILE_DATA | LCDC_OBJ_DISP | LCDC_BG_DISP);
+        // Every BG color index is shown as white, so only the index tells BG color 0 apart.
+        gb.io_ports.write(IO_BGP, 0);
+        gb.io_ports.write(IO_OBP0, 0b11_10_01_00);
+        gb.io_ports.write(IO_OBP1, 0b11_10_01_00);
+        gb.ppu.palette.obj0 = PALETTE_RED;
+        gb.ppu.palette.obj1 = PALETTE_BLUE;
+        {
+            let mut vram = gb.vram.lock().unwrap();
+            // Tile 1 is solid color 3 and covers x 8-15. Tile 2 is solid color 1.
+            vram[0x10..0x20].fill(0xff);
+            vram[0x1801] = 1;
+            for row in vram[0x20..0x30].chunks_mut(2) {
+                row[0] = 0xff;
+            }
+        }
+        {
+            let mut oam = gb.oam.lock().unwrap();
+            // Both objects are behind the BG, but only the first one is over a nonzero BG color.
+            oam[0..4].copy_from_slice(&[16, 16, 2, OBJ_PRIORITY | OBJ_PALETTE]);
+            oam[4..8].copy_from_slice(&[16, 24, 2, OBJ_PRIORITY]);
+            oam[8..12].copy_from_slice(&[16, 40, 2, OBJ_PALETTE]);
+        }
+        run_ppu(&mut gb, LINE_DOTS as u64);
+        let screen = &gb.ppu.back_buffer;
+        assert_eq!(screen[0][8], PALETTE_GREY[0]);
+        assert_eq!(screen[0][16], PALETTE_RED[1]);
+        assert_eq!(screen[0][32], PALETTE_BLUE[1]);
     }
-}
 
-fn wait_if_debug_break(ppu: &Ppu) {
-    while ppu.step_mode.load(Ordering::Acquire) {
-        thread::park();
+    #[test]
+    fn frame_presented_at_vblank() {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_BGP, 0b11_11_11_11);
+        run_ppu(&mut gb, LINE_DOTS as u64);
+        assert_eq!(gb.ppu.back_buffer[0][0], PALETTE_GREY[3]);
+        assert_eq!(gb.screen.lock().unwrap()[0][0], (0, 0, 0));
+        assert!(!gb.frame_ready.load(Ordering::Relaxed));
+
+        // The screen keeps the previous frame until the new one is done.
+        gb.screen.lock().unwrap()[0][0] = PALETTE_GREY[1];
+        run_to(&mut gb, 143, 0);
+        assert_eq!(gb.screen.lock().unwrap()[0][0], PALETTE_GREY[1]);
+        run_to(&mut gb, 144, 1);
+        assert_eq!(gb.screen.lock().unwrap()[0][0], PALETTE_GREY[3]);
+        assert!(gb.frame_ready.load(Ordering::Relaxed));
     }
 }
@@ -0,0 +1,31 @@
+use std::sync::atomic::{Ordering};
+use crate::gameboy::gameboy::{*};
+use crate::gameboy::ppu::{run_ppu};
+use crate::gameboy::timer::{run_timer};
+use crate::gameboy::joypad::{run_joypad};
+use crate::gameboy::movie::{run_movie_frame};
+use crate::gameboy::capture::{run_capture_frame};
+
+/// Runs the rest of the system for the given number of machine cycles, so that it stays in
+/// lockstep with the CPU.
+pub fn tick(gb: &mut Gameboy, m_cycles: u64) {
+    gb.cycles += m_cycles;
+    for _ in 0..m_cycles {
+        run_timer(gb);
+    }
+
+    // The PPU runs at 4 dots per machine cycle in normal speed mode. Double speed mode only
+    // speeds up the CPU, so the PPU gets half as many dots per machine cycle.
+    let dots = if gb.double_speed.load(Ordering::Relaxed) { m_cycles * 2 } else { m_cycles * 4 };
+    run_ppu(gb, dots);
+    if gb.ppu.frame_started {
+        gb.ppu.frame_started = false;
+        if let Some(palette) = gb.ppu.palette_request.lock().unwrap().take() {
+            gb.ppu.palette = palette;
+        }
+        run_capture_frame(gb);
+        run_movie_frame(gb);
+        gb.speed.start_frame();
+    }
+    run_joypad(gb);
+}
@@ -0,0 +1,498 @@
+use crate::gameboy::gameboy::{P1_OUT};
+use crate::gameboy::cgb_palette::{rgb555_to_rgb888};
+
+pub const SGB_BORDER_WIDTH: usize  = 256;
+pub const SGB_BORDER_HEIGHT: usize = 224;
+/// Position of the top-left corner of the Gameboy screen within the border.
+pub const SGB_SCREEN_X: usize = 48;
+pub const SGB_SCREEN_Y: usize = 40;
+
+const CMD_PAL01: u8    = 0x00;
+const CMD_PAL23: u8    = 0x01;
+const CMD_PAL03: u8    = 0x02;
+const CMD_PAL12: u8    = 0x03;
+const CMD_ATTR_BLK: u8 = 0x04;
+const CMD_ATTR_LIN: u8 = 0x05;
+const CMD_ATTR_DIV: u8 = 0x06;
+const CMD_ATTR_CHR: u8 = 0x07;
+const CMD_MLT_REQ: u8  = 0x11;
+const CMD_CHR_TRN: u8  = 0x13;
+const CMD_PCT_TRN: u8  = 0x14;
+const CMD_MASK_EN: u8  = 0x17;
+
+const MASK_CANCEL: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8  = 2;
+const MASK_COLOR0: u8 = 3;
+
+/// Palette the SGB uses until the game sets its own.
+const DEFAULT_PALETTE: [u16; 4] = [0x67bf, 0x265b, 0x10b5, 0x2866];
+
+/// Width and height of the screen in 8x8 attribute cells.
+const CELLS_W: usize = 20;
+const CELLS_H: usize = 18;
+
+#[derive(Debug, Copy, Clone)]
+enum VramTransfer {
+    /// CHR_TRN, with the half of border tile data being written (0 = tiles 0x00-0x7f).
+    Chr(usize),
+    /// PCT_TRN.
+    Pct,
+}
+
+/// Super Gameboy state.
+/// Games talk to the SGB by sending 16-byte packets one bit at a time through P1, and the SGB
+/// colorizes the Gameboy's LCD output and draws a border around it.
+pub struct Sgb {
+    /// P1 output lines from the previous write.
+    prev_lines: u8,
+    /// Whether a packet is currently being received.
+    receiving: bool,
+    /// Bits are only read after both lines have gone high again since the last bit.
+    ready_for_bit: bool,
+    packet: [u8; 16],
+    bit_ix: usize,
+    /// Packets received so far for the current command.
+    command: Vec<u8>,
+
+    /// Number of players enabled through MLT_REQ (1, 2 or 4).
+    pub player_count: u8,
+    /// Player whose controller is currently being read, with player 1 = 0.
+    pub current_player: u8,
+
+    /// Colorization palettes 0-3, as 15-bit colors. Color 0 is shared by all palettes.
+    palettes: [[u16; 4]; 4],
+    /// Which palette each 8x8 cell of the screen uses.
+    attr_map: [[u8; CELLS_W]; CELLS_H],
+    /// Set through MASK_EN. Lets the game hide the screen while it sets things up.
+    mask: u8,
+
+    /// Shades (after BGP/OBP0/OBP1) output by the Gameboy's LCD, before colorization.
+    /// This is also what VRAM transfers read from.
+    pub lcd: Box<[[u8; 160]; 144]>,
+    /// VRAM transfer waiting for the next frame, along with the number of VBlanks to wait for.
+    pending_transfer: Option<(VramTransfer, u8)>,
+
+    /// 256 4bpp tiles in SNES format.
+    border_tiles: Box<[u8; 0x2000]>,
+    /// 32x32 SNES tile map entries.
+    border_map: Box<[u8; 0x800]>,
+    /// Border palettes 4-7, 16 colors each.
+    border_palettes: [[u16; 16]; 4],
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            prev_lines: P1_OUT,
+            receiving: false,
+            ready_for_bit: false,
+            packet: [0; 16],
+            bit_ix: 0,
+            command: vec!(),
+
+            player_count: 1,
+            current_player: 0,
+
+            palettes: [DEFAULT_PALETTE; 4],
+            attr_map: [[0; CELLS_W]; CELLS_H],
+            mask: MASK_CANCEL,
+
+            lcd: Box::new([[0; 160]; 144]),
+            pending_transfer: None,
+
+            border_tiles: Box::new([0; 0x2000]),
+            border_map: Box::new([0; 0x800]),
+            border_palettes: [[0; 16]; 4],
+        }
+    }
+
+    /// Handles a write to P1, which is how the game sends packets and switches between
+    /// controllers in multiplayer mode.
+    pub fn write_p1(&mut self, value: u8) {
+        let lines = value & P1_OUT;
+        match lines {
+            // Both lines low resets the transfer and starts a new packet.
+            0b0000_0000 => {
+                self.receiving = true;
+                self.ready_for_bit = false;
+                self.packet = [0; 16];
+                self.bit_ix = 0;
+            },
+            0b0011_0000 => {
+                // P15 going back high selects the next controller.
+                if !self.receiving && self.player_count > 1 && self.prev_lines == 0b0001_0000 {
+                    self.current_player = (self.current_player + 1) % self.player_count;
+                }
+                self.ready_for_bit = true;
+            },
+            // P15 low sends a 1, P14 low sends a 0.
+            0b0001_0000 | 0b0010_0000 if self.receiving && self.ready_for_bit => {
+                self.ready_for_bit = false;
+                let bit = if lines == 0b0001_0000 { 1 } else { 0 };
+                if self.bit_ix < 128 {
+                    self.packet[self.bit_ix / 8] |= bit << (self.bit_ix % 8);
+                    self.bit_ix += 1;
+                } else {
+                    // The 129th bit is a stop bit, which must be 0.
+                    self.receiving = false;
+                    if bit == 0 {
+                        self.receive_packet();
+                    }
+                }
+            },
+            _ => {},
+        }
+        self.prev_lines = lines;
+    }
+
+    /// The lower bits of P1 when neither button group is selected, in multiplayer mode.
+    /// Returns None when multiplayer is off.
+    pub fn joypad_id(&self) -> Option<u8> {
+        if self.player_count > 1 {
+            Some(0x0f - self.current_player)
+        } else {
+            None
+        }
+    }
+
+    /// Color for a pixel of the Gameboy screen, or None if the screen is frozen and the pixel
+    /// shouldn't change.
+    pub fn screen_color(&self, x: usize, y: usize, shade: u8) -> Option<(u8, u8, u8)> {
+        match self.mask {
+            MASK_FREEZE => None,
+            MASK_BLACK => Some((0, 0, 0)),
+            MASK_COLOR0 => Some(rgb555_to_rgb888(self.palettes[0][0], false)),
+            _ => {
+                let palette = self.attr_map[y / 8][x / 8] as usize;
+                Some(rgb555_to_rgb888(self.palettes[palette][shade as usize], false))
+            },
+        }
+    }
+
+    /// Color shown wherever neither the border nor the Gameboy screen cover the SNES screen.
+    pub fn backdrop_color(&self) -> (u8, u8, u8) {
+        rgb555_to_rgb888(self.palettes[0][0], false)
+    }
+
+    /// Color of a border pixel, or None if the pixel is transparent.
+    pub fn border_color(&self, x: usize, y: usize) -> Option<(u8, u8, u8)> {
+        let map_ix = ((y / 8) * 32 + (x / 8)) * 2;
+        let entry = u16::from_le_bytes([self.border_map[map_ix], self.border_map[map_ix + 1]]);
+        let tile = (entry & 0x00ff) as usize;
+        let palette = ((entry >> 10) & 0b111) as usize;
+        let x_flip = entry & 0x4000 > 0;
+        let y_flip = entry & 0x8000 > 0;
+
+        let row = if y_flip { 7 - (y % 8) } else { y % 8 };
+        let col = if x_flip { x % 8 } else { 7 - (x % 8) };
+        // SNES 4bpp tiles store bit planes 0 and 1 interleaved for all rows, followed by planes 2
+        // and 3.
+        let tile_data = &self.border_tiles[tile * 32..tile * 32 + 32];
+        let color_ix =
+            ((tile_data[row * 2] >> col) & 1)
+            | (((tile_data[row * 2 + 1] >> col) & 1) << 1)
+            | (((tile_data[16 + row * 2] >> col) & 1) << 2)
+            | (((tile_data[16 + row * 2 + 1] >> col) & 1) << 3);
+
+        if color_ix == 0 || palette < 4 {
+            return None;
+        }
+        Some(rgb555_to_rgb888(self.border_palettes[palette - 4][color_ix as usize], false))
+    }
+
+    /// Should be called by the PPU at the start of every VBlank.
+    pub fn vblank(&mut self) {
+        match self.pending_transfer {
+            Some((transfer, 0)) => {
+                self.pending_transfer = None;
+                self.run_vram_transfer(transfer);
+            },
+            Some((transfer, frames)) => self.pending_transfer = Some((transfer, frames - 1)),
+            None => {},
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        // The low 3 bits of the first byte give the number of packets in the command.
+        let num_packets = usize::max(1, (self.command[0] & 0b111) as usize);
+        if self.command.len() >= num_packets * 16 {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            CMD_PAL01 => self.set_palettes(data, 0, 1),
+            CMD_PAL23 => self.set_palettes(data, 2, 3),
+            CMD_PAL03 => self.set_palettes(data, 0, 3),
+            CMD_PAL12 => self.set_palettes(data, 1, 2),
+            CMD_ATTR_BLK => self.attr_blk(data),
+            CMD_ATTR_LIN => self.attr_lin(data),
+            CMD_ATTR_DIV => self.attr_div(data),
+            CMD_ATTR_CHR => self.attr_chr(data),
+            CMD_MLT_REQ => {
+                self.player_count = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            },
+            // The data is read from the frame after the command is sent, so wait for the frame
+            // currently being drawn to finish first.
+            CMD_CHR_TRN => self.pending_transfer = Some((VramTransfer::Chr((data[1] & 1) as usize), 1)),
+            CMD_PCT_TRN => self.pending_transfer = Some((VramTransfer::Pct, 1)),
+            CMD_MASK_EN => self.mask = data[1] & 0b11,
+            cmd => eprintln!("Unsupported SGB command ${cmd:0>2x}"),
+        }
+    }
+
+    /// PAL01, PAL23, PAL03, PAL12: sets colors 1-3 of two palettes, and color 0 of all palettes.
+    fn set_palettes(&mut self, data: &[u8], a: usize, b: usize) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i*2], data[2 + i*2]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+    }
+
+    /// ATTR_BLK: sets palettes inside, on the edge of, and outside of rectangular blocks of cells.
+    fn attr_blk(&mut self, data: &[u8]) {
+        let num_sets = data[1] as usize;
+        for set in data[2..].chunks_exact(6).take(num_sets) {
+            let control = set[0] & 0b111;
+            let mut palette_inside = set[1] & 0b11;
+            let mut palette_edge = (set[1] >> 2) & 0b11;
+            let palette_outside = (set[1] >> 4) & 0b11;
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+
+            // When only the inside or only the outside is changed, the edge changes along with it.
+            let change_inside = control & 0b001 > 0;
+            let mut change_edge = control & 0b010 > 0;
+            let change_outside = control & 0b100 > 0;
+            if control == 0b001 {
+                change_edge = true;
+                palette_edge = palette_inside;
+            } else if control == 0b100 {
+                change_edge = true;
+                palette_edge = palette_outside;
+                palette_inside = palette_outside;
+            }
+
+            for y in 0..CELLS_H {
+                for x in 0..CELLS_W {
+                    let in_block = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_edge = in_block && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_edge {
+                        if change_edge { self.attr_map[y][x] = palette_edge; }
+                    } else if in_block {
+                        if change_inside { self.attr_map[y][x] = palette_inside; }
+                    } else if change_outside {
+                        self.attr_map[y][x] = palette_outside;
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: sets the palette of whole rows or columns of cells.
+    fn attr_lin(&mut self, data: &[u8]) {
+        let num_sets = data[1] as usize;
+        for &set in data[2..].iter().take(num_sets) {
+            let line = (set & 0b0001_1111) as usize;
+            let palette = (set >> 5) & 0b11;
+            let horizontal = set & 0b1000_0000 > 0;
+            if horizontal && line < CELLS_H {
+                self.attr_map[line] = [palette; CELLS_W];
+            } else if !horizontal && line < CELLS_W {
+                for row in self.attr_map.iter_mut() {
+                    row[line] = palette;
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV: divides the screen in two with a line, and sets the palette on each side and on
+    /// the line itself.
+    fn attr_div(&mut self, data: &[u8]) {
+        let palette_after = data[1] & 0b11;
+        let palette_before = (data[1] >> 2) & 0b11;
+        let palette_line = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & 0b0100_0000 > 0;
+        let coord = data[2] as usize;
+        for y in 0..CELLS_H {
+            for x in 0..CELLS_W {
+                let pos = if horizontal { y } else { x };
+                self.attr_map[y][x] =
+                    if pos < coord { palette_before }
+                    else if pos == coord { palette_line }
+                    else { palette_after };
+            }
+        }
+    }
+
+    /// ATTR_CHR: sets the palette of individual cells, starting from a given cell and continuing
+    /// either left to right or top to bottom.
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let top_to_bottom = data[5] & 1 > 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            if x >= CELLS_W || y >= CELLS_H {
+                break;
+            }
+            // Each byte holds 4 palettes, starting from the top bits.
+            self.attr_map[y][x] = (byte >> (6 - 2 * (i % 4))) & 0b11;
+            if top_to_bottom {
+                y += 1;
+                if y == CELLS_H { y = 0; x += 1; }
+            } else {
+                x += 1;
+                if x == CELLS_W { x = 0; y += 1; }
+            }
+        }
+    }
+
+    /// Reads 4KB of data from the LCD output. Games send data to the SGB by displaying a tile
+    /// map of consecutive tiles, which the SGB then reads back 2bpp tile by tile.
+    fn read_vram_transfer(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(0x1000);
+        for tile in 0..256 {
+            let tile_x = (tile % CELLS_W) * 8;
+            let tile_y = (tile / CELLS_W) * 8;
+            for row in 0..8 {
+                let mut low = 0;
+                let mut high = 0;
+                for col in 0..8 {
+                    let shade = self.lcd[tile_y + row][tile_x + col];
+                    low |= (shade & 1) << (7 - col);
+                    high |= ((shade >> 1) & 1) << (7 - col);
+                }
+                data.push(low);
+                data.push(high);
+            }
+        }
+        data
+    }
+
+    fn run_vram_transfer(&mut self, transfer: VramTransfer) {
+        let data = self.read_vram_transfer();
+        match transfer {
+            VramTransfer::Chr(half) => {
+                self.border_tiles[half * 0x1000..(half + 1) * 0x1000].copy_from_slice(&data);
+            },
+            VramTransfer::Pct => {
+                self.border_map.copy_from_slice(&data[0x000..0x800]);
+                for (i, color) in data[0x800..0x880].chunks_exact(2).enumerate() {
+                    self.border_palettes[i / 16][i % 16] = u16::from_le_bytes([color[0], color[1]]);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; 16]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for byte in packet.iter() {
+            for bit in 0..8 {
+                sgb.write_p1(if (byte >> bit) & 1 > 0 { 0x10 } else { 0x20 });
+                sgb.write_p1(0x30);
+            }
+        }
+        // Stop bit
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    #[test]
+    fn pal01() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = (CMD_PAL01 << 3) | 1;
+        for i in 0..7 {
+            packet[1 + i*2] = i as u8;
+        }
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0], [0, 1, 2, 3]);
+        assert_eq!(sgb.palettes[1], [0, 4, 5, 6]);
+        assert_eq!(sgb.palettes[2][0], 0);
+        assert_eq!(sgb.palettes[2][1], DEFAULT_PALETTE[1]);
+    }
+
+    #[test]
+    fn mlt_req() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = (CMD_MLT_REQ << 3) | 1;
+        packet[1] = 1;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.joypad_id(), Some(0x0f));
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.joypad_id(), Some(0x0e));
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.joypad_id(), Some(0x0f));
+    }
+
+    #[test]
+    fn attr_blk_inside_only_changes_edge() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = (CMD_ATTR_BLK << 3) | 1;
+        packet[1] = 1;
+        packet[2..8].copy_from_slice(&[0b001, 0b10, 2, 2, 5, 5]);
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.attr_map[2][2], 2);
+        assert_eq!(sgb.attr_map[3][3], 2);
+        assert_eq!(sgb.attr_map[5][5], 2);
+        assert_eq!(sgb.attr_map[6][6], 0);
+    }
+
+    #[test]
+    fn attr_div() {
+        let mut sgb = Sgb::new();
+        let mut packet = [0; 16];
+        packet[0] = (CMD_ATTR_DIV << 3) | 1;
+        packet[1] = 0b0110_0111;
+        packet[2] = 9;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.attr_map[8][0], 1);
+        assert_eq!(sgb.attr_map[9][0], 2);
+        assert_eq!(sgb.attr_map[10][19], 3);
+    }
+
+    #[test]
+    fn chr_trn() {
+        let mut sgb = Sgb::new();
+        // Tile 1 gets a solid row of shade 3 at the top.
+        for col in 8..16 {
+            sgb.lcd[0][col] = 3;
+        }
+        let mut packet = [0; 16];
+        packet[0] = (CMD_CHR_TRN << 3) | 1;
+        packet[1] = 1;
+        send_packet(&mut sgb, &packet);
+        sgb.vblank();
+        assert_eq!(sgb.border_tiles[0x1000 + 16], 0x00);
+        sgb.vblank();
+        assert_eq!(sgb.border_tiles[0x1000 + 16], 0xff);
+        assert_eq!(sgb.border_tiles[0x1000 + 17], 0xff);
+    }
+}
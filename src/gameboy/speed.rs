@@ -0,0 +1,226 @@
+use std::fmt;
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration};
+
+/// How fast emulation runs compared to hardware.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Speed {
+    Multiplier(f64),
+    /// As fast as the host can go, without sleeping between instructions.
+    Uncapped,
+}
+
+/// The speeds that the speed up and slow down keys step through.
+pub const SPEEDS: [Speed; 7] = [
+    Speed::Multiplier(0.25),
+    Speed::Multiplier(0.5),
+    Speed::Multiplier(1.0),
+    Speed::Multiplier(2.0),
+    Speed::Multiplier(4.0),
+    Speed::Multiplier(8.0),
+    Speed::Uncapped,
+];
+/// Index of 1x in SPEEDS.
+const NORMAL_SPEED: usize = 2;
+
+impl Speed {
+    /// Parses a multiplier from SPEEDS, like "0.25" or "2", or "uncapped".
+    pub fn new(s: &str) -> Result<Self, String> {
+        let s = s.trim_end_matches('x');
+        if s == "uncapped" {
+            return Ok(Speed::Uncapped);
+        }
+        s.parse::<f64>().ok()
+            .map(Speed::Multiplier)
+            .filter(|speed| SPEEDS.contains(speed))
+            .ok_or(format!("Unsupported speed '{s}', expected 0.25, 0.5, 1, 2, 4, 8 or uncapped"))
+    }
+
+    /// How long something that takes the given time on hardware should take at this speed, or
+    /// None if there's no need to wait at all.
+    pub fn scale(&self, dur: Duration) -> Option<Duration> {
+        match *self {
+            Speed::Multiplier(multiplier) => Some(dur.div_f64(multiplier)),
+            Speed::Uncapped => None,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Speed::Multiplier(multiplier) => write!(f, "{}x", multiplier),
+            Speed::Uncapped => write!(f, "uncapped"),
+        }
+    }
+}
+
+/// Pause, frame advance and speed controls, shared between the CPU thread, which acts on them, and
+/// the UI, which sets them.
+pub struct SpeedControl {
+    /// Index into SPEEDS of the speed to run at.
+    speed_ix: Arc<AtomicUsize>,
+    /// While set, emulation runs uncapped regardless of the selected speed.
+    pub fast_forward: Arc<AtomicBool>,
+    /// Set by the UI when the user pauses. Emulation only stops at the start of the next frame,
+    /// so that frame advance always moves forward by whole frames.
+    pause_requested: Arc<AtomicBool>,
+    /// Whether the CPU thread is actually waiting for emulation to be unpaused.
+    paused: Arc<AtomicBool>,
+    /// Frames left to run before stopping again while paused.
+    frames_to_advance: Arc<AtomicU32>,
+}
+
+impl SpeedControl {
+    pub fn new() -> Self {
+        Self {
+            speed_ix: Arc::new(AtomicUsize::new(NORMAL_SPEED)),
+            fast_forward: Arc::new(AtomicBool::new(false)),
+            pause_requested: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            frames_to_advance: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// The speed currently in effect, taking fast-forward into account.
+    pub fn speed(&self) -> Speed {
+        if self.fast_forward.load(Ordering::Relaxed) {
+            Speed::Uncapped
+        } else {
+            SPEEDS[self.speed_ix.load(Ordering::Relaxed)]
+        }
+    }
+
+    pub fn set_speed(&self, speed: Speed) {
+        if let Some(ix) = SPEEDS.iter().position(|&s| s == speed) {
+            self.speed_ix.store(ix, Ordering::Relaxed);
+        }
+    }
+
+    /// Steps to the next faster speed if `faster` is set, or the next slower one otherwise, and
+    /// returns the new speed.
+    pub fn change_speed(&self, faster: bool) -> Speed {
+        let ix = self.speed_ix.load(Ordering::Relaxed);
+        let ix = if faster { (ix + 1).min(SPEEDS.len() - 1) } else { ix.saturating_sub(1) };
+        self.speed_ix.store(ix, Ordering::Relaxed);
+        SPEEDS[ix]
+    }
+
+    /// Pauses or unpauses, and returns whether emulation is now paused.
+    pub fn toggle_pause(&self) -> bool {
+        let pause = !self.pause_requested.load(Ordering::Relaxed);
+        self.pause_requested.store(pause, Ordering::Relaxed);
+        self.frames_to_advance.store(0, Ordering::Relaxed);
+        pause
+    }
+
+    /// Runs one more frame while paused. If emulation isn't paused, it pauses at the end of the
+    /// current frame instead.
+    pub fn advance_frame(&self) {
+        if self.pause_requested.swap(true, Ordering::Relaxed) {
+            self.frames_to_advance.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Called by the CPU thread at the start of every frame.
+    pub fn start_frame(&self) {
+        if self.pause_requested.load(Ordering::Relaxed) {
+            self.paused.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Called by the CPU thread before every instruction. Returns true if emulation should wait,
+    /// either because it's paused or because there are no frames left to advance.
+    pub fn is_paused(&self) -> bool {
+        if !self.paused.load(Ordering::Relaxed) {
+            return false;
+        }
+        let resume = !self.pause_requested.load(Ordering::Relaxed)
+            || self.frames_to_advance.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok();
+        if resume {
+            self.paused.store(false, Ordering::Relaxed);
+        }
+        !resume
+    }
+}
+
+impl Clone for SpeedControl {
+    fn clone(&self) -> Self {
+        Self {
+            speed_ix: self.speed_ix.clone(),
+            fast_forward: self.fast_forward.clone(),
+            pause_requested: self.pause_requested.clone(),
+            paused: self.paused.clone(),
+            frames_to_advance: self.frames_to_advance.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    #[test]
+    fn parse() {
+        assert_eq!(Speed::new("0.25"), Ok(Speed::Multiplier(0.25)));
+        assert_eq!(Speed::new("8x"), Ok(Speed::Multiplier(8.0)));
+        assert_eq!(Speed::new("uncapped"), Ok(Speed::Uncapped));
+        assert!(Speed::new("3").is_err());
+        assert!(Speed::new("fast").is_err());
+    }
+
+    #[test]
+    fn scale() {
+        let frame = Duration::from_millis(16);
+        assert_eq!(Speed::Multiplier(2.0).scale(frame), Some(Duration::from_millis(8)));
+        assert_eq!(Speed::Multiplier(0.25).scale(frame), Some(Duration::from_millis(64)));
+        assert_eq!(Speed::Uncapped.scale(frame), None);
+    }
+
+    #[test]
+    fn change_speed() {
+        let control = SpeedControl::new();
+        assert_eq!(control.change_speed(false), Speed::Multiplier(0.5));
+        assert_eq!(control.change_speed(false), Speed::Multiplier(0.25));
+        assert_eq!(control.change_speed(false), Speed::Multiplier(0.25));
+
+        control.set_speed(Speed::Multiplier(8.0));
+        assert_eq!(control.change_speed(true), Speed::Uncapped);
+        assert_eq!(control.change_speed(true), Speed::Uncapped);
+
+        control.set_speed(Speed::Multiplier(1.0));
+        control.fast_forward.store(true, Ordering::Relaxed);
+        assert_eq!(control.speed(), Speed::Uncapped);
+    }
+
+    #[test]
+    fn pause_at_frame_start() {
+        let control = SpeedControl::new();
+        control.toggle_pause();
+        // The rest of the current frame still runs.
+        assert!(!control.is_paused());
+        control.start_frame();
+        assert!(control.is_paused());
+
+        control.toggle_pause();
+        assert!(!control.is_paused());
+    }
+
+    #[test]
+    fn frame_advance() {
+        let control = SpeedControl::new();
+        // Advancing while running pauses at the next frame.
+        control.advance_frame();
+        control.start_frame();
+        assert!(control.is_paused());
+
+        control.advance_frame();
+        control.advance_frame();
+        assert!(!control.is_paused());
+        control.start_frame();
+        assert!(!control.is_paused());
+        control.start_frame();
+        assert!(control.is_paused());
+    }
+}
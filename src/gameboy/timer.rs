@@ -1,56 +1,195 @@
-use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
-use std::time::{Duration};
 use crate::gameboy::gameboy::{*};
 
-const CLOCK_4096_HZ_PULSE: Duration    = Duration::from_nanos(1_000_000_000 / 4096);
-const CLOCK_262_144_HZ_PULSE: Duration = Duration::from_nanos(1_000_000_000 / 262_144);
-const CLOCK_65_536_HZ_PULSE: Duration  = Duration::from_nanos(1_000_000_000 / 65_536);
-const CLOCK_16_384_HZ_PULSE: Duration  = Duration::from_nanos(1_000_000_000 / 16_384);
-
+/// DIV and TIMA, clocked by the scheduler once per machine cycle.
 pub struct Timer {
-    pub io_ports: Arc<IoPorts>,
-    pub interrupt_received: Arc<(Mutex<bool>, Condvar)>,
-    pub ime: Arc<AtomicBool>,
-    pub timer_enabled: Arc<(Mutex<bool>, Condvar)>,
-}
-
-pub fn run_timer(timer: &mut Timer) {
-    let io_ports = &timer.io_ports;
-
-    loop {
-        if io_ports.read(IO_TAC) & TAC_ENABLE == 0 {
-            let (mutex, cvar) = &*timer.timer_enabled;
-            let mut enabled = mutex.lock().unwrap();
-            while !*enabled {
-                enabled = cvar.wait(enabled).unwrap();
-            }
+    /// Internal counter that's incremented every clock (4 times per machine cycle). DIV is the
+    /// upper byte of this.
+    counter: u16,
+    /// Set when TIMA overflows. TIMA stays 0 for a machine cycle, and is then reloaded from TMA.
+    overflow: bool,
+    /// Set during the machine cycle in which TIMA is reloaded from TMA.
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn new(div: u8) -> Self {
+        Self {
+            counter: (div as u16) << 8,
+            overflow: false,
+            reloading: false,
         }
+    }
+}
+
+/// Runs the timer for a single machine cycle.
+pub fn run_timer(gb: &mut Gameboy) {
+    gb.timer.reloading = false;
+    if gb.timer.overflow {
+        gb.timer.overflow = false;
+        gb.timer.reloading = true;
+        gb.io_ports.write(IO_TIMA, gb.io_ports.read(IO_TMA));
+        gb.io_ports.or(IO_IF, INT_TIMER);
+    }
+
+    set_counter(gb, gb.timer.counter.wrapping_add(4));
+}
+
+pub fn write_div(gb: &mut Gameboy) {
+    set_counter(gb, 0);
+}
+
+pub fn write_tima(gb: &mut Gameboy, value: u8) {
+    // Writes in the same cycle as the reload lose out to TMA. Writes in the cycle before the
+    // reload cancel it, along with the interrupt.
+    if gb.timer.reloading {
+        return;
+    }
+    gb.timer.overflow = false;
+    gb.io_ports.write(IO_TIMA, value);
+}
+
+pub fn write_tma(gb: &mut Gameboy, value: u8) {
+    gb.io_ports.write(IO_TMA, value);
+    if gb.timer.reloading {
+        gb.io_ports.write(IO_TIMA, value);
+    }
+}
+
+pub fn write_tac(gb: &mut Gameboy, value: u8) {
+    let was_high = timer_input(gb.timer.counter, gb.io_ports.read(IO_TAC));
+    gb.io_ports.write(IO_TAC, value);
+    // Disabling the timer or switching frequencies can produce a falling edge too.
+    if was_high && !timer_input(gb.timer.counter, value) {
+        increment_tima(gb);
+    }
+}
+
+/// TIMA is incremented whenever the counter bit selected by TAC goes from 1 to 0 while the timer
+/// is enabled.
+fn timer_input(counter: u16, tac: u8) -> bool {
+    let bit = match tac & TAC_CLOCK_SELECT {
+        0 => 9,  // 4096 Hz
+        1 => 3,  // 262144 Hz
+        2 => 5,  // 65536 Hz
+        _ => 7,  // 16384 Hz
+    };
+    tac & TAC_ENABLE > 0 && counter & (1 << bit) > 0
+}
+
+fn set_counter(gb: &mut Gameboy, counter: u16) {
+    let tac = gb.io_ports.read(IO_TAC);
+    let was_high = timer_input(gb.timer.counter, tac);
+    gb.timer.counter = counter;
+    gb.io_ports.write(IO_DIV, (counter >> 8) as u8);
+    if was_high && !timer_input(counter, tac) {
+        increment_tima(gb);
+    }
+}
 
-        let delay = match io_ports.read(IO_TAC) & TAC_CLOCK_SELECT {
-            0 => CLOCK_4096_HZ_PULSE,
-            1 => CLOCK_262_144_HZ_PULSE,
-            2 => CLOCK_65_536_HZ_PULSE,
-            3 => CLOCK_16_384_HZ_PULSE,
-            _ => panic!("Invalid clock select"),
-        };
-        
-        thread::sleep(delay);
-
-        io_ports.add(IO_TIMA, 1);
-
-        let timer_overflow = io_ports.read(IO_TIMA) == 0;
-        if timer_overflow {
-            io_ports.write(IO_TIMA, io_ports.read(IO_TMA));
+fn increment_tima(gb: &mut Gameboy) {
+    let tima = gb.io_ports.read(IO_TIMA).wrapping_add(1);
+    gb.io_ports.write(IO_TIMA, tima);
+    if tima == 0 {
+        gb.timer.overflow = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use crate::gameboy::utils::{test_dmg};
+
+    fn test_gameboy(tac: u8) -> Gameboy {
+        let mut gb = test_dmg(&[]);
+        gb.io_ports.write(IO_IF, 0);
+        gb.io_ports.write(IO_TAC, tac);
+        write_div(&mut gb);
+        gb
+    }
+
+    /// Runs the timer until TIMA has just overflowed.
+    fn run_to_overflow(gb: &mut Gameboy) {
+        gb.io_ports.write(IO_TIMA, 0xff);
+        while !gb.timer.overflow {
+            run_timer(gb);
+        }
+    }
+
+    #[test]
+    fn div() {
+        let mut gb = test_gameboy(0);
+        for _ in 0..64 {
+            run_timer(&mut gb);
+        }
+        assert_eq!(gb.io_ports.read(IO_DIV), 1);
+        write_div(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_DIV), 0);
+    }
+
+    #[test]
+    fn tima_frequency() {
+        // 262144 Hz, so once every 4 machine cycles.
+        let mut gb = test_gameboy(TAC_ENABLE | 0b01);
+        for _ in 0..16 {
+            run_timer(&mut gb);
         }
+        assert_eq!(gb.io_ports.read(IO_TIMA), 4);
+    }
 
-        if timer.ime.load(Ordering::Relaxed) && io_ports.read(IO_IE) & INT_TIMER > 0 && timer_overflow {
-            io_ports.or(IO_IF, INT_TIMER);
-            let (mutex, cvar) = &*timer.interrupt_received;
-            let mut interrupted = mutex.lock().unwrap();
-            *interrupted = true;
-            cvar.notify_one();
+    #[test]
+    fn tima_disabled() {
+        let mut gb = test_gameboy(0b01);
+        for _ in 0..16 {
+            run_timer(&mut gb);
         }
+        assert_eq!(gb.io_ports.read(IO_TIMA), 0);
+    }
+
+    #[test]
+    fn div_write_falling_edge() {
+        let mut gb = test_gameboy(TAC_ENABLE | 0b01);
+        run_timer(&mut gb);
+        run_timer(&mut gb);
+        // Bit 3 of the counter is now set, so resetting it increments TIMA.
+        write_div(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_TIMA), 1);
+    }
+
+    #[test]
+    fn overflow_reload_delayed() {
+        let mut gb = test_gameboy(TAC_ENABLE | 0b01);
+        gb.io_ports.write(IO_TMA, 0x80);
+        run_to_overflow(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_TIMA), 0x00);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_TIMER, 0);
+
+        run_timer(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_TIMA), 0x80);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_TIMER, INT_TIMER);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut gb = test_gameboy(TAC_ENABLE | 0b01);
+        gb.io_ports.write(IO_TMA, 0x80);
+        run_to_overflow(&mut gb);
+        write_tima(&mut gb, 0x10);
+
+        run_timer(&mut gb);
+        assert_eq!(gb.io_ports.read(IO_TIMA), 0x10);
+        assert_eq!(gb.io_ports.read(IO_IF) & INT_TIMER, 0);
+    }
+
+    #[test]
+    fn tima_write_during_reload_ignored() {
+        let mut gb = test_gameboy(TAC_ENABLE | 0b01);
+        gb.io_ports.write(IO_TMA, 0x80);
+        run_to_overflow(&mut gb);
+        run_timer(&mut gb);
+        write_tima(&mut gb, 0x10);
+        assert_eq!(gb.io_ports.read(IO_TIMA), 0x80);
+
+        write_tma(&mut gb, 0x20);
+        assert_eq!(gb.io_ports.read(IO_TIMA), 0x20);
     }
 }
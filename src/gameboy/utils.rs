@@ -1,4 +1,10 @@
 use std::time::{Duration, Instant};
+#[cfg(test)]
+use crate::gameboy::cartridge::{load_cartridge};
+#[cfg(test)]
+use crate::gameboy::gameboy::{Gameboy};
+#[cfg(test)]
+use crate::gameboy::model::{Model};
 
 /// Higher-precision sleep for very short durations. thread::sleep relies on OS scheduling, which
 /// is often too slow.
@@ -12,3 +18,12 @@ pub fn sleep_precise(dur: Duration) {
         }
     }
 }
+
+/// A DMG with the program at the cartridge entry point of an otherwise blank 32KB ROM, for tests
+/// that need a whole system.
+#[cfg(test)]
+pub fn test_dmg(program: &[u8]) -> Gameboy {
+    let mut rom = Box::new([0; 0x8000]);
+    rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+    Gameboy::new(load_cartridge(&*rom, None).unwrap(), Model::Dmg, None)
+}
@@ -0,0 +1,251 @@
+use std::fs::{File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+/// The exact frame rate is the CPU clock divided by the dots in a frame, about 59.73 Hz.
+const FRAME_RATE_NUM: u32 = 4_194_304;
+const FRAME_RATE_DEN: u32 = 70_224;
+
+/// Offsets of the AVI header fields that change as frames get added.
+const AVI_RIFF_SIZE: u64 = 0x04;
+const AVI_TOTAL_FRAMES: u64 = 0x30;
+const AVI_STREAM_LENGTH: u64 = 0x8c;
+const AVI_MOVI_SIZE: u64 = 0xd8;
+/// Size of the AVI header, up to and including the "movi" list type.
+const AVI_HEADER_SIZE: u32 = 0xe0;
+const AVIF_ISINTERLEAVED: u32 = 0x100;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VideoFormat {
+    /// YUV4MPEG2 with full resolution chroma.
+    Y4m,
+    /// Uncompressed 24 bit RGB in an AVI 1.0 file.
+    Avi,
+}
+
+/// Writes every frame to an uncompressed video file, at the Gameboy's frame rate rather than the
+/// host's, so the result plays back at the right speed no matter how fast emulation ran.
+pub struct VideoRecorder {
+    writer: BufWriter<File>,
+    format: VideoFormat,
+    width: u32,
+    height: u32,
+    frames: u32,
+}
+
+impl VideoRecorder {
+    /// Files ending in .avi are written as AVI, and everything else as Y4M.
+    pub fn create(filepath: &str, width: u32, height: u32) -> Result<Self, String> {
+        let format = if filepath.to_lowercase().ends_with(".avi") { VideoFormat::Avi } else { VideoFormat::Y4m };
+        let file = File::create(filepath)
+            .map_err(|e| format!("Failed to create video file '{filepath}': {e}"))?;
+        let mut recorder = Self {
+            writer: BufWriter::new(file),
+            format,
+            width,
+            height,
+            frames: 0,
+        };
+        let header = match format {
+            VideoFormat::Y4m => format!("YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+                                        width, height, FRAME_RATE_NUM, FRAME_RATE_DEN).into_bytes(),
+            VideoFormat::Avi => avi_header(width, height),
+        };
+        recorder.writer.write_all(&header)
+            .map_err(|e| format!("Failed to write video file '{filepath}': {e}"))?;
+        Ok(recorder)
+    }
+
+    /// Adds a frame of RGB pixels, stored row by row.
+    pub fn write_frame(&mut self, pixels: &[(u8, u8, u8)]) -> Result<(), String> {
+        assert_eq!(pixels.len(), (self.width * self.height) as usize, "Expected {} pixels", self.width * self.height);
+        let result = match self.format {
+            VideoFormat::Y4m => self.write_y4m_frame(pixels),
+            VideoFormat::Avi => {
+                // AVI 1.0 stores sizes in 32 bits.
+                let file_size = AVI_HEADER_SIZE as u64 + (self.frames as u64 + 1) * (8 + avi_frame_size(self.width, self.height) as u64);
+                if file_size > u32::MAX as u64 {
+                    return Err("AVI files can't be bigger than 4GB".to_string());
+                }
+                self.write_avi_frame(pixels)
+            },
+        };
+        result.map_err(|e| format!("Failed to write video frame: {e}"))?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn write_y4m_frame(&mut self, pixels: &[(u8, u8, u8)]) -> std::io::Result<()> {
+        // Each frame has a full Y plane, followed by full U and V planes since the chroma isn't
+        // subsampled. The conversion is BT.601 with studio swing, which is what Y4M readers expect.
+        let mut frame = Vec::with_capacity(6 + pixels.len() * 3);
+        frame.extend_from_slice(b"FRAME\n");
+        frame.extend(pixels.iter().map(|&(r, g, b)| {
+            (((66 * r as i32 + 129 * g as i32 + 25 * b as i32 + 128) >> 8) + 16) as u8
+        }));
+        frame.extend(pixels.iter().map(|&(r, g, b)| {
+            (((-38 * r as i32 - 74 * g as i32 + 112 * b as i32 + 128) >> 8) + 128) as u8
+        }));
+        frame.extend(pixels.iter().map(|&(r, g, b)| {
+            (((112 * r as i32 - 94 * g as i32 - 18 * b as i32 + 128) >> 8) + 128) as u8
+        }));
+        self.writer.write_all(&frame)
+    }
+
+    fn write_avi_frame(&mut self, pixels: &[(u8, u8, u8)]) -> std::io::Result<()> {
+        let frame_size = avi_frame_size(self.width, self.height);
+
+        // Rows are stored bottom to top, with each pixel in BGR order.
+        let mut chunk = Vec::with_capacity(8 + frame_size as usize);
+        chunk.extend_from_slice(b"00db");
+        chunk.extend_from_slice(&frame_size.to_le_bytes());
+        for row in pixels.chunks(self.width as usize).rev() {
+            let row_start = chunk.len();
+            for &(r, g, b) in row {
+                chunk.extend_from_slice(&[b, g, r]);
+            }
+            chunk.resize(row_start + avi_stride(self.width) as usize, 0);
+        }
+
+        // The sizes and frame counts in the header are kept up to date after every frame, so the
+        // file can be played even if the emulator is closed without warning.
+        let frames = self.frames + 1;
+        let movi_size = 4 + frames * (8 + frame_size);
+        let riff_size = AVI_HEADER_SIZE - 8 + movi_size - 4;
+        self.writer.write_all(&chunk)?;
+        for &(offset, value) in [(AVI_RIFF_SIZE, riff_size), (AVI_TOTAL_FRAMES, frames),
+                                 (AVI_STREAM_LENGTH, frames), (AVI_MOVI_SIZE, movi_size)].iter() {
+            self.writer.seek(SeekFrom::Start(offset))?;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/// Bytes per row of an AVI frame, which are padded to a multiple of 4.
+fn avi_stride(width: u32) -> u32 {
+    (width * 3 + 3) & !3
+}
+
+fn avi_frame_size(width: u32, height: u32) -> u32 {
+    avi_stride(width) * height
+}
+
+/// Everything in an AVI file before the first frame, with the sizes and frame counts set to 0.
+fn avi_header(width: u32, height: u32) -> Vec<u8> {
+    let frame_size = avi_frame_size(width, height);
+    let mut h = vec!();
+    let u32_le = |h: &mut Vec<u8>, value: u32| h.extend_from_slice(&value.to_le_bytes());
+
+    h.extend_from_slice(b"RIFF");
+    u32_le(&mut h, 0);
+    h.extend_from_slice(b"AVI LIST");
+    u32_le(&mut h, 0xc0);
+    h.extend_from_slice(b"hdrlavih");
+    u32_le(&mut h, 56);
+    // Microseconds per frame, max bytes per second, padding, flags, total frames, initial frames,
+    // streams, suggested buffer size, width, height, and 4 reserved fields.
+    u32_le(&mut h, (1_000_000u64 * FRAME_RATE_DEN as u64 / FRAME_RATE_NUM as u64) as u32);
+    u32_le(&mut h, (frame_size as u64 * FRAME_RATE_NUM as u64 / FRAME_RATE_DEN as u64) as u32);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, AVIF_ISINTERLEAVED);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, 1);
+    u32_le(&mut h, frame_size);
+    u32_le(&mut h, width);
+    u32_le(&mut h, height);
+    h.extend_from_slice(&[0; 16]);
+
+    h.extend_from_slice(b"LIST");
+    u32_le(&mut h, 0x74);
+    h.extend_from_slice(b"strlstrh");
+    u32_le(&mut h, 56);
+    // Stream type, handler, flags, priority and language, initial frames, scale, rate, start,
+    // length, suggested buffer size, quality (-1 for default), sample size, and the frame rectangle.
+    h.extend_from_slice(b"vidsDIB ");
+    u32_le(&mut h, 0);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, FRAME_RATE_DEN);
+    u32_le(&mut h, FRAME_RATE_NUM);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, 0);
+    u32_le(&mut h, frame_size);
+    u32_le(&mut h, 0xffff_ffff);
+    u32_le(&mut h, frame_size);
+    h.extend_from_slice(&[0, 0, 0, 0]);
+    h.extend_from_slice(&(width as u16).to_le_bytes());
+    h.extend_from_slice(&(height as u16).to_le_bytes());
+
+    h.extend_from_slice(b"strf");
+    u32_le(&mut h, 40);
+    // BITMAPINFOHEADER: size, width, height, planes and bit count, compression (BI_RGB), image
+    // size, pixels per meter on both axes, and colors used and important.
+    u32_le(&mut h, 40);
+    u32_le(&mut h, width);
+    u32_le(&mut h, height);
+    h.extend_from_slice(&1u16.to_le_bytes());
+    h.extend_from_slice(&24u16.to_le_bytes());
+    u32_le(&mut h, 0);
+    u32_le(&mut h, frame_size);
+    h.extend_from_slice(&[0; 16]);
+
+    h.extend_from_slice(b"LIST");
+    u32_le(&mut h, 4);
+    h.extend_from_slice(b"movi");
+    assert_eq!(h.len(), AVI_HEADER_SIZE as usize);
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use std::env;
+    use std::fs;
+
+    fn u32_at(bytes: &[u8], offset: u64) -> u32 {
+        let offset = offset as usize;
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    fn record(extension: &str, frames: usize) -> Vec<u8> {
+        let filepath = env::temp_dir().join(format!("gbemu-video-{}.{}", std::process::id(), extension));
+        let filepath = filepath.to_str().unwrap();
+        {
+            let mut recorder = VideoRecorder::create(filepath, 2, 2).unwrap();
+            for _ in 0..frames {
+                recorder.write_frame(&[(0xff, 0xff, 0xff), (0, 0, 0), (0xff, 0, 0), (0, 0, 0xff)]).unwrap();
+            }
+        }
+        let bytes = fs::read(filepath).unwrap();
+        fs::remove_file(filepath).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn y4m() {
+        let bytes = record("y4m", 2);
+        let header = b"YUV4MPEG2 W2 H2 F4194304:70224 Ip A1:1 C444\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let frame = &bytes[header.len()..];
+        assert_eq!(frame.len(), 2 * (6 + 12));
+        // White and black at the limits of studio swing.
+        assert_eq!(&frame[..8], b"FRAME\n\xeb\x10");
+    }
+
+    #[test]
+    fn avi() {
+        let bytes = record("avi", 3);
+        // Each row is padded from 6 to 8 bytes.
+        let frame_size = 2 * 8;
+        assert_eq!(bytes.len(), AVI_HEADER_SIZE as usize + 3 * (8 + frame_size));
+        assert_eq!(u32_at(&bytes, AVI_RIFF_SIZE) as usize, bytes.len() - 8);
+        assert_eq!(u32_at(&bytes, AVI_TOTAL_FRAMES), 3);
+        assert_eq!(u32_at(&bytes, AVI_STREAM_LENGTH), 3);
+        assert_eq!(u32_at(&bytes, AVI_MOVI_SIZE) as usize, bytes.len() - AVI_MOVI_SIZE as usize - 4);
+        assert_eq!(&bytes[0xe0..0xe4], b"00db");
+        // The bottom row comes first, in BGR order.
+        assert_eq!(&bytes[0xe8..0xee], &[0, 0, 0xff, 0xff, 0, 0]);
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{HashMap};
+use std::fs;
+use sdl2::{GameControllerSubsystem};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::{Event};
+use sdl2::keyboard::{KeyboardState, Scancode};
+use crate::gameboy::{*};
+
+/// How far a stick has to be pushed before it counts as pressed.
+const AXIS_THRESHOLD: i16 = 16384;
+
+/// A physical input that can be bound to an action.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Input {
+    Key(Scancode),
+    Button(Button),
+    /// A controller axis pushed past AXIS_THRESHOLD, towards the positive end if the flag is set.
+    Axis(Axis, bool),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// One of the Gameboy's buttons, as its CONTROLLER_DATA flag.
+    Joypad(u8),
+    ToggleLcd,
+    ToggleSprites,
+    ToggleBackground,
+    ToggleWindow,
+    Pause,
+    FrameAdvance,
+    /// Runs uncapped while held.
+    FastForward,
+    SpeedUp,
+    SlowDown,
+    Screenshot,
+    /// Switches to the next DMG palette.
+    NextPalette,
+}
+
+/// Maps keyboard keys and controller buttons/axes to actions. Several inputs can be bound to the
+/// same action.
+///
+/// A bindings file has one `<input> = <action>` binding per line, with `#` starting a comment.
+/// Inputs are `key:<SDL scancode name>`, `button:<SDL controller button name>`, or
+/// `axis:<SDL controller axis name><+ or ->`, e.g. `key:Return`, `button:dpleft` or `axis:leftx-`.
+/// Actions are the Gameboy buttons (`a`, `b`, `select`, `start`, `right`, `left`, `up`, `down`),
+/// `toggle_lcd`, `toggle_sprites`, `toggle_background`, `toggle_window`, `pause`, `frame_advance`,
+/// `fast_forward`, `speed_up`, `slow_down`, `screenshot` and `next_palette`.
+pub struct Bindings {
+    bindings: Vec<(Input, Action)>,
+    /// The last position of each axis, by controller instance ID, to tell when an axis gets
+    /// pushed past AXIS_THRESHOLD.
+    axis_positions: HashMap<(u32, Axis), i16>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let bindings = vec!(
+            (Input::Key(Scancode::Right), Action::Joypad(CONTROLLER_DATA_RIGHT)),
+            (Input::Key(Scancode::Left), Action::Joypad(CONTROLLER_DATA_LEFT)),
+            (Input::Key(Scancode::Up), Action::Joypad(CONTROLLER_DATA_UP)),
+            (Input::Key(Scancode::Down), Action::Joypad(CONTROLLER_DATA_DOWN)),
+            (Input::Key(Scancode::X), Action::Joypad(CONTROLLER_DATA_A)),
+            (Input::Key(Scancode::Z), Action::Joypad(CONTROLLER_DATA_B)),
+            (Input::Key(Scancode::Backspace), Action::Joypad(CONTROLLER_DATA_SE)),
+            (Input::Key(Scancode::Return), Action::Joypad(CONTROLLER_DATA_ST)),
+            (Input::Key(Scancode::L), Action::ToggleLcd),
+            (Input::Key(Scancode::S), Action::ToggleSprites),
+            (Input::Key(Scancode::B), Action::ToggleBackground),
+            (Input::Key(Scancode::W), Action::ToggleWindow),
+            (Input::Key(Scancode::P), Action::Pause),
+            (Input::Key(Scancode::N), Action::FrameAdvance),
+            (Input::Key(Scancode::Tab), Action::FastForward),
+            (Input::Key(Scancode::Equals), Action::SpeedUp),
+            (Input::Key(Scancode::Minus), Action::SlowDown),
+            (Input::Key(Scancode::F12), Action::Screenshot),
+            (Input::Key(Scancode::C), Action::NextPalette),
+
+            (Input::Button(Button::DPadRight), Action::Joypad(CONTROLLER_DATA_RIGHT)),
+            (Input::Button(Button::DPadLeft), Action::Joypad(CONTROLLER_DATA_LEFT)),
+            (Input::Button(Button::DPadUp), Action::Joypad(CONTROLLER_DATA_UP)),
+            (Input::Button(Button::DPadDown), Action::Joypad(CONTROLLER_DATA_DOWN)),
+            (Input::Axis(Axis::LeftX, true), Action::Joypad(CONTROLLER_DATA_RIGHT)),
+            (Input::Axis(Axis::LeftX, false), Action::Joypad(CONTROLLER_DATA_LEFT)),
+            (Input::Axis(Axis::LeftY, false), Action::Joypad(CONTROLLER_DATA_UP)),
+            (Input::Axis(Axis::LeftY, true), Action::Joypad(CONTROLLER_DATA_DOWN)),
+            (Input::Button(Button::A), Action::Joypad(CONTROLLER_DATA_A)),
+            (Input::Button(Button::B), Action::Joypad(CONTROLLER_DATA_B)),
+            (Input::Button(Button::Back), Action::Joypad(CONTROLLER_DATA_SE)),
+            (Input::Button(Button::Start), Action::Joypad(CONTROLLER_DATA_ST)),
+            (Input::Button(Button::RightShoulder), Action::FastForward),
+        );
+        Self::new(bindings)
+    }
+}
+
+impl Bindings {
+    fn new(bindings: Vec<(Input, Action)>) -> Self {
+        Self {
+            bindings,
+            axis_positions: HashMap::new(),
+        }
+    }
+
+    /// Reads bindings from a file. These replace the default bindings entirely.
+    pub fn load(filepath: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(filepath)
+            .map_err(|e| format!("Failed to open bindings file '{filepath}': {e}"))?;
+        let mut bindings = vec!();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let binding = parse_binding(line)
+                .map_err(|e| format!("{}:{}: {}", filepath, i + 1, e))?;
+            bindings.push(binding);
+        }
+        Ok(Self::new(bindings))
+    }
+
+    /// Actions to run in response to a key or button being pressed, or an axis being pushed.
+    pub fn triggered(&mut self, event: &Event) -> Vec<Action> {
+        let input = match *event {
+            Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => Input::Key(scancode),
+            Event::ControllerButtonDown { button, .. } => Input::Button(button),
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                let previous = self.axis_positions.insert((which, axis), value).unwrap_or(0);
+                let pushed = |value: i16, positive: bool| {
+                    if positive { value > AXIS_THRESHOLD } else { value < -AXIS_THRESHOLD }
+                };
+                match [true, false].iter().find(|&&positive| pushed(value, positive) && !pushed(previous, positive)) {
+                    Some(&positive) => Input::Axis(axis, positive),
+                    None => return vec!(),
+                }
+            },
+            _ => return vec!(),
+        };
+        self.bindings.iter()
+            .filter(|(bound, _)| *bound == input)
+            .map(|&(_, action)| action)
+            .collect()
+    }
+
+    /// Which Gameboy buttons are being held, in the format of Gameboy::controller_data.
+    pub fn controller_data(&self, kb_state: &KeyboardState, controllers: &Controllers) -> u8 {
+        let mut cont_data = 0b1111_1111;
+        for &(input, action) in self.bindings.iter() {
+            if let Action::Joypad(button) = action {
+                if is_held(input, kb_state, controllers) {
+                    cont_data &= !button;
+                }
+            }
+        }
+        cont_data
+    }
+
+    /// Whether any of the inputs bound to the action are being held.
+    pub fn is_held(&self, action: Action, kb_state: &KeyboardState, controllers: &Controllers) -> bool {
+        self.bindings.iter()
+            .any(|&(input, bound)| bound == action && is_held(input, kb_state, controllers))
+    }
+}
+
+fn is_held(input: Input, kb_state: &KeyboardState, controllers: &Controllers) -> bool {
+    match input {
+        Input::Key(scancode) => kb_state.is_scancode_pressed(scancode),
+        _ => controllers.is_held(input),
+    }
+}
+
+fn parse_binding(line: &str) -> Result<(Input, Action), String> {
+    let (input, action) = line.split_once('=')
+        .ok_or(format!("Expected '<input> = <action>', got '{line}'"))?;
+    // The action is checked first, since input names have to be looked up through SDL.
+    let action = parse_action(action.trim())?;
+    let input = parse_input(input.trim())?;
+    Ok((input, action))
+}
+
+fn parse_action(name: &str) -> Result<Action, String> {
+    match name {
+        "a" => Ok(Action::Joypad(CONTROLLER_DATA_A)),
+        "b" => Ok(Action::Joypad(CONTROLLER_DATA_B)),
+        "select" => Ok(Action::Joypad(CONTROLLER_DATA_SE)),
+        "start" => Ok(Action::Joypad(CONTROLLER_DATA_ST)),
+        "right" => Ok(Action::Joypad(CONTROLLER_DATA_RIGHT)),
+        "left" => Ok(Action::Joypad(CONTROLLER_DATA_LEFT)),
+        "up" => Ok(Action::Joypad(CONTROLLER_DATA_UP)),
+        "down" => Ok(Action::Joypad(CONTROLLER_DATA_DOWN)),
+        "toggle_lcd" => Ok(Action::ToggleLcd),
+        "toggle_sprites" => Ok(Action::ToggleSprites),
+        "toggle_background" => Ok(Action::ToggleBackground),
+        "toggle_window" => Ok(Action::ToggleWindow),
+        "pause" => Ok(Action::Pause),
+        "frame_advance" => Ok(Action::FrameAdvance),
+        "fast_forward" => Ok(Action::FastForward),
+        "speed_up" => Ok(Action::SpeedUp),
+        "slow_down" => Ok(Action::SlowDown),
+        "screenshot" => Ok(Action::Screenshot),
+        "next_palette" => Ok(Action::NextPalette),
+        _ => Err(format!("Unknown action '{name}'")),
+    }
+}
+
+fn parse_input(input: &str) -> Result<Input, String> {
+    let (kind, name) = input.split_once(':')
+        .ok_or(format!("Expected 'key:', 'button:' or 'axis:' before '{input}'"))?;
+    match kind {
+        "key" => Scancode::from_name(name)
+            .map(Input::Key)
+            .ok_or(format!("Unknown key '{name}'")),
+        "button" => Button::from_string(name)
+            .map(Input::Button)
+            .ok_or(format!("Unknown controller button '{name}'")),
+        "axis" => {
+            let (axis, positive) = match name.strip_suffix('+') {
+                Some(axis) => (axis, true),
+                None => (name.strip_suffix('-').ok_or(format!("Expected '+' or '-' after axis '{name}'"))?, false),
+            };
+            Axis::from_string(axis)
+                .map(|axis| Input::Axis(axis, positive))
+                .ok_or(format!("Unknown controller axis '{axis}'"))
+        },
+        _ => Err(format!("Unknown input type '{kind}'")),
+    }
+}
+
+/// The game controllers currently plugged in.
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+}
+
+impl Controllers {
+    /// SDL sends a device added event for every controller that's already plugged in on startup,
+    /// so these get opened by handle_event as well.
+    pub fn new(subsystem: GameControllerSubsystem) -> Self {
+        Self {
+            subsystem,
+            controllers: vec!(),
+        }
+    }
+
+    /// Opens controllers as they're plugged in, and closes them when they're unplugged.
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.subsystem.open(which) {
+                    Ok(controller) => {
+                        println!("Connected controller '{}'", controller.name());
+                        self.controllers.push(controller);
+                    },
+                    Err(err) => eprintln!("Failed to open controller {}: {}", which, err),
+                }
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.retain(|controller| {
+                    let removed = controller.instance_id() == which;
+                    if removed {
+                        println!("Disconnected controller '{}'", controller.name());
+                    }
+                    !removed
+                });
+            },
+            _ => {},
+        }
+    }
+
+    /// Whether a controller button or axis is held on any of the controllers.
+    fn is_held(&self, input: Input) -> bool {
+        self.controllers.iter().any(|controller| match input {
+            Input::Button(button) => controller.button(button),
+            Input::Axis(axis, true) => controller.axis(axis) > AXIS_THRESHOLD,
+            Input::Axis(axis, false) => controller.axis(axis) < -AXIS_THRESHOLD,
+            Input::Key(_) => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+    use std::env;
+    use sdl2::keyboard::{Mod};
+
+    #[test]
+    fn actions() {
+        assert_eq!(parse_action("start"), Ok(Action::Joypad(CONTROLLER_DATA_ST)));
+        assert_eq!(parse_action("toggle_sprites"), Ok(Action::ToggleSprites));
+        assert_eq!(parse_action("fast_forward"), Ok(Action::FastForward));
+        assert!(parse_action("jump").is_err());
+    }
+
+    #[test]
+    fn malformed_bindings() {
+        assert!(parse_binding("key:Return").is_err());
+        assert!(parse_binding("key:Return = jump").is_err());
+        assert!(parse_binding("Return = start").is_err());
+    }
+
+    #[test]
+    fn inputs() {
+        assert_eq!(parse_input("key:Return"), Ok(Input::Key(Scancode::Return)));
+        assert_eq!(parse_input("button:dpleft"), Ok(Input::Button(Button::DPadLeft)));
+        assert_eq!(parse_input("axis:leftx+"), Ok(Input::Axis(Axis::LeftX, true)));
+        assert_eq!(parse_input("axis:lefty-"), Ok(Input::Axis(Axis::LeftY, false)));
+        assert!(parse_input("axis:leftx").is_err());
+        assert!(parse_input("key:NotAKey").is_err());
+        assert!(parse_input("button:NotAButton").is_err());
+    }
+
+    fn axis_motion(axis: Axis, value: i16) -> Event {
+        Event::ControllerAxisMotion { timestamp: 0, which: 0, axis, value }
+    }
+
+    #[test]
+    fn load_and_trigger() {
+        let filepath = env::temp_dir().join(format!("gbemu-bindings-{}.txt", std::process::id()));
+        fs::write(&filepath, "key:F12 = screenshot # comment\nbutton:start = pause\naxis:triggerright+ = screenshot\n").unwrap();
+        let bindings = Bindings::load(filepath.to_str().unwrap());
+        fs::remove_file(&filepath).unwrap();
+        let mut bindings = bindings.unwrap();
+
+        let key = Event::KeyDown {
+            timestamp: 0, window_id: 0, keycode: None, scancode: Some(Scancode::F12), keymod: Mod::NOMOD, repeat: false,
+        };
+        assert_eq!(bindings.triggered(&key), [Action::Screenshot]);
+        let button = Event::ControllerButtonDown { timestamp: 0, which: 0, button: Button::Start };
+        assert_eq!(bindings.triggered(&button), [Action::Pause]);
+
+        // Axes trigger once when they cross the threshold, and again only after being let go.
+        assert_eq!(bindings.triggered(&axis_motion(Axis::TriggerRight, 1000)), []);
+        assert_eq!(bindings.triggered(&axis_motion(Axis::TriggerRight, 20000)), [Action::Screenshot]);
+        assert_eq!(bindings.triggered(&axis_motion(Axis::TriggerRight, 30000)), []);
+        assert_eq!(bindings.triggered(&axis_motion(Axis::TriggerRight, 0)), []);
+        assert_eq!(bindings.triggered(&axis_motion(Axis::TriggerRight, 20000)), [Action::Screenshot]);
+        assert_eq!(bindings.triggered(&axis_motion(Axis::LeftX, 20000)), []);
+    }
+}
@@ -0,0 +1,197 @@
+/// Colors the classic DMG screen shows for its 4 shades, from lightest to darkest, used as control
+/// points for the DMG tint.
+const DMG_SHADES: [(u8, u8, u8); 4] = [(0x9b, 0xbc, 0x0f), (0x8b, 0xac, 0x0f), (0x30, 0x62, 0x30), (0x0f, 0x38, 0x0f)];
+
+/// The gaps between pixels in the grid keep this much of the pixel's brightness, out of 256.
+const GRID_BRIGHTNESS: u32 = 192;
+
+/// The smallest window scale at which there's room for a grid.
+pub const MIN_GRID_SCALE: u32 = 3;
+
+/// How the LCD's slow response blends frames into each other, which is what makes flickering
+/// sprites look see-through on a real Gameboy.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Ghosting {
+    None,
+    /// Shows the average of each frame and the one before it.
+    FrameBlend,
+    /// Each frame fades out exponentially, keeping this fraction of its brightness on every frame
+    /// that follows.
+    Exponential(f32),
+}
+
+impl Ghosting {
+    /// Either "none", "blend", or the fraction of each frame that stays on screen for the next one.
+    pub fn new(name: &str) -> Result<Self, String> {
+        match name {
+            "none" => Ok(Ghosting::None),
+            "blend" => Ok(Ghosting::FrameBlend),
+            _ => match name.parse::<f32>() {
+                Ok(persistence) if (0.0..1.0).contains(&persistence) => Ok(Ghosting::Exponential(persistence)),
+                _ => Err(format!("Expected none, blend, or a number from 0 up to 1 for ghosting, got '{name}'")),
+            },
+        }
+    }
+}
+
+/// Post-processing that makes frames look more like they would on a real LCD, applied right
+/// before they're presented.
+pub struct LcdEffects {
+    ghosting: Ghosting,
+    dmg_tint: bool,
+    /// The previous frame for frame blending, or what's left on screen of all the previous
+    /// frames for exponential ghosting.
+    previous: Vec<(f32, f32, f32)>,
+}
+
+impl LcdEffects {
+    pub fn new(ghosting: Ghosting, dmg_tint: bool) -> Self {
+        Self {
+            ghosting,
+            dmg_tint,
+            previous: vec!(),
+        }
+    }
+
+    /// Applies the DMG tint and ghosting to a frame at the LCD's own resolution.
+    pub fn apply(&mut self, frame: &mut [(u8, u8, u8)]) {
+        if self.dmg_tint {
+            for pixel in frame.iter_mut() {
+                *pixel = dmg_tint(*pixel);
+            }
+        }
+
+        if self.ghosting == Ghosting::None {
+            return;
+        }
+        if self.previous.len() != frame.len() {
+            self.previous = frame.iter().map(|&pixel| to_f32(pixel)).collect();
+        }
+        for (pixel, previous) in frame.iter_mut().zip(self.previous.iter_mut()) {
+            let current = to_f32(*pixel);
+            let (shown, remembered) = match self.ghosting {
+                Ghosting::None => unreachable!(),
+                Ghosting::FrameBlend => (mix(*previous, current, 0.5), current),
+                Ghosting::Exponential(persistence) => {
+                    let shown = mix(*previous, current, 1.0 - persistence);
+                    (shown, shown)
+                },
+            };
+            *pixel = (shown.0.round() as u8, shown.1.round() as u8, shown.2.round() as u8);
+            *previous = remembered;
+        }
+    }
+}
+
+fn to_f32((r, g, b): (u8, u8, u8)) -> (f32, f32, f32) {
+    (r as f32, g as f32, b as f32)
+}
+
+fn mix(a: (f32, f32, f32), b: (f32, f32, f32), amount_of_b: f32) -> (f32, f32, f32) {
+    let mix = |a: f32, b: f32| a + (b - a) * amount_of_b;
+    (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+}
+
+/// Maps a color to the green of the DMG screen by its brightness. The DMG's response isn't
+/// linear, so the brightness is mapped through its 4 shades rather than straight from the
+/// lightest to the darkest.
+fn dmg_tint((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+    // Position between the lightest (0) and the darkest (3) shade, in 1/255 steps.
+    let position = (255 - luma) * 3;
+    let (shade, fraction) = ((position / 255) as usize, position % 255);
+    if shade == 3 {
+        return DMG_SHADES[3];
+    }
+    let (from, to) = (DMG_SHADES[shade], DMG_SHADES[shade + 1]);
+    let mix = |a: u8, b: u8| ((a as u32 * (255 - fraction) + b as u32 * fraction + 127) / 255) as u8;
+    (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
+
+/// Scales a frame up so that each LCD pixel is `scale` pixels wide and high, and darkens the
+/// last row and column of each one to leave a grid of gaps between them. The frame may already
+/// have been scaled up by `factor` (by a filter), in which case it's resampled to fit.
+pub fn draw_grid(frame: &[(u8, u8, u8)], width: usize, factor: usize, scale: usize) -> Vec<(u8, u8, u8)> {
+    let height = frame.len() / width;
+    let (out_width, out_height) = (width / factor * scale, height / factor * scale);
+    let mut out = Vec::with_capacity(out_width * out_height);
+    for y in 0..out_height {
+        let row = &frame[(y * factor / scale) * width..][..width];
+        for x in 0..out_width {
+            let (r, g, b) = row[x * factor / scale];
+            let in_gap = x % scale == scale - 1 || y % scale == scale - 1;
+            out.push(if in_gap {
+                let darken = |c: u8| (c as u32 * GRID_BRIGHTNESS / 256) as u8;
+                (darken(r), darken(g), darken(b))
+            } else {
+                (r, g, b)
+            });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*};
+
+    const W: (u8, u8, u8) = (0xff, 0xff, 0xff);
+    const K: (u8, u8, u8) = (0, 0, 0);
+
+    #[test]
+    fn parse_ghosting() {
+        assert_eq!(Ghosting::new("blend"), Ok(Ghosting::FrameBlend));
+        assert_eq!(Ghosting::new("0.5"), Ok(Ghosting::Exponential(0.5)));
+        assert!(Ghosting::new("1").is_err());
+        assert!(Ghosting::new("lots").is_err());
+    }
+
+    #[test]
+    fn frame_blend() {
+        let mut effects = LcdEffects::new(Ghosting::FrameBlend, false);
+        let mut frame = [W, K];
+        effects.apply(&mut frame);
+        assert_eq!(frame, [W, K]);
+        // A sprite that flickers on and off every frame shows at half brightness.
+        for _ in 0..4 {
+            let mut frame = [K, W];
+            effects.apply(&mut frame);
+            assert_eq!(frame, [(0x80, 0x80, 0x80); 2]);
+            let mut frame = [W, K];
+            effects.apply(&mut frame);
+            assert_eq!(frame, [(0x80, 0x80, 0x80); 2]);
+        }
+    }
+
+    #[test]
+    fn exponential_ghosting() {
+        let mut effects = LcdEffects::new(Ghosting::Exponential(0.5), false);
+        effects.apply(&mut [W]);
+        let fades: Vec<u8> = (0..3).map(|_| {
+            let mut frame = [K];
+            effects.apply(&mut frame);
+            frame[0].0
+        }).collect();
+        assert_eq!(fades, [0x80, 0x40, 0x20]);
+    }
+
+    #[test]
+    fn dmg_tint_follows_shades() {
+        assert_eq!(dmg_tint(W), DMG_SHADES[0]);
+        assert_eq!(dmg_tint((0xaa, 0xaa, 0xaa)), DMG_SHADES[1]);
+        assert_eq!(dmg_tint((0x55, 0x55, 0x55)), DMG_SHADES[2]);
+        assert_eq!(dmg_tint(K), DMG_SHADES[3]);
+    }
+
+    #[test]
+    fn grid() {
+        let grid = draw_grid(&[W, K], 2, 1, 3);
+        assert_eq!(grid.len(), 6 * 3);
+        let gap = (GRID_BRIGHTNESS as u8 - 1, GRID_BRIGHTNESS as u8 - 1, GRID_BRIGHTNESS as u8 - 1);
+        assert_eq!(&grid[..6], &[W, W, gap, K, K, K]);
+        assert_eq!(&grid[12..18], &[gap, gap, gap, K, K, K]);
+
+        // A frame that was filtered to twice the size has the same grid.
+        assert_eq!(draw_grid(&[W, W, K, K, W, W, K, K], 4, 2, 3), grid);
+    }
+}
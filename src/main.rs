@@ -1,71 +1,180 @@
 mod gameboy;
+mod input;
+mod config_file;
+mod lcd_effects;
 
 extern crate sdl2;
 
 use std::thread;
 use std::time::{Duration};
 use std::fs;
+use std::path::{Path};
 use std::num::{Wrapping};
-use std::sync::{Arc};
 use std::sync::atomic::{Ordering};
 use sdl2::event::{Event, WindowEvent};
-use sdl2::keyboard::{Keycode, Scancode};
 use sdl2::pixels::{PixelFormatEnum};
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, Store, StoreConst, StoreOption};
 use crate::gameboy::{*};
+use crate::input::{*};
+use crate::config_file::{*};
+use crate::lcd_effects::{*};
+
+/// How often the UI checks for a new frame from the PPU, in between handling input.
+const FRAME_POLL_INTERVAL: Duration = Duration::from_millis(1);
 
 struct Config {
     pub rom_filepath: String,
     pub scale: u32,
-    pub palette: [(u8,u8,u8); 4],
+    pub palette: String,
     pub debug_show_speed: bool,
     pub breakpoints: Vec<u16>,
     pub vram_viewer: bool,
+    pub boot_rom_filepath: Option<String>,
+    pub model: Option<Model>,
+    pub color_correction: bool,
+    pub bindings_filepath: Option<String>,
+    pub record_filepath: Option<String>,
+    pub play_filepath: Option<String>,
+    pub speed: Speed,
+    pub screenshot_at_frame: Option<(u64, String)>,
+    pub screenshot_scale: u32,
+    pub dump_frames_dir: Option<String>,
+    pub record_av_filepath: Option<String>,
+    pub save_dir: Option<String>,
+    pub screenshot_dir: Option<String>,
+    pub filter: Filter,
+    pub ghosting: Ghosting,
+    pub lcd_grid: bool,
+    pub dmg_tint: bool,
 }
 
 impl Config {
     pub fn new() -> Result<Self, String> {
         let mut rom_filepath = String::from("roms/hello-world.gb");
-        let mut scale = 4;
-        let mut palette_str = String::from("grey");
-        let mut debug_show_speed = false;
-        let mut breakpoints_str = String::from("");
-        let mut vram_viewer = false;
+        let mut config_filepath: Option<String> = None;
+        let mut scale: Option<u32> = None;
+        let mut palette: Option<String> = None;
+        let mut debug_show_speed: Option<bool> = None;
+        let mut breakpoints_str: Option<String> = None;
+        let mut vram_viewer: Option<bool> = None;
+        let mut boot_rom_filepath: Option<String> = None;
+        let mut model_str: Option<String> = None;
+        let mut color_correction: Option<bool> = None;
+        let mut bindings_filepath: Option<String> = None;
+        let mut record_filepath: Option<String> = None;
+        let mut play_filepath = String::from("");
+        let mut speed_str: Option<String> = None;
+        let mut screenshot_frame: Option<u64> = None;
+        let mut screenshot_out: Option<String> = None;
+        let mut screenshot_scale: Option<u32> = None;
+        let mut dump_frames_dir: Option<String> = None;
+        let mut record_av_filepath: Option<String> = None;
+        let mut save_dir: Option<String> = None;
+        let mut screenshot_dir: Option<String> = None;
+        let mut filter_str: Option<String> = None;
+        let mut ghosting_str: Option<String> = None;
+        let mut lcd_grid: Option<bool> = None;
+        let mut dmg_tint: Option<bool> = None;
 
         {
             let mut ap = ArgumentParser::new();
             ap.refer(&mut rom_filepath)
                 .add_argument("rom_filepath", Store, "Path to a Gameboy ROM file");
+            ap.refer(&mut config_filepath)
+                .add_option(&["-c", "--config"], StoreOption, "Path to a config file (defaults to gbemu/config.ini in the user config directory)");
             ap.refer(&mut scale)
-                .add_option(&["-s", "--scale"], Store, "Scale factor for the display (e.g. 1x, 2x, 3x...)");
-            ap.refer(&mut palette_str)
-                .add_option(&["-p", "--palette"], Store, "Configure color palette");
+                .add_option(&["-s", "--scale"], StoreOption, "Scale factor for the display (e.g. 1x, 2x, 3x...)");
+            ap.refer(&mut palette)
+                .add_option(&["-p", "--palette"], StoreOption, "Color palette for DMG games (grey, red, green, blue, auto for the palette the CGB boot ROM picks for the game, or a path to a palette file)");
             ap.refer(&mut debug_show_speed)
-                .add_option(&["-d", "--debug-speed"], StoreTrue, "Write CPU and PPU speed to console");
+                .add_option(&["-d", "--debug-speed"], StoreConst(Some(true)), "Write CPU and PPU speed to console")
+                .add_option(&["--no-debug-speed"], StoreConst(Some(false)), "Don't write CPU and PPU speed to console");
             ap.refer(&mut breakpoints_str)
-                .add_option(&["-b", "--breakpoints"], Store, "List of addresses (in hexadecimal) to set as breakpoints for debugging, separated by commas");
+                .add_option(&["-b", "--breakpoints"], StoreOption, "List of addresses (in hexadecimal) to set as breakpoints for debugging, separated by commas");
             ap.refer(&mut vram_viewer)
-                .add_option(&["-v", "--vram"], StoreTrue, "Display the VRAM viewer");
+                .add_option(&["-v", "--vram"], StoreConst(Some(true)), "Display the VRAM viewer")
+                .add_option(&["--no-vram"], StoreConst(Some(false)), "Don't display the VRAM viewer");
+            ap.refer(&mut boot_rom_filepath)
+                .add_option(&["--boot-rom"], StoreOption, "Path to a boot ROM to run before the game starts");
+            ap.refer(&mut model_str)
+                .add_option(&["-m", "--model"], StoreOption, "Gameboy model to emulate (auto, dmg0, dmg, mgb, sgb, cgb)");
+            ap.refer(&mut color_correction)
+                .add_option(&["--color-correction"], StoreConst(Some(true)), "Adjust CGB colors to look like they would on a real CGB screen")
+                .add_option(&["--no-color-correction"], StoreConst(Some(false)), "Show CGB colors as they are");
+            ap.refer(&mut bindings_filepath)
+                .add_option(&["--bindings"], StoreOption, "Path to a file mapping keys and controller buttons to actions (see input.rs)");
+            ap.refer(&mut record_filepath)
+                .add_option(&["--record"], StoreOption, "Record the input on every frame to a movie file (.gbm)");
+            ap.refer(&mut play_filepath)
+                .add_option(&["--play"], Store, "Play back a movie file (.gbm) recorded with --record");
+            ap.refer(&mut speed_str)
+                .add_option(&["--speed"], StoreOption, "Emulation speed (0.25, 0.5, 1, 2, 4, 8 or uncapped)");
+            ap.refer(&mut screenshot_frame)
+                .add_option(&["--screenshot-at-frame"], StoreOption, "Save a screenshot of the given frame to the PNG file given by --screenshot-out");
+            ap.refer(&mut screenshot_out)
+                .add_option(&["--screenshot-out"], StoreOption, "File to save the --screenshot-at-frame screenshot to");
+            ap.refer(&mut screenshot_scale)
+                .add_option(&["--screenshot-scale"], StoreOption, "Scale factor for screenshots");
+            ap.refer(&mut dump_frames_dir)
+                .add_option(&["--dump-frames"], StoreOption, "Save every frame as a PNG file in the given directory");
+            ap.refer(&mut record_av_filepath)
+                .add_option(&["--record-av"], StoreOption, "Record every frame to an uncompressed video file (.y4m, or .avi for raw AVI)");
+            ap.refer(&mut save_dir)
+                .add_option(&["--save-dir"], StoreOption, "Directory to look for save states (.bess) in, instead of next to the ROM");
+            ap.refer(&mut screenshot_dir)
+                .add_option(&["--screenshot-dir"], StoreOption, "Directory to save hotkey screenshots to, instead of next to the ROM");
+            ap.refer(&mut filter_str)
+                .add_option(&["--filter"], StoreOption, "Upscaling filter for the screen, screenshots and recordings (none, scale2x, scale3x, scale4x, hq2x, hq3x, xbr)");
+            ap.refer(&mut ghosting_str)
+                .add_option(&["--ghosting"], StoreOption, "Blend frames like a slow LCD (none, blend for the average of every 2 frames, or the fraction of each frame left on the next one, e.g. 0.4)");
+            ap.refer(&mut lcd_grid)
+                .add_option(&["--lcd-grid"], StoreConst(Some(true)), "Draw a grid between pixels at scale 3 or higher")
+                .add_option(&["--no-lcd-grid"], StoreConst(Some(false)), "Don't draw a grid between pixels");
+            ap.refer(&mut dmg_tint)
+                .add_option(&["--dmg-tint"], StoreConst(Some(true)), "Tint the screen green like the original Gameboy LCD")
+                .add_option(&["--no-dmg-tint"], StoreConst(Some(false)), "Don't tint the screen green");
             ap.parse_args()
                 .map_err(|e| format!("Argument parsing failed with error code {e}"))?;
         }
 
+        // Anything not given on the command line comes from the config file, where per-game
+        // sections are picked based on the cartridge header.
+        let config_file = match config_filepath {
+            Some(filepath) => ConfigFile::load(&filepath)?,
+            None => match default_config_filepath() {
+                Some(filepath) if filepath.exists() => ConfigFile::load(&filepath.to_string_lossy())?,
+                _ => ConfigFile::default(),
+            },
+        };
+        let header_keys = fs::read(&rom_filepath).ok().and_then(|rom| rom_header_keys(&rom));
+        let settings = config_file.settings(header_keys.as_ref());
+
+        let mut scale = setting(scale, &settings, "scale", 4)?;
+        let palette = setting(palette, &settings, "palette", "grey".to_string())?;
+        let debug_show_speed = flag_setting(debug_show_speed, &settings, "debug_speed")?;
+        let breakpoints_str = setting(breakpoints_str, &settings, "breakpoints", "".to_string())?;
+        let vram_viewer = flag_setting(vram_viewer, &settings, "vram_viewer")?;
+        let boot_rom_filepath = setting(boot_rom_filepath, &settings, "boot_rom", "".to_string())?;
+        let model_str = setting(model_str, &settings, "model", "auto".to_string())?;
+        let color_correction = flag_setting(color_correction, &settings, "color_correction")?;
+        let bindings_filepath = setting(bindings_filepath, &settings, "bindings", "".to_string())?;
+        let speed_str = setting(speed_str, &settings, "speed", "1".to_string())?;
+        let mut screenshot_scale = setting(screenshot_scale, &settings, "screenshot_scale", 1)?;
+        let save_dir = setting(save_dir, &settings, "save_dir", "".to_string())?;
+        let screenshot_dir = setting(screenshot_dir, &settings, "screenshot_dir", "".to_string())?;
+        let filter_str = setting(filter_str, &settings, "filter", "none".to_string())?;
+        let ghosting_str = setting(ghosting_str, &settings, "ghosting", "none".to_string())?;
+        let mut lcd_grid = flag_setting(lcd_grid, &settings, "lcd_grid")?;
+        let dmg_tint = flag_setting(dmg_tint, &settings, "dmg_tint")?;
+        let record_filepath = setting(record_filepath, &settings, "record", "".to_string())?;
+        let dump_frames_dir = setting(dump_frames_dir, &settings, "dump_frames", "".to_string())?;
+        let record_av_filepath = setting(record_av_filepath, &settings, "record_av", "".to_string())?;
+
         if scale < 1 {
             println!("Minimum allowed scale factor is 1, clamping.");
             scale = 1;
         }
 
-        let palette = match palette_str.as_str() {
-            "grey" => PALETTE_GREY,
-            "red" => PALETTE_RED,
-            "green" => PALETTE_GREEN,
-            "blue" => PALETTE_BLUE,
-            _ => {
-                println!("Unknown palette '{palette_str}', defaulting to palette 'grey'.");
-                PALETTE_GREY
-            },
-        };
-
         let mut breakpoints = vec!();
         for breakpoint in breakpoints_str.split(',') {
             if breakpoint.is_empty() {
@@ -79,6 +188,41 @@ impl Config {
             breakpoints.push(breakpoint_u16);
         }
 
+        let boot_rom_filepath = if boot_rom_filepath.is_empty() { None } else { Some(boot_rom_filepath) };
+        let bindings_filepath = if bindings_filepath.is_empty() { None } else { Some(bindings_filepath) };
+        let record_filepath = if record_filepath.is_empty() { None } else { Some(record_filepath) };
+        let play_filepath = if play_filepath.is_empty() { None } else { Some(play_filepath) };
+        if record_filepath.is_some() && play_filepath.is_some() {
+            return Err("Can't record and play a movie at the same time".to_string());
+        }
+
+        let speed = Speed::new(&speed_str)?;
+        let filter = Filter::new(&filter_str)?;
+        let ghosting = Ghosting::new(&ghosting_str)?;
+        if lcd_grid && scale < MIN_GRID_SCALE {
+            println!("The LCD grid needs a scale factor of at least {MIN_GRID_SCALE}, turning it off.");
+            lcd_grid = false;
+        }
+
+        let screenshot_at_frame = match (screenshot_frame, screenshot_out) {
+            (None, None) => None,
+            (Some(frame), Some(filepath)) => Some((frame, filepath)),
+            _ => return Err("--screenshot-at-frame and --screenshot-out have to be given together".to_string()),
+        };
+        if screenshot_scale < 1 {
+            println!("Minimum allowed screenshot scale factor is 1, clamping.");
+            screenshot_scale = 1;
+        }
+        let dump_frames_dir = if dump_frames_dir.is_empty() { None } else { Some(dump_frames_dir) };
+        let record_av_filepath = if record_av_filepath.is_empty() { None } else { Some(record_av_filepath) };
+        let save_dir = if save_dir.is_empty() { None } else { Some(save_dir) };
+        let screenshot_dir = if screenshot_dir.is_empty() { None } else { Some(screenshot_dir) };
+
+        let model = match model_str.as_str() {
+            "auto" => None,
+            _ => Some(Model::new(&model_str)?),
+        };
+
         let config = Self {
             rom_filepath,
             scale,
@@ -86,22 +230,74 @@ impl Config {
             debug_show_speed,
             breakpoints,
             vram_viewer,
+            boot_rom_filepath,
+            model,
+            color_correction,
+            bindings_filepath,
+            record_filepath,
+            play_filepath,
+            speed,
+            screenshot_at_frame,
+            screenshot_scale,
+            dump_frames_dir,
+            record_av_filepath,
+            save_dir,
+            screenshot_dir,
+            filter,
+            ghosting,
+            lcd_grid,
+            dmg_tint,
         };
 
         Ok(config)
     }
+
+    /// Files that belong to the ROM, like its save state, are named after it with an extension
+    /// added, and are kept either next to the ROM or in the given directory.
+    fn rom_file_prefix(&self, dir: &Option<String>) -> String {
+        match dir {
+            Some(dir) => {
+                let rom_filename = Path::new(&self.rom_filepath).file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                Path::new(dir).join(rom_filename).to_string_lossy().to_string()
+            },
+            None => self.rom_filepath.clone(),
+        }
+    }
 }
 
 fn main() -> Result<(), String> {
     let config = Config::new()?;
 
-    let bess_filename = format!("{}.bess", &config.rom_filepath);
-    let bess_bytes = match fs::read(&bess_filename) {
-        Err(err) => {
-            eprintln!("Couldn't open BESS file '{}': {}", bess_filename, err);
-            vec![]
+    let cart_bytes = fs::read(&config.rom_filepath)
+        .expect("Failed to open ROM file");
+
+    // A movie being played back always starts from the state it was recorded from, if any.
+    let movie = match &config.play_filepath {
+        Some(filepath) => {
+            let movie = Movie::load(filepath)?;
+            movie.check_rom(&cart_bytes)?;
+            Some(movie)
+        },
+        None => None,
+    };
+
+    let (bess_filename, bess_bytes) = match &movie {
+        Some(movie) => {
+            (config.play_filepath.clone().unwrap(), movie.start_state.clone().unwrap_or_default())
+        },
+        None => {
+            let bess_filename = format!("{}.bess", config.rom_file_prefix(&config.save_dir));
+            let bess_bytes = match fs::read(&bess_filename) {
+                Err(err) => {
+                    eprintln!("Couldn't open BESS file '{}': {}", bess_filename, err);
+                    vec![]
+                },
+                Ok(bess_bytes) => bess_bytes,
+            };
+            (bess_filename, bess_bytes)
         },
-        Ok(bess_bytes) => bess_bytes,
     };
     let bess = if !bess_bytes.is_empty() {
         match Bess::new(&bess_bytes, &bess_filename) {
@@ -115,56 +311,105 @@ fn main() -> Result<(), String> {
         }
     } else { None };
 
-    let cart_bytes = fs::read(&config.rom_filepath)
-        .expect("Failed to open ROM file");
+    let movie = match (movie, &config.record_filepath) {
+        (Some(movie), _) => Some(MovieRunner::play(movie)),
+        (None, Some(filepath)) => {
+            let start_state = if bess.is_some() { Some(bess_bytes.clone()) } else { None };
+            Some(MovieRunner::record(Movie::new(&cart_bytes, start_state), filepath)?)
+        },
+        (None, None) => None,
+    };
+
+    let execution_state = bess.as_ref().map(|bess| bess.core_block.execution_state);
     let cart = load_cartridge(&cart_bytes, bess)
         .expect("Failed to parse ROM file");
 
-    run_gameboy(cart, config)
+    // Games that support the CGB or SGB say so in the cartridge header.
+    let model = config.model.unwrap_or(
+        if cart_bytes[0x143] & 0x80 > 0 {
+            Model::Cgb
+        } else if cart_bytes[0x146] == 0x03 {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        });
+
+    let boot_rom = match &config.boot_rom_filepath {
+        None => None,
+        Some(filepath) => {
+            let boot_rom = fs::read(filepath)
+                .map_err(|e| format!("Failed to open boot ROM file '{filepath}': {e}"))?;
+            if boot_rom.len() != model.boot_rom_size() {
+                return Err(format!("Boot ROM '{}' is {} bytes, expected {} bytes for model {:?}",
+                                   filepath, boot_rom.len(), model.boot_rom_size(), model));
+            }
+            Some(boot_rom)
+        },
+    };
+
+    // The configured palette comes first, followed by the built-in ones it can be switched to.
+    let mut palettes = vec!((config.palette.clone(), DmgPalette::new(&config.palette, &cart_bytes, config.color_correction)?));
+    for name in PALETTE_NAMES.iter().filter(|&&name| name != config.palette) {
+        palettes.push((name.to_string(), DmgPalette::new(name, &cart_bytes, config.color_correction)?));
+    }
+
+    run_gameboy(cart, model, boot_rom, execution_state, movie, palettes, config)
 }
 
-fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
-    let mut gb = Gameboy::new(cartridge);
+fn run_gameboy(cartridge: Cartridge, model: Model, boot_rom: Option<Vec<u8>>, execution_state: Option<u8>,
+               movie: Option<MovieRunner>, palettes: Vec<(String, DmgPalette)>, config: Config) -> Result<(), String> {
+    let mut gb = Gameboy::new(cartridge, model, boot_rom);
+    if let Some(execution_state) = execution_state {
+        gb.set_execution_state(execution_state);
+    }
+    gb.movie = movie;
 
     for breakpoint in &config.breakpoints {
         gb.debug.breakpoints.push(*breakpoint);
     }
 
     let io_ports_sdl = gb.io_ports.clone();
-    let ime_sdl = gb.ime.clone();
     let controller_data_sdl = gb.controller_data.clone();
-    let interrupt_received_sdl = Arc::clone(&gb.interrupt_received);
     let screen_sdl = gb.screen.clone();
+    let frame_ready_sdl = gb.frame_ready.clone();
+    let sgb_sdl = gb.sgb.clone();
     let vram_sdl = gb.vram.clone();
+    let speed_sdl = gb.speed.clone();
+    speed_sdl.set_speed(config.speed);
+    let screenshot_requested_sdl = gb.capture.screenshot_requested.clone();
+
+    gb.capture.screenshot_prefix = config.rom_file_prefix(&config.screenshot_dir);
+    gb.capture.screenshot_at_frame = config.screenshot_at_frame.clone();
+    gb.capture.screenshot_scale = config.screenshot_scale;
+    gb.capture.filter = config.filter;
+    if let Some(dir) = &config.dump_frames_dir {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create frame dump directory '{dir}': {e}"))?;
+        gb.capture.dump_dir = Some(dir.into());
+    }
+    if let Some(filepath) = &config.record_av_filepath {
+        let factor = config.filter.factor();
+        gb.capture.video = Some(VideoRecorder::create(filepath, 160 * factor, 144 * factor)?);
+    }
 
-    let debug_info_cpu = DebugInfoCpu::new();
-    let debug_info_ppu = DebugInfoPpu::new();
-
-    let ppu_thread = {
-        let mut ppu = Ppu::new(&gb, config.palette);
-        let debug = debug_info_ppu.clone();
+    gb.ppu.palette = palettes[0].1;
+    let palette_request_sdl = gb.ppu.palette_request.clone();
+    let mut current_palette = 0;
+    gb.ppu.color_correction = config.color_correction;
 
-        thread::Builder::new().name("ppu".into()).spawn(move || { 
-            run_ppu(&mut ppu, debug);
-        }).expect("Failed to create ppu thread")
+    let mut bindings = match &config.bindings_filepath {
+        Some(filepath) => Bindings::load(filepath)?,
+        None => Bindings::default(),
     };
 
-    let mut timer = Timer {
-        io_ports: gb.io_ports.clone(),
-        ime: gb.ime.clone(),
-        interrupt_received: Arc::clone(&gb.interrupt_received),
-        timer_enabled: Arc::clone(&gb.timer_enabled),
-    };
-    let timer_thread = thread::Builder::new().name("timer".into()).spawn(move || {
-        run_timer(&mut timer);
-    }).expect("Failed to create timer thread");
+    let debug_info_cpu = DebugInfoCpu::new();
+    let debug_info_ppu = gb.ppu.debug_info.clone();
 
     {
         let debug = debug_info_cpu.clone();
-        let components = vec!(ppu_thread, timer_thread);
 
         thread::Builder::new().name("cpu".into()).spawn(move || {
-            run_cpu(&mut gb, debug, components.as_slice());
+            run_cpu(&mut gb, debug);
         }).expect("Failed to create cpu thread");
     }
 
@@ -172,8 +417,14 @@ fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
+    let mut controllers = Controllers::new(sdl_context.game_controller()?);
+    // On the SGB, the screen is shown in the middle of a larger border.
+    let (screen_width, screen_height) = match sgb_sdl {
+        Some(_) => (SGB_BORDER_WIDTH, SGB_BORDER_HEIGHT),
+        None => (160, 144),
+    };
     let window = video_subsystem
-        .window("gameboy emulator", 160 * config.scale, 144 * config.scale)
+        .window("gameboy emulator", screen_width as u32 * config.scale, screen_height as u32 * config.scale)
         .position_centered()
         .opengl()
         .build()
@@ -185,8 +436,12 @@ fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
         .map_err(|e| e.to_string())?;
     let texture_creator = canvas.texture_creator();
     
+    // The texture holds the filtered frame, which SDL then scales to fit the window. The LCD grid
+    // is drawn at the window's own size instead.
+    let filter_factor = config.filter.factor() as usize;
+    let texture_factor = if config.lcd_grid { config.scale as usize } else { filter_factor };
     let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
+        .create_texture_streaming(PixelFormatEnum::RGB24, (screen_width * texture_factor) as u32, (screen_height * texture_factor) as u32)
         .map_err(|e| e.to_string())?;
 
     canvas.clear();
@@ -211,21 +466,41 @@ fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
         vram_canvas.window_mut().hide();
     }
 
+    let mut lcd_effects = LcdEffects::new(config.ghosting, config.dmg_tint);
     let mut frames: u128 = 0;
+    let mut redraw = false;
+    // The last frame shown, after the effects and filter, for redrawing uncovered windows.
+    let mut presented_frame = vec!();
     let mut event_pump = sdl_context.event_pump()?;
     'running: loop {
         for event in event_pump.poll_iter() {
+            controllers.handle_event(&event);
+            for action in bindings.triggered(&event) {
+                match action {
+                    Action::ToggleLcd => io_ports_sdl.xor(IO_LCDC, LCDC_ON),
+                    Action::ToggleSprites => io_ports_sdl.xor(IO_LCDC, LCDC_OBJ_DISP),
+                    Action::ToggleBackground => io_ports_sdl.xor(IO_LCDC, LCDC_BG_DISP),
+                    Action::ToggleWindow => io_ports_sdl.xor(IO_LCDC, LCDC_WIN_DISP),
+                    Action::Pause => {
+                        let paused = speed_sdl.toggle_pause();
+                        println!("{}", if paused { "Paused" } else { "Unpaused" });
+                    },
+                    Action::FrameAdvance => speed_sdl.advance_frame(),
+                    Action::SpeedUp => println!("Speed: {}", speed_sdl.change_speed(true)),
+                    Action::SlowDown => println!("Speed: {}", speed_sdl.change_speed(false)),
+                    Action::Screenshot => screenshot_requested_sdl.store(true, Ordering::Relaxed),
+                    Action::NextPalette => {
+                        current_palette = (current_palette + 1) % palettes.len();
+                        *palette_request_sdl.lock().unwrap() = Some(palettes[current_palette].1);
+                        println!("Palette: {}", palettes[current_palette].0);
+                    },
+                    // Gameboy buttons and fast-forward are polled below instead.
+                    Action::Joypad(_) | Action::FastForward => {},
+                }
+            }
             match event {
                 Event::Window { win_event: WindowEvent::Close, .. } | Event::Quit { .. } => break 'running,
-                Event::KeyDown { keycode: Some(kc), .. } => {
-                    match kc {
-                        Keycode::L => io_ports_sdl.xor(IO_LCDC, LCDC_ON), // Toggle LCD on/off
-                        Keycode::S => io_ports_sdl.xor(IO_LCDC, LCDC_OBJ_DISP), // Toggle sprites
-                        Keycode::B => io_ports_sdl.xor(IO_LCDC, LCDC_BG_DISP), // Toggle background
-                        Keycode::W => io_ports_sdl.xor(IO_LCDC, LCDC_WIN_DISP), // Toggle window
-                        _ => {}
-                    };
-                },
+                Event::Window { win_event: WindowEvent::Exposed, .. } => redraw = true,
                 Event::MouseMotion { window_id, x, y, .. } => {
                     if config.vram_viewer && window_id == vram_window_id {
                         let x = x as u32 / config.scale;
@@ -249,60 +524,64 @@ fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
             }
         }
 
-        let mut cont_data = 0b1111_1111;
         let kb_state = event_pump.keyboard_state();
-        if kb_state.is_scancode_pressed(Scancode::Right) {
-            cont_data &= !CONTROLLER_DATA_RIGHT;
-        }
-        if kb_state.is_scancode_pressed(Scancode::Left) {
-            cont_data &= !CONTROLLER_DATA_LEFT;
-        }
-        if kb_state.is_scancode_pressed(Scancode::Up) {
-            cont_data &= !CONTROLLER_DATA_UP;
-        }
-        if kb_state.is_scancode_pressed(Scancode::Down) {
-            cont_data &= !CONTROLLER_DATA_DOWN;
-        }
-        if kb_state.is_scancode_pressed(Scancode::X) {
-            cont_data &= !CONTROLLER_DATA_A;
-        }
-        if kb_state.is_scancode_pressed(Scancode::Z) {
-            cont_data &= !CONTROLLER_DATA_B;
-        }
-        if kb_state.is_scancode_pressed(Scancode::A) {
-            cont_data &= !CONTROLLER_DATA_SE;
-        }
-        if kb_state.is_scancode_pressed(Scancode::S) {
-            cont_data &= !CONTROLLER_DATA_ST;
-        }
-        let prev_cont_data = controller_data_sdl.load(Ordering::Relaxed);
+        let cont_data = bindings.controller_data(&kb_state, &controllers);
         controller_data_sdl.store(cont_data, Ordering::Relaxed);
-        // Technically we should only trigger this interrupt when a low signal lasts for 2^4 *
-        // 4MHz = 4 microsecs. We currently poll 60 times per second, which is once every ~16,666 microseconds.
-        // If we need more sensitive polling, could move controller handling to its own thread.
-        if ime_sdl.load(Ordering::Relaxed) && io_ports_sdl.read(IO_IE) & INT_HILO > 0 {
-            for i in 0..8 {
-                if prev_cont_data & (1 << i) > 0 && cont_data & (1 << i) == 0 {
-                    io_ports_sdl.or(IO_IF, INT_HILO);
-                    let (mutex, cvar) = &*interrupt_received_sdl;
-                    let mut interrupted = mutex.lock().unwrap();
-                    *interrupted = true;
-                    cvar.notify_one();
-                    break;
+        let fast_forward = bindings.is_held(Action::FastForward, &kb_state, &controllers);
+        speed_sdl.fast_forward.store(fast_forward, Ordering::Relaxed);
+
+        // Each frame is presented once, as soon as the PPU hands it over at the start of VBlank.
+        // Uncovered windows are redrawn with the frame they were showing before, without running
+        // it through the effects again, which would count as another frame for ghosting.
+        let frame_ready = frame_ready_sdl.swap(false, Ordering::Relaxed);
+        if !frame_ready && !redraw {
+            thread::sleep(FRAME_POLL_INTERVAL);
+            continue;
+        }
+        redraw = false;
+
+        if frame_ready {
+            let mut frame = Vec::with_capacity(screen_width * screen_height);
+            {
+                let screen = screen_sdl.lock().unwrap();
+                match &sgb_sdl {
+                    None => frame.extend(screen.iter().flatten()),
+                    Some(sgb) => {
+                        let sgb = sgb.lock().unwrap();
+                        let backdrop = sgb.backdrop_color();
+                        for y in 0..SGB_BORDER_HEIGHT {
+                            for x in 0..SGB_BORDER_WIDTH {
+                                let in_screen = (SGB_SCREEN_X..SGB_SCREEN_X+160).contains(&x)
+                                    && (SGB_SCREEN_Y..SGB_SCREEN_Y+144).contains(&y);
+                                // The border is drawn on top of the Gameboy screen, except where
+                                // it's transparent.
+                                let pixel = sgb.border_color(x, y).unwrap_or(
+                                    if in_screen {
+                                        screen[y - SGB_SCREEN_Y][x - SGB_SCREEN_X]
+                                    } else {
+                                        backdrop
+                                    });
+                                frame.push(pixel);
+                            }
+                        }
+                    },
                 }
             }
+            lcd_effects.apply(&mut frame);
+            let mut frame = config.filter.apply(screen_width as u32, &frame);
+            if config.lcd_grid {
+                frame = draw_grid(&frame, screen_width * filter_factor, filter_factor, texture_factor);
+            }
+            presented_frame = frame;
         }
-
+        let frame_width = screen_width * texture_factor;
         texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-            // TODO We should probably have PPU write to a backbuffer and just swap the buffers
-            // here instead, could fix the uneveness in updating the screen.
-            let screen = screen_sdl.lock().unwrap();
-            for y in 0..144 {
-                for x in 0..160 {
+            for (y, row) in presented_frame.chunks(frame_width).enumerate() {
+                for (x, pixel) in row.iter().enumerate() {
                     let offset = y*pitch + x*3;
-                    buffer[offset] = screen[y][x].0;
-                    buffer[offset + 1] = screen[y][x].1;
-                    buffer[offset + 2] = screen[y][x].2;
+                    buffer[offset] = pixel.0;
+                    buffer[offset + 1] = pixel.1;
+                    buffer[offset + 2] = pixel.2;
                 }
             }
         })?;
@@ -335,7 +614,7 @@ fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
                         let palette_ix = 2*high_bit + low_bit;
                         let bgp_mask = 0b11 << (palette_ix * 2);
                         let bgp_palette_ix = (bgp & bgp_mask) >> (palette_ix * 2);
-                        let pixel = config.palette[bgp_palette_ix as usize];
+                        let pixel = palettes[current_palette].1.bg[bgp_palette_ix as usize];
 
                         let offset = y*pitch + x*3;
                         buffer[offset] = pixel.0;
@@ -353,14 +632,12 @@ fn run_gameboy(cartridge: Cartridge, config: Config) -> Result<(), String> {
             let cpu_actual = debug_info_cpu.actual_time_nanos.load(Ordering::Relaxed);
             let ppu_expected = debug_info_ppu.expected_time_micros.load(Ordering::Relaxed);
             let ppu_actual = debug_info_ppu.actual_time_micros.load(Ordering::Relaxed);
-            let oam_time = debug_info_ppu.oam_time_nanos.load(Ordering::Relaxed);
             println!("CPU: {}/{} ({:.4}%)", cpu_expected, cpu_actual, (cpu_expected as f64 / cpu_actual as f64) * 100.0);
-            println!("PPU: {}/{} ({:.4}%), oam {}ns", ppu_expected, ppu_actual, (ppu_expected as f64 / ppu_actual as f64) * 100.0, oam_time);
+            println!("PPU: {}/{} ({:.4}%)", ppu_expected, ppu_actual, (ppu_expected as f64 / ppu_actual as f64) * 100.0);
+            println!("Speed: {}", speed_sdl.speed());
         }
 
         frames = (Wrapping(frames) + Wrapping(1)).0;
-
-        thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
     }
 
     Ok(())